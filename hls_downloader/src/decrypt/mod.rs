@@ -0,0 +1,218 @@
+//! Decryption of segments encrypted as `EXT-X-KEY` describes: whole
+//! segments with AES-128, or the samples of MPEG-TS segments with
+//! [SAMPLE-AES](sample_aes).
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecryptMut, KeyIvInit},
+    Aes128,
+};
+use tokio::sync::OnceCell;
+
+use crate::{
+    fetch::{FetchError, Fetcher, Source},
+    hls::m3u8::media::{Key, KeyMethod},
+    ts::TsError,
+};
+
+pub mod sample_aes;
+
+const BLOCK_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum DecryptError {
+    /// The key couldn't be loaded.
+    KeyFetch { uri: String, error: FetchError },
+    /// The key isn't 128 bits long.
+    KeyLength(usize),
+    /// The ciphertext isn't whole blocks, or its padding is wrong.
+    Padding,
+    /// None of the keys is in the `identity` format.
+    NoIdentityKey,
+    /// An initialization section is encrypted with AES-128 by a key without
+    /// an `IV`.
+    MissingIv,
+    Unsupported(KeyMethod),
+    /// A SAMPLE-AES segment isn't a well-formed transport stream.
+    Transport(TsError),
+}
+
+/// Streaming AES-128-CBC decryption with PKCS7 padding.
+pub struct Decryptor {
+    cipher: cbc::Decryptor<Aes128>,
+    /// Ciphertext not decrypted yet: a partial block, or the last whole
+    /// block, which may carry the padding.
+    pending: Vec<u8>,
+}
+
+/// Keys by URI, each loaded once however many segments ask for it at once.
+#[derive(Default)]
+pub struct KeyCache {
+    keys: Mutex<HashMap<String, Arc<OnceCell<[u8; 16]>>>>,
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptError::KeyFetch { uri, error } => write!(f, "can't load key {}: {}", uri, error),
+            DecryptError::KeyLength(len) => write!(f, "key is {} bytes long instead of 16", len),
+            DecryptError::Padding => f.write_str("bad padding, wrong key or iv?"),
+            DecryptError::NoIdentityKey => f.write_str("no key in the identity format"),
+            DecryptError::MissingIv => f.write_str("initialization section encrypted with AES-128 has no iv"),
+            DecryptError::Unsupported(method) => write!(f, "unsupported encryption method {}", method),
+            DecryptError::Transport(e) => write!(f, "can't demux segment: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecryptError::KeyFetch { error, .. } => Some(error),
+            DecryptError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<TsError> for DecryptError {
+    fn from(e: TsError) -> Self {
+        DecryptError::Transport(e)
+    }
+}
+
+impl DecryptError {
+    /// Whether trying again may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, DecryptError::KeyFetch { error, .. } if error.is_transient())
+    }
+}
+
+/// IV of a segment: the key's `IV` attribute, or else the segment's media
+/// sequence number as a big-endian 128-bit integer (RFC 8216, section 5.2).
+pub fn iv(key: &Key, sequence: u64) -> [u8; 16] {
+    key.iv.unwrap_or_else(|| u128::from(sequence).to_be_bytes())
+}
+
+impl Decryptor {
+    pub fn new(key: &[u8; 16], iv: &[u8; 16]) -> Self {
+        Decryptor { cipher: cbc::Decryptor::new(key.into(), iv.into()), pending: Vec::with_capacity(BLOCK_SIZE) }
+    }
+
+    /// Decrypts the blocks `data` completes onto `out`, keeping back the
+    /// last one until [`finish`](Self::finish).
+    pub fn update(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.pending.extend_from_slice(data);
+        let keep = match self.pending.len() % BLOCK_SIZE {
+            0 => BLOCK_SIZE.min(self.pending.len()),
+            partial => partial,
+        };
+        let ready = self.pending.len() - keep;
+        for block in self.pending[..ready].chunks_exact_mut(BLOCK_SIZE) {
+            self.cipher.decrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+        out.extend_from_slice(&self.pending[..ready]);
+        self.pending.drain(..ready);
+    }
+
+    /// Decrypts the last block onto `out`, without its padding.
+    pub fn finish(mut self, out: &mut Vec<u8>) -> Result<(), DecryptError> {
+        if self.pending.len() != BLOCK_SIZE {
+            return Err(DecryptError::Padding);
+        }
+        let block = GenericArray::from_mut_slice(&mut self.pending);
+        self.cipher.decrypt_block_mut(block);
+        let padding = block[BLOCK_SIZE - 1] as usize;
+        if padding == 0 || padding > BLOCK_SIZE || block[BLOCK_SIZE - padding..].iter().any(|&b| b as usize != padding) {
+            return Err(DecryptError::Padding);
+        }
+        out.extend_from_slice(&block[..BLOCK_SIZE - padding]);
+        Ok(())
+    }
+}
+
+impl KeyCache {
+    /// Key at `location`, loaded through `fetcher` the first time.
+    pub async fn get(&self, fetcher: &Fetcher, location: &str) -> Result<[u8; 16], DecryptError> {
+        let cell = self.keys.lock().unwrap().entry(location.to_string()).or_default().clone();
+        let key = cell
+            .get_or_try_init(|| async {
+                let key = fetcher
+                    .load(&Source::parse(location))
+                    .await
+                    .map_err(|error| DecryptError::KeyFetch { uri: location.to_string(), error })?;
+                <[u8; 16]>::try_from(key.as_slice()).map_err(|_| DecryptError::KeyLength(key.len()))
+            })
+            .await?;
+        Ok(*key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut};
+
+    use super::*;
+    use crate::test_server::fetcher;
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+    const IV: [u8; 16] = *b"fedcba9876543210";
+
+    fn encrypt(plaintext: &[u8]) -> Vec<u8> {
+        let mut buf = plaintext.to_vec();
+        buf.resize(plaintext.len() + BLOCK_SIZE, 0);
+        let len = cbc::Encryptor::<Aes128>::new(&KEY.into(), &IV.into()).encrypt_padded_mut::<Pkcs7>(&mut buf, plaintext.len()).unwrap().len();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn decrypts_in_pieces_of_any_size() {
+        for len in [0, 1, 15, 16, 17, 100] {
+            let plaintext: Vec<u8> = (0..len as u8).collect();
+            let ciphertext = encrypt(&plaintext);
+            for piece in [1, 7, 16, 33] {
+                let mut decryptor = Decryptor::new(&KEY, &IV);
+                let mut out = Vec::new();
+                for chunk in ciphertext.chunks(piece) {
+                    decryptor.update(chunk, &mut out);
+                    assert!(out.len() <= plaintext.len());
+                }
+                decryptor.finish(&mut out).unwrap();
+                assert_eq!(out, plaintext, "{} bytes in pieces of {}", len, piece);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_ciphertext_is_reported() {
+        let ciphertext = encrypt(b"segment");
+        let mut decryptor = Decryptor::new(&[0; 16], &IV);
+        decryptor.update(&ciphertext, &mut Vec::new());
+        assert!(matches!(decryptor.finish(&mut Vec::new()), Err(DecryptError::Padding)));
+        let mut decryptor = Decryptor::new(&KEY, &IV);
+        decryptor.update(&ciphertext[..10], &mut Vec::new());
+        assert!(matches!(decryptor.finish(&mut Vec::new()), Err(DecryptError::Padding)));
+    }
+
+    #[test]
+    fn iv_defaults_to_the_sequence_number() {
+        let mut key = Key { method: KeyMethod::Aes128, uri: Some("k".into()), iv: None, key_format: None, key_format_versions: None };
+        assert_eq!(iv(&key, 0x0102), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        key.iv = Some(IV);
+        assert_eq!(iv(&key, 0x0102), IV);
+    }
+
+    #[tokio::test]
+    async fn keys_load_from_data_uris() {
+        let fetcher = fetcher();
+        let cache = KeyCache::default();
+        assert_eq!(cache.get(&fetcher, "data:;base64,MDEyMzQ1Njc4OWFiY2RlZg==").await.unwrap(), KEY);
+        assert!(matches!(cache.get(&fetcher, "data:,short").await, Err(DecryptError::KeyLength(5))));
+    }
+}
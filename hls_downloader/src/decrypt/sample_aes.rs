@@ -0,0 +1,352 @@
+//! SAMPLE-AES decryption of MPEG-TS segments, as in Apple's "MPEG-2 Stream
+//! Encryption Format for HTTP Live Streaming".
+//!
+//! Only parts of H.264 slices and of AAC, AC-3 and E-AC-3 frames are
+//! encrypted, each with its own CBC chain starting from the segment's IV.
+//! Once decrypted, the streams are declared with their usual stream types
+//! so that any player takes them.
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, KeyInit},
+    Aes128,
+};
+
+use super::DecryptError;
+use crate::{
+    hls::m3u8::media::KeyMethod,
+    ts::{self, Stream},
+};
+
+const BLOCK_SIZE: usize = 16;
+
+/// Encrypted stream types, with the stream types of their clear versions.
+const STREAM_TYPES: [(u8, u8); 4] = [(0xdb, 0x1b), (0xcf, 0x0f), (0xc1, 0x81), (0xc2, 0x87)];
+const H264: u8 = 0xdb;
+const AAC: u8 = 0xcf;
+
+/// Slices, IDR or not, are the NAL units encrypted.
+const ENCRYPTED_NAL_TYPES: [u8; 2] = [1, 5];
+/// NAL units this long or shorter are left clear.
+const MIN_ENCRYPTED_NAL: usize = 49;
+/// Clear bytes at the start of an encrypted NAL unit.
+const NAL_LEADER: usize = 32;
+/// Clear bytes after each encrypted block of a NAL unit.
+const NAL_CLEAR_RUN: usize = 144;
+/// Clear bytes at the start of an audio frame, after any ADTS header.
+const AUDIO_LEADER: usize = 16;
+
+/// AC-3 bit rates in kbit/s, by `frmsizecod / 2`.
+const AC3_BIT_RATES: [usize; 19] = [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 576, 640];
+
+/// CBC decryption of blocks spread through one NAL unit or frame.
+struct Chain<'a> {
+    aes: &'a Aes128,
+    previous: [u8; BLOCK_SIZE],
+}
+
+impl Chain<'_> {
+    fn decrypt(&mut self, block: &mut [u8]) {
+        let ciphertext: [u8; BLOCK_SIZE] = block.try_into().expect("whole block");
+        self.aes.decrypt_block(GenericArray::from_mut_slice(block));
+        block.iter_mut().zip(self.previous).for_each(|(byte, previous)| *byte ^= previous);
+        self.previous = ciphertext;
+    }
+}
+
+/// Decrypts `segment`, an MPEG-TS segment, into a clear one.
+pub fn decrypt_segment(segment: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>, DecryptError> {
+    // fragmented MP4 uses the cbcs scheme instead
+    if segment.first().is_some_and(|&byte| byte != 0x47) {
+        return Err(DecryptError::Unsupported(KeyMethod::SampleAes));
+    }
+    let aes = Aes128::new(key.into());
+    let chain = || Chain { aes: &aes, previous: *iv };
+    let clear = ts::rewrite(segment, clear_stream, |stream_type, payload| match stream_type {
+        H264 => decrypt_h264(payload, chain),
+        AAC => decrypt_adts(payload, chain),
+        _ => decrypt_ac3(payload, chain),
+    })?;
+    Ok(clear)
+}
+
+/// Declares an encrypted stream as clear, telling whether it was encrypted.
+fn clear_stream(stream: &mut Stream) -> bool {
+    let Some(&(_, clear)) = STREAM_TYPES.iter().find(|(encrypted, _)| *encrypted == stream.stream_type) else {
+        return false;
+    };
+    stream.stream_type = clear;
+    // private data indicators and the audio setup information
+    stream.descriptors.retain(|descriptor| match (descriptor.tag, &descriptor.data[..]) {
+        (0x0f, b"zavc" | b"aacd" | b"ac3d" | b"ec3d") => false,
+        (0x05, data) => !data.starts_with(b"apad"),
+        _ => true,
+    });
+    true
+}
+
+/// Decrypts the slices of an Annex B H.264 stream.
+///
+/// Start code emulation prevention bytes were inserted after encryption,
+/// so they're removed before decrypting, which gives the NAL unit back
+/// with its original ones.
+fn decrypt_h264<'a>(data: &mut Vec<u8>, chain: impl Fn() -> Chain<'a>) {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let mut clear = Vec::with_capacity(data.len());
+    let mut written = 0;
+    for (n, &start) in starts.iter().enumerate() {
+        let mut end = starts.get(n + 1).map_or(data.len(), |next| next - 3);
+        // trailing zeros belong to the next start code
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        let nal = &data[start..end];
+        if nal.len() < MIN_ENCRYPTED_NAL || !ENCRYPTED_NAL_TYPES.contains(&(nal[0] & 0x1f)) {
+            continue;
+        }
+        clear.extend_from_slice(&data[written..start]);
+        let mut nal = remove_emulation_prevention(nal);
+        let mut chain = chain();
+        let mut offset = NAL_LEADER;
+        while offset < nal.len() {
+            if nal.len() - offset > BLOCK_SIZE {
+                chain.decrypt(&mut nal[offset..offset + BLOCK_SIZE]);
+                offset += BLOCK_SIZE;
+            }
+            offset += NAL_CLEAR_RUN.min(nal.len() - offset);
+        }
+        clear.extend(nal);
+        written = end;
+    }
+    if written > 0 {
+        clear.extend_from_slice(&data[written..]);
+        *data = clear;
+    }
+}
+
+fn remove_emulation_prevention(nal: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        unescaped.push(byte);
+    }
+    unescaped
+}
+
+/// Decrypts the ADTS frames of an AAC stream.
+fn decrypt_adts<'a>(data: &mut [u8], chain: impl Fn() -> Chain<'a>) {
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + 7) {
+        if header[0] != 0xff || header[1] & 0xf0 != 0xf0 {
+            break;
+        }
+        let header_length = if header[1] & 1 == 1 { 7 } else { 9 };
+        let length = ((header[3] as usize & 0b11) << 11) | (header[4] as usize) << 3 | (header[5] as usize) >> 5;
+        let Some(frame) = data.get_mut(offset + header_length..offset + length) else {
+            break;
+        };
+        decrypt_frame(frame, chain());
+        offset += length;
+    }
+}
+
+/// Decrypts the sync frames of an AC-3 or E-AC-3 stream.
+fn decrypt_ac3<'a>(data: &mut [u8], chain: impl Fn() -> Chain<'a>) {
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + 6) {
+        if header[..2] != [0x0b, 0x77] {
+            break;
+        }
+        let length = if header[5] >> 3 <= 10 {
+            let (sample_rate_code, frame_size_code) = (header[4] >> 6, (header[4] & 0x3f) as usize);
+            let Some(&bit_rate) = AC3_BIT_RATES.get(frame_size_code / 2) else {
+                break;
+            };
+            // sizes come in 16-bit words
+            match sample_rate_code {
+                0 => bit_rate * 2 * 2,
+                1 => (bit_rate * 320 / 147 + (frame_size_code & 1)) * 2,
+                2 => bit_rate * 3 * 2,
+                _ => break,
+            }
+        } else {
+            (((header[2] as usize & 0b111) << 8 | header[3] as usize) + 1) * 2
+        };
+        let Some(frame) = data.get_mut(offset..offset + length) else {
+            break;
+        };
+        decrypt_frame(frame, chain());
+        offset += length;
+    }
+}
+
+/// Decrypts the whole blocks after the leader of an audio frame.
+fn decrypt_frame(frame: &mut [u8], mut chain: Chain) {
+    if let Some(encrypted) = frame.get_mut(AUDIO_LEADER..) {
+        for block in encrypted.chunks_exact_mut(BLOCK_SIZE) {
+            chain.decrypt(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::BlockEncrypt;
+
+    use super::*;
+    use crate::ts::Descriptor;
+
+    const KEY: [u8; 16] = *b"sample aes key!!";
+    const IV: [u8; 16] = [0x3c; 16];
+
+    /// CBC encryption of blocks spread through one NAL unit or frame.
+    struct EncryptChain {
+        aes: Aes128,
+        previous: [u8; BLOCK_SIZE],
+    }
+
+    impl EncryptChain {
+        fn new() -> Self {
+            EncryptChain { aes: Aes128::new(&KEY.into()), previous: IV }
+        }
+
+        fn encrypt(&mut self, block: &mut [u8]) {
+            block.iter_mut().zip(self.previous).for_each(|(byte, previous)| *byte ^= previous);
+            self.aes.encrypt_block(GenericArray::from_mut_slice(block));
+            self.previous.copy_from_slice(block);
+        }
+    }
+
+    fn encrypt_nal(nal: &[u8]) -> Vec<u8> {
+        let mut nal = nal.to_vec();
+        let mut chain = EncryptChain::new();
+        let mut offset = NAL_LEADER;
+        while offset < nal.len() {
+            if nal.len() - offset > BLOCK_SIZE {
+                chain.encrypt(&mut nal[offset..offset + BLOCK_SIZE]);
+                offset += BLOCK_SIZE;
+            }
+            offset += NAL_CLEAR_RUN.min(nal.len() - offset);
+        }
+        let mut escaped = Vec::new();
+        let mut zeros = 0;
+        for byte in nal {
+            if zeros >= 2 && byte <= 3 {
+                escaped.push(3);
+                zeros = 0;
+            }
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            escaped.push(byte);
+        }
+        escaped
+    }
+
+    fn encrypt_frame(frame: &mut [u8]) {
+        let mut chain = EncryptChain::new();
+        for block in frame[AUDIO_LEADER..].chunks_exact_mut(BLOCK_SIZE) {
+            chain.encrypt(block);
+        }
+    }
+
+    /// NAL unit of `nal_type` whose bytes encrypt to emulated start codes
+    /// now and then.
+    fn nal(nal_type: u8, len: usize) -> Vec<u8> {
+        let mut nal = vec![0x60 | nal_type];
+        nal.extend((1..len).map(|i| if i % 37 < 3 { 0 } else { i as u8 }));
+        // and which has emulation prevention bytes of its own
+        nal[20..24].copy_from_slice(&[0, 0, 3, 1]);
+        nal
+    }
+
+    fn adts_frame(len: usize) -> Vec<u8> {
+        let mut frame = vec![0xff, 0xf1, 0x50, 0x80 | (len >> 11) as u8, (len >> 3) as u8, ((len & 7) << 5) as u8 | 0x1f, 0xfc];
+        frame.extend((7..len).map(|i| i as u8));
+        frame
+    }
+
+    fn ac3_frame() -> Vec<u8> {
+        // 48 kHz, 32 kbit/s: 64 words
+        let mut frame = vec![0x0b, 0x77, 0, 0, 0x00, 0x08 << 3];
+        frame.extend((6..128).map(|i| i as u8));
+        frame
+    }
+
+    #[test]
+    fn slices_are_decrypted() {
+        let (sps, idr, slice, short) = (nal(7, 60), nal(5, 500), nal(1, 49), nal(1, 48));
+        let clear = [&[0, 0, 0, 1][..], &sps, &[0, 0, 1], &idr, &[0, 0, 0, 1], &slice, &[0, 0, 1], &short].concat();
+        let encrypted = [&[0, 0, 0, 1][..], &sps, &[0, 0, 1], &encrypt_nal(&idr), &[0, 0, 0, 1], &encrypt_nal(&slice), &[0, 0, 1], &short].concat();
+        assert_ne!(encrypted.len(), clear.len());
+
+        let aes = Aes128::new(&KEY.into());
+        let mut data = encrypted;
+        decrypt_h264(&mut data, || Chain { aes: &aes, previous: IV });
+        assert_eq!(data, clear);
+    }
+
+    #[test]
+    fn audio_frames_are_decrypted() {
+        let aes = Aes128::new(&KEY.into());
+        let clear = [adts_frame(200), adts_frame(23), adts_frame(40)].concat();
+        let mut data = clear.clone();
+        for (start, end) in [(0, 200), (200, 223), (223, 263)] {
+            encrypt_frame(&mut data[start + 7..end]);
+        }
+        assert_ne!(data, clear);
+        decrypt_adts(&mut data, || Chain { aes: &aes, previous: IV });
+        assert_eq!(data, clear);
+
+        let clear = [ac3_frame(), ac3_frame()].concat();
+        let mut data = clear.clone();
+        encrypt_frame(&mut data[..128]);
+        encrypt_frame(&mut data[128..]);
+        decrypt_ac3(&mut data, || Chain { aes: &aes, previous: IV });
+        assert_eq!(data, clear);
+    }
+
+    #[test]
+    fn segments_are_remuxed_clear() {
+        let idr = nal(5, 1200);
+        let audio = [adts_frame(300), adts_frame(300)].concat();
+        let mut encrypted_audio = audio.clone();
+        encrypt_frame(&mut encrypted_audio[7..300]);
+        encrypt_frame(&mut encrypted_audio[307..]);
+        let zavc = Descriptor { tag: 0x0f, data: b"zavc".to_vec() };
+        let apad = Descriptor { tag: 0x05, data: b"apad\x00\x01".to_vec() };
+        let language = Descriptor { tag: 0x0a, data: b"eng\x00".to_vec() };
+        let streams = |video, audio, descriptors: bool| {
+            vec![
+                Stream { stream_type: video, pid: 0x101, descriptors: if descriptors { vec![zavc.clone()] } else { vec![] } },
+                Stream {
+                    stream_type: audio,
+                    pid: 0x102,
+                    descriptors: if descriptors { vec![apad.clone(), language.clone()] } else { vec![language.clone()] },
+                },
+            ]
+        };
+        let segment = ts::mux(
+            &streams(0xdb, 0xcf, true),
+            &[(0x101, ts::pes(0xe0, &[&[0, 0, 0, 1][..], &encrypt_nal(&idr)].concat())), (0x102, ts::pes(0xc0, &encrypted_audio))],
+        );
+        let clear = ts::mux(
+            &streams(0x1b, 0x0f, false),
+            &[(0x101, ts::pes(0xe0, &[&[0, 0, 0, 1][..], &idr].concat())), (0x102, ts::pes(0xc0, &audio))],
+        );
+
+        assert_eq!(decrypt_segment(&segment, &KEY, &IV).unwrap(), clear);
+        assert!(matches!(decrypt_segment(b"\0\0\0\x18ftypiso6", &KEY, &IV), Err(DecryptError::Unsupported(KeyMethod::SampleAes))));
+    }
+}
@@ -0,0 +1,609 @@
+//! Downloading the segments of a media playlist.
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use futures::{stream, StreamExt};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    decrypt::{self, sample_aes, DecryptError, Decryptor, KeyCache},
+    fetch::{self, FetchError, Fetcher, Source},
+    hls::m3u8::media::{ByteRange, Key, KeyMethod, Map, Playlist, Segment},
+};
+
+/// Where downloaded segments go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// One file per segment and initialization section, named after the
+    /// media sequence number.
+    Dir(PathBuf),
+    /// All segments one after the other, `-` for stdout.
+    File(PathBuf),
+}
+
+/// How fetching a segment or initialization section went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentReport {
+    /// Media sequence number of the segment, or of the first segment using
+    /// the initialization section.
+    pub sequence: u64,
+    pub uri: String,
+    /// Whether this is an initialization section from `EXT-X-MAP`.
+    pub init: bool,
+    pub bytes: usize,
+    /// From the first request to the last byte, retries included.
+    pub elapsed: Duration,
+    pub attempts: u32,
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+    /// A segment failed, retries exhausted.
+    Segment { sequence: u64, uri: String, error: FetchError },
+    /// A segment couldn't be decrypted.
+    Decrypt { sequence: u64, uri: String, error: DecryptError },
+    Output(io::Error),
+}
+
+/// Fetches segments concurrently and writes them in sequence order.
+pub struct Downloader<'a> {
+    fetcher: &'a Fetcher,
+    jobs: usize,
+    retries: u32,
+    retry_delay: Duration,
+    keys: KeyCache,
+}
+
+/// Resource to fetch for the output.
+enum Part<'p> {
+    /// Initialization section, with the first segment using it.
+    Init { map: &'p Map, segment: &'p Segment },
+    Media(&'p Segment),
+}
+
+/// Output open for writing, which may take several downloads.
+pub struct Sink {
+    target: Target,
+    /// Initialization section written last.
+    map: Option<Map>,
+    /// Times the media sequence started over.
+    resets: u32,
+}
+
+/// Where finished parts are written.
+enum Target {
+    Dir(PathBuf),
+    Writer(Box<dyn AsyncWrite + Unpin + Send>),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Segment { sequence, uri, error } => write!(f, "segment {} ({}): {}", sequence, uri, error),
+            DownloadError::Decrypt { sequence, uri, error } => write!(f, "segment {} ({}): {}", sequence, uri, error),
+            DownloadError::Output(e) => write!(f, "can't write output: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DownloadError::Segment { error, .. } => Some(error),
+            DownloadError::Decrypt { error, .. } => Some(error),
+            DownloadError::Output(e) => Some(e),
+        }
+    }
+}
+
+impl DownloadError {
+    /// Whether trying the segment again may succeed.
+    fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Segment { error, .. } => error.is_transient(),
+            DownloadError::Decrypt { error, .. } => error.is_transient(),
+            DownloadError::Output(_) => false,
+        }
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(e: io::Error) -> Self {
+        DownloadError::Output(e)
+    }
+}
+
+impl Part<'_> {
+    fn sequence(&self) -> u64 {
+        match self {
+            Part::Init { segment, .. } | Part::Media(segment) => segment.sequence,
+        }
+    }
+
+    fn uri(&self) -> &str {
+        match self {
+            Part::Init { map, .. } => &map.uri,
+            Part::Media(segment) => &segment.uri,
+        }
+    }
+
+    fn byte_range(&self) -> Option<ByteRange> {
+        match self {
+            Part::Init { map, .. } => map.byte_range,
+            Part::Media(segment) => segment.byte_range,
+        }
+    }
+
+    /// Key the part is encrypted with, `None` if it's in the clear.
+    ///
+    /// An initialization section has no media sequence number to derive an
+    /// IV from, so one encrypted with AES-128 needs a key with an `IV`
+    /// (RFC 8216, section 4.3.2.5). SAMPLE-AES leaves initialization
+    /// sections clear.
+    fn key(&self) -> Result<Option<&Key>, DecryptError> {
+        let (segment, init) = match self {
+            Part::Init { segment, .. } => (segment, true),
+            Part::Media(segment) => (segment, false),
+        };
+        if segment.keys.is_empty() {
+            return Ok(None);
+        }
+        let key = segment.key().ok_or(DecryptError::NoIdentityKey)?;
+        match key.method {
+            KeyMethod::None => Ok(None),
+            KeyMethod::Aes128 if init && key.iv.is_none() => Err(DecryptError::MissingIv),
+            KeyMethod::SampleAes if init => Ok(None),
+            KeyMethod::Aes128 | KeyMethod::SampleAes => Ok(Some(key)),
+        }
+    }
+
+    /// Name of the part's file in an output directory, prefixed with `r1-`,
+    /// `r2-` and so on once the media sequence started over.
+    fn file_name(&self, resets: u32) -> String {
+        let path = self.uri().split(['?', '#']).next().unwrap_or_default();
+        let extension = Path::new(path).extension().and_then(|extension| extension.to_str()).unwrap_or("ts");
+        let prefix = match resets {
+            0 => String::new(),
+            resets => format!("r{}-", resets),
+        };
+        match self {
+            Part::Init { segment, .. } => format!("{}init-{}.{}", prefix, segment.sequence, extension),
+            Part::Media(segment) => format!("{}{}.{}", prefix, segment.sequence, extension),
+        }
+    }
+}
+
+impl Sink {
+    pub async fn open(output: &Output) -> io::Result<Self> {
+        let target = match output {
+            Output::Dir(dir) => {
+                tokio::fs::create_dir_all(dir).await?;
+                Target::Dir(dir.clone())
+            }
+            Output::File(path) if path.as_os_str() == "-" => Target::Writer(Box::new(tokio::io::stdout())),
+            Output::File(path) => Target::Writer(Box::new(tokio::fs::File::create(path).await?)),
+        };
+        Ok(Sink { target, map: None, resets: 0 })
+    }
+
+    /// Starts over after the media sequence did, so that the segments to
+    /// come don't overwrite those with the same numbers in a directory. The
+    /// next initialization section is written again.
+    pub fn reset(&mut self) {
+        self.resets += 1;
+        self.map = None;
+    }
+
+    /// Writes `part`, remembering an initialization section once it's out.
+    async fn write(&mut self, part: &Part<'_>, data: &[u8]) -> io::Result<()> {
+        match &mut self.target {
+            Target::Dir(dir) => tokio::fs::write(dir.join(part.file_name(self.resets)), data).await?,
+            Target::Writer(writer) => writer.write_all(data).await?,
+        }
+        if let Part::Init { map, .. } = part {
+            self.map = Some((*map).clone());
+        }
+        Ok(())
+    }
+
+    /// Flushes and closes the output.
+    pub async fn finish(self) -> io::Result<()> {
+        match self.target {
+            Target::Dir(_) => Ok(()),
+            Target::Writer(mut writer) => writer.shutdown().await,
+        }
+    }
+}
+
+impl<'a> Downloader<'a> {
+    /// Fetches through `fetcher`, 4 segments at a time, trying each up to 3
+    /// more times.
+    pub fn new(fetcher: &'a Fetcher) -> Self {
+        Downloader { fetcher, jobs: 4, retries: 3, retry_delay: Duration::from_millis(500), keys: KeyCache::default() }
+    }
+
+    /// Number of segments fetched at once.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Times a segment failing with a transient error is tried again.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Pause before the first retry, growing linearly with the next ones.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Downloads the segments of `playlist`, loaded from `base_url`, to
+    /// `output`, each preceded by its initialization section when it
+    /// changes. `on_part` is told about each part once written, in order.
+    /// Segments marked with `EXT-X-GAP` are skipped, and those encrypted
+    /// with AES-128, or SAMPLE-AES for MPEG-TS, are written decrypted.
+    pub async fn download(
+        &self,
+        playlist: &Playlist,
+        base_url: &str,
+        output: &Output,
+        on_part: impl FnMut(&SegmentReport),
+    ) -> Result<(), DownloadError> {
+        let mut sink = Sink::open(output).await?;
+        self.download_segments(&playlist.segments, base_url, &mut sink, on_part).await?;
+        sink.finish().await?;
+        Ok(())
+    }
+
+    /// Downloads `segments` of a playlist loaded from `base_url` to `sink`,
+    /// like [`download`](Self::download). An initialization section already
+    /// written to `sink` isn't written again.
+    pub async fn download_segments<'p>(
+        &self,
+        segments: impl IntoIterator<Item = &'p Segment>,
+        base_url: &str,
+        sink: &mut Sink,
+        mut on_part: impl FnMut(&SegmentReport),
+    ) -> Result<(), DownloadError> {
+        let mut parts = Vec::new();
+        let mut map_before = sink.map.clone();
+        for segment in segments.into_iter().filter(|segment| !segment.gap) {
+            if let Some(map) = &segment.map {
+                if map_before.as_ref() != Some(map) {
+                    parts.push(Part::Init { map, segment });
+                    map_before = Some(map.clone());
+                }
+            }
+            parts.push(Part::Media(segment));
+        }
+
+        // buffered() runs up to `jobs` fetches at once but yields them in order
+        let mut fetched = stream::iter(&parts).map(|part| self.fetch(part, base_url)).buffered(self.jobs);
+        for part in &parts {
+            let (data, report) = fetched.next().await.expect("one result per part")?;
+            sink.write(part, &data).await?;
+            on_part(&report);
+        }
+        Ok(())
+    }
+
+    async fn fetch(&self, part: &Part<'_>, base_url: &str) -> Result<(Vec<u8>, SegmentReport), DownloadError> {
+        let source = Source::parse(&fetch::resolve(base_url, part.uri()));
+        let started = Instant::now();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.fetch_once(part, base_url, &source).await {
+                Ok(data) => {
+                    let report = SegmentReport {
+                        sequence: part.sequence(),
+                        uri: part.uri().to_string(),
+                        init: matches!(part, Part::Init { .. }),
+                        bytes: data.len(),
+                        elapsed: started.elapsed(),
+                        attempts,
+                    };
+                    return Ok((data, report));
+                }
+                Err(error) if error.is_transient() && attempts <= self.retries => {
+                    tokio::time::sleep(self.retry_delay * attempts).await;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Fetches `part` from `source`, decrypting it as it arrives.
+    async fn fetch_once(&self, part: &Part<'_>, base_url: &str, source: &Source) -> Result<Vec<u8>, DownloadError> {
+        let decrypt_error = |error| DownloadError::Decrypt { sequence: part.sequence(), uri: part.uri().to_string(), error };
+        let key = match part.key().map_err(decrypt_error)? {
+            Some(key) => {
+                let location = fetch::resolve(base_url, key.uri.as_deref().unwrap_or_default());
+                let secret = self.keys.get(self.fetcher, &location).await.map_err(decrypt_error)?;
+                Some((key.method, secret, decrypt::iv(key, part.sequence())))
+            }
+            None => None,
+        };
+        // AES-128 is decrypted as the data arrives, SAMPLE-AES once it's all there
+        let mut decryptor = match key {
+            Some((KeyMethod::Aes128, secret, iv)) => Some(Decryptor::new(&secret, &iv)),
+            _ => None,
+        };
+        let range = part.byte_range().map(|range| range.offset..range.offset + range.length);
+        let mut data = Vec::new();
+        self.fetcher
+            .stream_range(source, range, |chunk| match &mut decryptor {
+                Some(decryptor) => decryptor.update(chunk, &mut data),
+                None => data.extend_from_slice(chunk),
+            })
+            .await
+            .map_err(|error| DownloadError::Segment { sequence: part.sequence(), uri: part.uri().to_string(), error })?;
+        if let Some(decryptor) = decryptor {
+            decryptor.finish(&mut data).map_err(decrypt_error)?;
+        }
+        if let Some((KeyMethod::SampleAes, secret, iv)) = key {
+            data = sample_aes::decrypt_segment(&data, &secret, &iv).map_err(decrypt_error)?;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use aes::{
+        cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit},
+        Aes128,
+    };
+    use base64::Engine;
+    use hyper::{header, Body, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::test_server::{fetcher, serve, temp_path};
+
+    const PLAYLIST: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-MEDIA-SEQUENCE:10
+#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"4@2\"
+#EXTINF:4,
+slow.m4s
+#EXTINF:4,
+flaky.m4s
+#EXT-X-GAP
+#EXTINF:4,
+missing.m4s
+#EXTINF:4,
+/media/fast.m4s
+#EXT-X-ENDLIST
+";
+
+    const KEY: [u8; 16] = *b"key from the url";
+    const DATA_KEY: [u8; 16] = *b"key in data uri!";
+    const IV: [u8; 16] = [0xa5; 16];
+
+    fn encrypt(key: &[u8; 16], iv: &[u8; 16], plaintext: &[u8]) -> Vec<u8> {
+        let mut buf = plaintext.to_vec();
+        buf.resize(plaintext.len() + 16, 0);
+        let len = cbc::Encryptor::<Aes128>::new(key.into(), iv.into()).encrypt_padded_mut::<Pkcs7>(&mut buf, plaintext.len()).unwrap().len();
+        buf.truncate(len);
+        buf
+    }
+
+    /// Stand-in HLS origin. `slow.m4s` finishes last, `flaky.m4s` and
+    /// `flaky-init.mp4` fail once and `init.mp4` honors ranges.
+    /// `one.ts`, `two.ts` and `three.ts` are encrypted as in
+    /// [`encrypted_playlist`]. Counts requests per path.
+    fn origin() -> (SocketAddr, Arc<Mutex<HashMap<String, u32>>>) {
+        let requests = Arc::new(Mutex::new(HashMap::new()));
+        let counts = requests.clone();
+        let addr = serve(move |req: Request<Body>| {
+            let requests = requests.clone();
+            async move {
+                let path = req.uri().path().to_string();
+                let count = {
+                    let mut requests = requests.lock().unwrap();
+                    let count = requests.entry(path.clone()).or_insert(0);
+                    *count += 1;
+                    *count
+                };
+                match path.as_str() {
+                    "/live/index.m3u8" => Response::new(Body::from(PLAYLIST)),
+                    "/live/init.mp4" => {
+                        assert_eq!(req.headers()[header::RANGE], "bytes=2-5");
+                        Response::builder().status(StatusCode::PARTIAL_CONTENT).header(header::CONTENT_RANGE, "bytes 2-5/10").body(Body::from("INIT")).unwrap()
+                    }
+                    "/live/slow.m4s" => {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        Response::new(Body::from("slow;"))
+                    }
+                    "/live/flaky.m4s" if count == 1 => {
+                        Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::empty()).unwrap()
+                    }
+                    "/live/flaky.m4s" => Response::new(Body::from("flaky;")),
+                    "/live/flaky-init.mp4" if count == 1 => {
+                        Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::empty()).unwrap()
+                    }
+                    "/live/flaky-init.mp4" => Response::new(Body::from("init;")),
+                    "/media/fast.m4s" => Response::new(Body::from("fast;")),
+                    "/live/key.bin" => Response::new(Body::from(KEY.to_vec())),
+                    "/live/one.ts" => Response::new(Body::from(encrypt(&KEY, &u128::to_be_bytes(5), b"one;"))),
+                    "/live/two.ts" => Response::new(Body::from(encrypt(&KEY, &u128::to_be_bytes(6), b"two;"))),
+                    // ranges of this one are left to the client
+                    "/live/three.ts" => {
+                        let mut body = encrypt(&DATA_KEY, &IV, b"three, first half;");
+                        body.extend(encrypt(&DATA_KEY, &IV, b"second half;"));
+                        Response::new(Body::from(body))
+                    }
+                    _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+                }
+            }
+        });
+        (addr, counts)
+    }
+
+    /// Key rotation from a key URL to a `data:` key with an explicit IV,
+    /// then back to the clear.
+    fn encrypted_playlist() -> String {
+        let data_key = base64::engine::general_purpose::STANDARD.encode(DATA_KEY);
+        format!(
+            "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-MEDIA-SEQUENCE:5
+#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"
+#EXTINF:4,
+one.ts
+#EXTINF:4,
+two.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"data:;base64,{}\",IV=0x{}
+#EXT-X-BYTERANGE:32@0
+#EXTINF:4,
+three.ts
+#EXT-X-BYTERANGE:16
+#EXTINF:4,
+three.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:4,
+/media/fast.m4s
+#EXT-X-ENDLIST
+",
+            data_key,
+            "a5".repeat(16)
+        )
+    }
+
+    #[tokio::test]
+    async fn segments_are_written_in_order() {
+        let (addr, requests) = origin();
+        let base_url = format!("http://{}/live/index.m3u8", addr);
+        let fetcher = fetcher();
+        let playlist = Playlist::parse(std::str::from_utf8(&fetcher.load(&Source::parse(&base_url)).await.unwrap()).unwrap()).unwrap();
+
+        let path = temp_path("ordered.mp4");
+        let mut reports = Vec::new();
+        let downloader = Downloader::new(&fetcher).jobs(4).retry_delay(Duration::from_millis(10));
+        downloader.download(&playlist, &base_url, &Output::File(path.clone()), |report| reports.push(report.clone())).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "INITslow;flaky;fast;");
+        std::fs::remove_file(&path).unwrap();
+        let parts: Vec<(u64, &str, bool, u32)> = reports.iter().map(|r| (r.sequence, r.uri.as_str(), r.init, r.attempts)).collect();
+        assert_eq!(parts, [(10, "init.mp4", true, 1), (10, "slow.m4s", false, 1), (11, "flaky.m4s", false, 2), (13, "/media/fast.m4s", false, 1)]);
+        assert!(reports[1].elapsed >= Duration::from_millis(200));
+        assert_eq!(reports[3].bytes, 5);
+        assert!(!requests.lock().unwrap().contains_key("/live/missing.m4s"));
+    }
+
+    #[tokio::test]
+    async fn segments_are_written_to_a_directory() {
+        let (addr, _) = origin();
+        let base_url = format!("http://{}/live/index.m3u8", addr);
+        let fetcher = fetcher();
+        let playlist = Playlist::parse(PLAYLIST).unwrap();
+
+        let dir = temp_path("segments");
+        let downloader = Downloader::new(&fetcher).retry_delay(Duration::from_millis(10));
+        downloader.download(&playlist, &base_url, &Output::Dir(dir.clone()), |_| {}).await.unwrap();
+
+        let mut files: Vec<String> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        files.sort();
+        assert_eq!(files, ["10.m4s", "11.m4s", "13.m4s", "init-10.mp4"]);
+        assert_eq!(std::fs::read_to_string(dir.join("11.m4s")).unwrap(), "flaky;");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn encrypted_segments_are_decrypted() {
+        let (addr, requests) = origin();
+        let base_url = format!("http://{}/live/index.m3u8", addr);
+        let fetcher = fetcher();
+        let playlist = Playlist::parse(&encrypted_playlist()).unwrap();
+
+        let path = temp_path("decrypted.ts");
+        let mut sizes = Vec::new();
+        let downloader = Downloader::new(&fetcher).jobs(4);
+        downloader.download(&playlist, &base_url, &Output::File(path.clone()), |report| sizes.push(report.bytes)).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one;two;three, first half;second half;fast;");
+        assert_eq!(sizes, [4, 4, 18, 12, 5]);
+        std::fs::remove_file(&path).unwrap();
+        // the key is loaded once for both segments using it
+        assert_eq!(requests.lock().unwrap()["/live/key.bin"], 1);
+    }
+
+    #[tokio::test]
+    async fn wrong_keys_are_reported() {
+        let (addr, _) = origin();
+        let base_url = format!("http://{}/live/index.m3u8", addr);
+        let fetcher = fetcher();
+        let path = temp_path("undecryptable.ts");
+        let downloader = Downloader::new(&fetcher).retries(0);
+
+        let playlist = Playlist::parse(&encrypted_playlist().replace("one.ts", "two.ts")).unwrap();
+        let result = downloader.download(&playlist, &base_url, &Output::File(path.clone()), |_| {}).await;
+        assert!(matches!(result, Err(DownloadError::Decrypt { sequence: 5, error: DecryptError::Padding, .. })), "{:?}", result);
+        let playlist = Playlist::parse(&encrypted_playlist().replace("key.bin", "gone.bin")).unwrap();
+        let result = downloader.download(&playlist, &base_url, &Output::File(path.clone()), |_| {}).await;
+        assert!(matches!(result, Err(DownloadError::Decrypt { error: DecryptError::KeyFetch { .. }, .. })), "{:?}", result);
+        // an AES-128 initialization section can't be decrypted without an IV
+        let playlist = Playlist::parse(&encrypted_playlist().replace("#EXTINF:4,\none.ts", "#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"4@2\"\n#EXTINF:4,\none.ts")).unwrap();
+        let result = downloader.download(&playlist, &base_url, &Output::File(path.clone()), |_| {}).await;
+        assert!(matches!(result, Err(DownloadError::Decrypt { sequence: 5, error: DecryptError::MissingIv, .. })), "{:?}", result);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_initialization_sections_are_fetched_again() {
+        let (addr, _) = origin();
+        let base_url = format!("http://{}/live/index.m3u8", addr);
+        let fetcher = fetcher();
+        let playlist = Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MAP:URI=\"flaky-init.mp4\"\n#EXTINF:4,\n/media/fast.m4s\n").unwrap();
+
+        let path = temp_path("init-again.mp4");
+        let mut sink = Sink::open(&Output::File(path.clone())).await.unwrap();
+        let downloader = Downloader::new(&fetcher).retries(0);
+        let result = downloader.download_segments(&playlist.segments, &base_url, &mut sink, |_| {}).await;
+        assert!(matches!(result, Err(DownloadError::Segment { ref uri, .. }) if uri == "flaky-init.mp4"), "{:?}", result);
+        assert!(sink.map.is_none());
+        downloader.download_segments(&playlist.segments, &base_url, &mut sink, |_| {}).await.unwrap();
+        sink.finish().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "init;fast;");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_segments_are_reported() {
+        let (addr, requests) = origin();
+        let base_url = format!("http://{}/live/index.m3u8", addr);
+        let fetcher = fetcher();
+        let playlist = Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4,\nflaky.m4s\n#EXTINF:4,\ngone.m4s\n").unwrap();
+
+        let path = temp_path("failed.ts");
+        let downloader = Downloader::new(&fetcher).retries(0);
+        match downloader.download(&playlist, &base_url, &Output::File(path.clone()), |_| {}).await {
+            Err(DownloadError::Segment { sequence: 0, uri, error: FetchError::Status(StatusCode::SERVICE_UNAVAILABLE) }) => {
+                assert_eq!(uri, "flaky.m4s")
+            }
+            other => panic!("{:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+        // not found isn't retried
+        let before = requests.lock().unwrap().get("/live/gone.m4s").copied().unwrap_or(0);
+        let downloader = Downloader::new(&fetcher).retries(3);
+        let playlist = Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4,\ngone.m4s\n").unwrap();
+        let result = downloader.download(&playlist, &base_url, &Output::File(path.clone()), |_| {}).await;
+        assert!(matches!(result, Err(DownloadError::Segment { error: FetchError::Status(StatusCode::NOT_FOUND), .. })));
+        assert_eq!(requests.lock().unwrap()["/live/gone.m4s"], before + 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
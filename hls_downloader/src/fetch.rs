@@ -0,0 +1,456 @@
+//! Loading playlists and segments from the network, local files or stdin.
+
+use std::{fmt, io, ops::Range, path::{Path, PathBuf}};
+
+use base64::Engine;
+use futures::StreamExt;
+use hyper::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use url_stream::{HttpStream, StatusError, TimeoutError, UrlStreamClient};
+
+/// Size of the pieces files are read in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where a playlist is loaded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Url(String),
+    File(PathBuf),
+    /// `data:` URI, carrying the data itself.
+    Data(String),
+    Stdin,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    InvalidUrl(url::ParseError),
+    Http(url_stream::Error),
+    Status(StatusCode),
+    /// No response, or no data for too long.
+    Timeout,
+    Io(io::Error),
+}
+
+/// Downloads over `http` and `https`.
+pub struct Fetcher {
+    client: UrlStreamClient,
+}
+
+impl Source {
+    /// `-` is stdin, `http://` and `https://` locations are URLs, `data:`
+    /// ones data URIs and anything else a path.
+    pub fn parse(location: &str) -> Self {
+        if location == "-" {
+            Source::Stdin
+        } else if location.starts_with("http://") || location.starts_with("https://") {
+            Source::Url(location.to_string())
+        } else if location.starts_with("data:") {
+            Source::Data(location.to_string())
+        } else {
+            Source::File(PathBuf::from(location))
+        }
+    }
+
+    /// Location relative URIs in the playlist resolve against; empty for
+    /// stdin.
+    pub fn base_url(&self) -> String {
+        match self {
+            Source::Url(url) => url.clone(),
+            Source::File(path) => path.to_string_lossy().into_owned(),
+            Source::Data(_) | Source::Stdin => String::new(),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Url(url) => f.write_str(url),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Data(_) => f.write_str("<data uri>"),
+            Source::Stdin => f.write_str("<stdin>"),
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::InvalidUrl(e) => write!(f, "invalid url: {}", e),
+            FetchError::Http(e) => write!(f, "http error: {}", e),
+            FetchError::Status(code) => write!(f, "unexpected response status {}", code),
+            FetchError::Timeout => f.write_str("timed out"),
+            FetchError::Io(e) => write!(f, "i/o error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FetchError::InvalidUrl(e) => Some(e),
+            FetchError::Http(e) => Some(e.as_ref()),
+            FetchError::Io(e) => Some(e),
+            FetchError::Status(_) | FetchError::Timeout => None,
+        }
+    }
+}
+
+impl From<url::ParseError> for FetchError {
+    fn from(e: url::ParseError) -> Self {
+        FetchError::InvalidUrl(e)
+    }
+}
+
+impl From<url_stream::Error> for FetchError {
+    fn from(e: url_stream::Error) -> Self {
+        if let Some(e) = e.downcast_ref::<StatusError>() {
+            return FetchError::Status(e.status);
+        }
+        if e.is::<TimeoutError>() {
+            return FetchError::Timeout;
+        }
+        match e.downcast::<io::Error>() {
+            Ok(e) => FetchError::from(*e),
+            Err(e) => FetchError::Http(e),
+        }
+    }
+}
+
+impl From<io::Error> for FetchError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => FetchError::Timeout,
+            _ => FetchError::Io(e),
+        }
+    }
+}
+
+impl Fetcher {
+    /// Fetches URLs through `client`. Segments are tried again by the
+    /// [`Downloader`](crate::download::Downloader), so `client` is best
+    /// left without a [`RetryPolicy`](url_stream::RetryPolicy) of its own.
+    pub fn new(client: UrlStreamClient) -> Self {
+        Fetcher { client }
+    }
+
+    /// Reads all of `source`.
+    pub async fn load(&self, source: &Source) -> Result<Vec<u8>, FetchError> {
+        Ok(self.load_playlist(source).await?.0)
+    }
+
+    /// Reads all of `source`, with the location URIs in it resolve against:
+    /// where redirects led for a URL.
+    pub async fn load_playlist(&self, source: &Source) -> Result<(Vec<u8>, String), FetchError> {
+        let mut data = Vec::new();
+        let location = match source {
+            Source::Url(url) => self.stream_url(url, None, |chunk| data.extend_from_slice(chunk)).await?,
+            source => {
+                self.stream_range(source, None, |chunk| data.extend_from_slice(chunk)).await?;
+                source.base_url()
+            }
+        };
+        Ok((data, location))
+    }
+
+    /// Reads `source`, or the `range` of it, handing it to `on_chunk` piece
+    /// by piece as it arrives.
+    pub async fn stream_range(&self, source: &Source, range: Option<Range<u64>>, mut on_chunk: impl FnMut(&[u8])) -> Result<(), FetchError> {
+        match source {
+            Source::Url(url) => self.stream_url(url, range, on_chunk).await.map(drop),
+            Source::File(path) => {
+                let mut file = tokio::fs::File::open(path).await?;
+                let mut remaining = match &range {
+                    Some(range) => {
+                        file.seek(io::SeekFrom::Start(range.start)).await?;
+                        range.end - range.start
+                    }
+                    None => u64::MAX,
+                };
+                let mut buf = vec![0; CHUNK_SIZE];
+                while remaining > 0 {
+                    let len = file.read(&mut buf[..CHUNK_SIZE.min(remaining.try_into().unwrap_or(CHUNK_SIZE))]).await?;
+                    if len == 0 {
+                        if range.is_some() {
+                            return Err(past_the_end());
+                        }
+                        break;
+                    }
+                    on_chunk(&buf[..len]);
+                    remaining -= len as u64;
+                }
+                Ok(())
+            }
+            Source::Data(uri) => {
+                let data = decode_data_uri(uri)?;
+                on_chunk(slice(&data, range)?);
+                Ok(())
+            }
+            Source::Stdin => {
+                let mut data = Vec::new();
+                tokio::io::stdin().read_to_end(&mut data).await?;
+                on_chunk(slice(&data, range)?);
+                Ok(())
+            }
+        }
+    }
+
+    /// Streams `url`, following redirects, and returns where they led.
+    async fn stream_url(&self, url: &str, range: Option<Range<u64>>, mut on_chunk: impl FnMut(&[u8])) -> Result<String, FetchError> {
+        let url = url::Url::parse(url)?;
+        let mut stream = match &range {
+            Some(range) => HttpStream::open_range(&url, &self.client, range.clone()).await?,
+            None => HttpStream::open(&url, &self.client).await?,
+        };
+        let mut received = 0;
+        while let Some(data) = stream.next().await {
+            let data = data?;
+            received += data.len() as u64;
+            on_chunk(&data);
+        }
+        match range {
+            Some(range) if received < range.end - range.start => Err(past_the_end()),
+            _ => Ok(stream.url().to_string()),
+        }
+    }
+}
+
+fn past_the_end() -> FetchError {
+    FetchError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "range past the end of the resource"))
+}
+
+fn slice(data: &[u8], range: Option<Range<u64>>) -> Result<&[u8], FetchError> {
+    match range {
+        Some(range) if range.end > data.len() as u64 => Err(past_the_end()),
+        Some(range) => Ok(&data[range.start as usize..range.end as usize]),
+        None => Ok(data),
+    }
+}
+
+/// Decodes the data of a `data:` URI (RFC 2397).
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>, FetchError> {
+    let invalid = |reason: &str| FetchError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("invalid data uri: {}", reason)));
+    let (header, data) = uri.strip_prefix("data:").and_then(|rest| rest.split_once(',')).ok_or_else(|| invalid("no data"))?;
+    if header.ends_with(";base64") {
+        return base64::engine::general_purpose::STANDARD.decode(data.trim()).map_err(|_| invalid("bad base64"));
+    }
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut bytes = data.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                let hex = std::str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                decoded.push(hex.ok_or_else(|| invalid("bad percent-encoding"))?);
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    Ok(decoded)
+}
+
+/// Resolves the URI `reference` from a playlist against `base`, the URL or
+/// path the playlist was loaded from (RFC 3986, section 5.2).
+pub fn resolve(base: &str, reference: &str) -> String {
+    if url::Url::parse(reference).is_ok() {
+        return reference.to_string();
+    }
+    match Source::parse(base) {
+        Source::Url(base) => url::Url::parse(&base).and_then(|base| base.join(reference)).map_or_else(|_| reference.to_string(), String::from),
+        Source::File(path) => path.parent().unwrap_or(Path::new("")).join(reference).to_string_lossy().into_owned(),
+        Source::Data(_) | Source::Stdin => reference.to_string(),
+    }
+}
+
+impl FetchError {
+    /// Whether trying again may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            FetchError::InvalidUrl(_) => false,
+            FetchError::Http(e) => url_stream::retry::is_transient(e),
+            FetchError::Timeout => true,
+            FetchError::Status(status) => {
+                status.is_server_error() || *status == StatusCode::REQUEST_TIMEOUT || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            FetchError::Io(e) => e.kind() != io::ErrorKind::NotFound,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+
+    use hyper::{header, Body, Request, Response};
+    use url_stream::{HttpVersion, Timeouts};
+
+    use super::*;
+    use crate::test_server::{client, fetcher, serve, serve_tls};
+
+    #[tokio::test]
+    async fn redirects_are_followed() {
+        let addr = serve(|req: Request<Body>| async move {
+            let redirect = |location: &str| Response::builder().status(StatusCode::FOUND).header(header::LOCATION, location).body(Body::empty()).unwrap();
+            match req.uri().path() {
+                "/old/index.m3u8" => redirect("/cdn/../new/index.m3u8"),
+                "/new/index.m3u8" => Response::new(Body::from("#EXTM3U")),
+                "/loop" => redirect("loop"),
+                "/elsewhere" => redirect("file:///etc/passwd"),
+                "/range" => {
+                    assert_eq!(req.headers()[header::RANGE], "bytes=1-2");
+                    redirect("new/index.m3u8")
+                }
+                _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+            }
+        });
+        let fetcher = fetcher();
+        let url = |path: &str| Source::Url(format!("http://{}{}", addr, path));
+
+        let (data, location) = fetcher.load_playlist(&url("/old/index.m3u8")).await.unwrap();
+        assert_eq!((data.as_slice(), location), (&b"#EXTM3U"[..], format!("http://{}/new/index.m3u8", addr)));
+        let mut data = Vec::new();
+        fetcher.stream_range(&url("/range"), Some(1..3), |chunk| data.extend_from_slice(chunk)).await.unwrap();
+        assert_eq!(data, b"EX");
+        let result = fetcher.load(&url("/loop")).await;
+        assert!(matches!(&result, Err(e @ FetchError::Http(_)) if e.to_string().contains("too many redirects")), "{:?}", result);
+        assert!(fetcher.load(&url("/elsewhere")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn partial_content_must_match_the_range() {
+        let addr = serve(|req: Request<Body>| async move {
+            let partial = |range: &str, body: &'static str| {
+                Response::builder().status(StatusCode::PARTIAL_CONTENT).header(header::CONTENT_RANGE, range).body(Body::from(body)).unwrap()
+            };
+            match req.uri().path() {
+                "/exact" => partial("bytes 1-2/7", "EX"),
+                "/shifted" => partial("bytes 2-3/7", "XT"),
+                _ => Response::new(Body::from("#EXTM3U")),
+            }
+        });
+        let fetcher = fetcher();
+        let range = |path: &str, range: Range<u64>| {
+            let source = Source::Url(format!("http://{}{}", addr, path));
+            let fetcher = &fetcher;
+            async move {
+                let mut data = Vec::new();
+                fetcher.stream_range(&source, Some(range), |chunk| data.extend_from_slice(chunk)).await.map(|()| data)
+            }
+        };
+
+        assert_eq!(range("/exact", 1..3).await.unwrap(), b"EX");
+        // a server ignoring Range sends it all
+        assert_eq!(range("/ignored", 1..3).await.unwrap(), b"EX");
+        let result = range("/shifted", 1..3).await;
+        assert!(matches!(&result, Err(FetchError::Io(e)) if e.kind() == io::ErrorKind::InvalidData), "{:?}", result);
+        let result = range("/ignored", 5..20).await;
+        assert!(matches!(&result, Err(FetchError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn stalled_responses_time_out() {
+        let addr = serve(|req: Request<Body>| async move {
+            match req.uri().path() {
+                "/slow-head" => {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    Response::new(Body::empty())
+                }
+                _ => {
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        sender.send_data("first;".into()).await.unwrap();
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        let _ = sender.send_data("late".into()).await;
+                    });
+                    Response::new(body)
+                }
+            }
+        });
+        let timeouts = Timeouts::new().first_byte(Duration::from_millis(200)).read_idle(Duration::from_millis(200));
+        let fetcher = Fetcher::new(client().timeouts(timeouts).build());
+        let url = |path: &str| Source::Url(format!("http://{}{}", addr, path));
+
+        let started = std::time::Instant::now();
+        assert!(matches!(fetcher.load(&url("/slow-head")).await, Err(FetchError::Timeout)));
+        let mut data = Vec::new();
+        let result = fetcher.stream_range(&url("/slow-body"), None, |chunk| data.extend_from_slice(chunk)).await;
+        assert!(matches!(result, Err(FetchError::Timeout)), "{:?}", result);
+        assert_eq!(data, b"first;");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn streams_in_flight_are_limited() {
+        let (active, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (counted, peak) = (active.clone(), most.clone());
+        let addr = serve(move |req: Request<Body>| {
+            let (active, most) = (counted.clone(), peak.clone());
+            async move {
+                assert_eq!(req.version(), hyper::Version::HTTP_2);
+                most.fetch_max(active.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+                Response::new(Body::from("segment"))
+            }
+        });
+        let fetcher = Fetcher::new(client().http_version(HttpVersion::Http2PriorKnowledge).http2_max_concurrent_streams(2).build());
+        let url = Source::Url(format!("http://{}/seg.ts", addr));
+        let loads = futures::future::join_all((0..6).map(|_| fetcher.load(&url))).await;
+        assert!(loads.iter().all(|load| matches!(load, Ok(data) if data == b"segment")));
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    async fn version_seen(req: Request<Body>) -> Response<Body> {
+        Response::new(Body::from(format!("{:?}", req.version())))
+    }
+
+    #[tokio::test]
+    async fn http2_through_alpn() {
+        let (addr, pem) = serve_tls(&[b"h2", b"http/1.1"], version_seen);
+        let (http1_addr, http1_pem) = serve_tls(&[b"http/1.1"], version_seen);
+        let version_seen = |version: HttpVersion, addr: std::net::SocketAddr| {
+            let tls = crate::tls::client_config(&[pem.clone(), http1_pem.clone()], true, None, &[]).unwrap();
+            let fetcher = Fetcher::new(client().tls(tls).http_version(version).build());
+            async move { fetcher.load(&Source::Url(format!("https://localhost:{}/seg.ts", addr.port()))).await.unwrap() }
+        };
+        assert_eq!(version_seen(HttpVersion::Auto, addr).await, b"HTTP/2.0");
+        assert_eq!(version_seen(HttpVersion::Http1, addr).await, b"HTTP/1.1");
+        // servers without h2 get HTTP/1.1
+        assert_eq!(version_seen(HttpVersion::Auto, http1_addr).await, b"HTTP/1.1");
+        std::fs::remove_file(pem).unwrap();
+        std::fs::remove_file(http1_pem).unwrap();
+    }
+
+    #[tokio::test]
+    async fn h2c_with_prior_knowledge() {
+        let url = Source::Url(format!("http://{}/seg.ts", serve(version_seen)));
+        let version_seen = |version: HttpVersion| Fetcher::new(client().http_version(version).build());
+        assert_eq!(version_seen(HttpVersion::Auto).load(&url).await.unwrap(), b"HTTP/1.1");
+        assert_eq!(version_seen(HttpVersion::Http2PriorKnowledge).load(&url).await.unwrap(), b"HTTP/2.0");
+    }
+
+    #[test]
+    fn references_resolve_against_the_playlist() {
+        let base = "https://cdn.example.com/live/stream/index.m3u8?token=1";
+        assert_eq!(resolve(base, "seg-1.ts"), "https://cdn.example.com/live/stream/seg-1.ts");
+        assert_eq!(resolve(base, "seg-1.ts?part=2"), "https://cdn.example.com/live/stream/seg-1.ts?part=2");
+        assert_eq!(resolve(base, "../audio/./a.ts"), "https://cdn.example.com/live/audio/a.ts");
+        assert_eq!(resolve(base, "/other/b.ts"), "https://cdn.example.com/other/b.ts");
+        assert_eq!(resolve(base, "//mirror.example.com/c.ts"), "https://mirror.example.com/c.ts");
+        assert_eq!(resolve(base, "http://elsewhere.example.com/d.ts"), "http://elsewhere.example.com/d.ts");
+        assert_eq!(resolve("http://example.com", "e.ts"), "http://example.com/e.ts");
+        assert_eq!(resolve("http://example.com/a/b.m3u8", "../../../f.ts"), "http://example.com/f.ts");
+        assert_eq!(resolve("captures/master.m3u8", "low/index.m3u8"), Path::new("captures/low/index.m3u8").to_string_lossy());
+        assert_eq!(resolve("-", "https://example.com/g.ts"), "https://example.com/g.ts");
+        assert_eq!(resolve(base, "data:;base64,AAEC"), "data:;base64,AAEC");
+    }
+
+    #[test]
+    fn data_uris_are_decoded() {
+        assert_eq!(decode_data_uri("data:application/octet-stream;base64,AAECAw==").unwrap(), [0, 1, 2, 3]);
+        assert_eq!(decode_data_uri("data:,a%20b%2C").unwrap(), b"a b,");
+        assert!(decode_data_uri("data:;base64,!!").is_err());
+        assert!(decode_data_uri("data:,%4").is_err());
+        assert!(decode_data_uri("data:abc").is_err());
+    }
+}
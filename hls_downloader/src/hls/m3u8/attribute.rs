@@ -0,0 +1,235 @@
+//! Attribute lists of M3U8 tags (RFC 8216, section 4.2).
+//!
+//! The type of a value depends on the attribute it belongs to, so the lexer
+//! only splits the list and [`Attribute`] converts values on request.
+
+use std::{fmt, io};
+
+type AttributeError = Box<dyn std::error::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value<'a> {
+    /// Contents of a quoted-string, without the quotes.
+    Quoted(&'a str),
+    Unquoted(&'a str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attribute<'a> {
+    pub name: &'a str,
+    value: Value<'a>,
+}
+
+/// Lexes `list`, rejecting malformed lists and repeated attribute names.
+pub fn parse(list: &str) -> Result<Vec<Attribute<'_>>, AttributeError> {
+    let mut attributes: Vec<Attribute> = Vec::new();
+    let mut rest = list.trim();
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('=').ok_or_else(|| AttributeError::from(format!("attribute without value in {}", list)))?;
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'-') {
+            return Err(AttributeError::from(format!("invalid attribute name {:?}", name)));
+        }
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find(['"', '\r', '\n']).filter(|&end| quoted[end..].starts_with('"'));
+                let end = end.ok_or_else(|| AttributeError::from(format!("unterminated quoted string for {}", name)))?;
+                (Value::Quoted(&quoted[..end]), &quoted[end + 1..])
+            }
+            None => {
+                let (value, after) = after.split_at(after.find(',').unwrap_or(after.len()));
+                if value.is_empty() || value.contains(['"', ' ', '\t']) {
+                    return Err(AttributeError::from(format!("invalid value {:?} for {}", value, name)));
+                }
+                (Value::Unquoted(value), after)
+            }
+        };
+        rest = match after.strip_prefix(',') {
+            Some(next) if !next.is_empty() => next,
+            None if after.trim().is_empty() => "",
+            _ => return Err(AttributeError::from(format!("unexpected {:?} after {}", after, name))),
+        };
+        if attributes.iter().any(|attribute| attribute.name == name) {
+            return Err(AttributeError::from(format!("attribute {} repeated", name)));
+        }
+        attributes.push(Attribute { name, value });
+    }
+    Ok(attributes)
+}
+
+impl<'a> Attribute<'a> {
+    fn unquoted(&self, kind: &str) -> Result<&'a str, AttributeError> {
+        match self.value {
+            Value::Unquoted(value) => Ok(value),
+            Value::Quoted(value) => Err(self.invalid(kind, value)),
+        }
+    }
+
+    fn invalid(&self, kind: &str, value: &str) -> AttributeError {
+        AttributeError::from(format!("{} must be a {}, not {:?}", self.name, kind, value))
+    }
+
+    pub fn decimal_integer(&self) -> Result<u64, AttributeError> {
+        let value = self.unquoted("decimal-integer")?;
+        if value.len() > 20 || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(self.invalid("decimal-integer", value));
+        }
+        value.parse().map_err(|_| self.invalid("decimal-integer", value))
+    }
+
+    /// Bytes of a hexadecimal-sequence, an odd digit count padded on the left.
+    pub fn hex_sequence(&self) -> Result<Vec<u8>, AttributeError> {
+        let value = self.unquoted("hexadecimal-sequence")?;
+        let digits = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X"));
+        let digits = digits.filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_hexdigit()));
+        let digits = digits.ok_or_else(|| self.invalid("hexadecimal-sequence", value))?;
+        let padded = if digits.len() % 2 == 1 { format!("0{}", digits) } else { digits.to_string() };
+        Ok((0..padded.len()).step_by(2).map(|i| u8::from_str_radix(&padded[i..i + 2], 16).unwrap()).collect())
+    }
+
+    pub fn decimal_float(&self) -> Result<f64, AttributeError> {
+        let value = self.unquoted("decimal-floating-point")?;
+        parse_float(value).ok_or_else(|| self.invalid("decimal-floating-point", value))
+    }
+
+    pub fn signed_float(&self) -> Result<f64, AttributeError> {
+        let value = self.unquoted("signed-decimal-floating-point")?;
+        let float = match value.strip_prefix('-') {
+            Some(magnitude) => parse_float(magnitude).map(|magnitude| -magnitude),
+            None => parse_float(value),
+        };
+        float.ok_or_else(|| self.invalid("signed-decimal-floating-point", value))
+    }
+
+    pub fn quoted_string(&self) -> Result<&'a str, AttributeError> {
+        match self.value {
+            Value::Quoted(value) => Ok(value),
+            Value::Unquoted(value) => Err(self.invalid("quoted-string", value)),
+        }
+    }
+
+    pub fn enumerated_string(&self) -> Result<&'a str, AttributeError> {
+        self.unquoted("enumerated-string")
+    }
+
+    /// `YES` or `NO`, the enumerated strings of boolean attributes.
+    pub fn yes_no(&self) -> Result<bool, AttributeError> {
+        match self.enumerated_string()? {
+            "YES" => Ok(true),
+            "NO" => Ok(false),
+            value => Err(self.invalid("YES or NO", value)),
+        }
+    }
+
+    /// `<width>x<height>`
+    pub fn resolution(&self) -> Result<(u32, u32), AttributeError> {
+        let value = self.unquoted("decimal-resolution")?;
+        let is_integer = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        value
+            .split_once('x')
+            .filter(|(width, height)| is_integer(width) && is_integer(height))
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+            .ok_or_else(|| self.invalid("decimal-resolution", value))
+    }
+}
+
+/// Writes the attribute as it was lexed.
+impl fmt::Display for Attribute<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Value::Quoted(value) => write!(f, "{}={}", self.name, Quoted(value)),
+            Value::Unquoted(value) => write!(f, "{}={}", self.name, value),
+        }
+    }
+}
+
+/// Writes a quoted-string. Values with characters a quoted-string can't
+/// hold are written as they are; see [`check_quotable`].
+pub struct Quoted<'a>(pub &'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.0)
+    }
+}
+
+/// Fails with `InvalidData` on the first of `values` holding `"`, CR or LF,
+/// which a quoted-string can't.
+pub fn check_quotable<'a>(values: impl IntoIterator<Item = &'a str>) -> io::Result<()> {
+    match values.into_iter().find(|value| value.contains(['"', '\r', '\n'])) {
+        Some(value) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} can't be written as a quoted-string", value))),
+        None => Ok(()),
+    }
+}
+
+/// Parses an unsigned decimal number, digits with an optional fraction.
+pub(super) fn parse_float(value: &str) -> Option<f64> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, "0"));
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if is_digits(whole) && is_digits(fraction) {
+        value.parse().ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_strings_keep_commas_and_case() {
+        let attributes = parse("BANDWIDTH=1280000,CODECS=\"avc1.64001f,mp4a.40.2\",URI=\"Media/Low.m3u8\",RESOLUTION=1280x720").unwrap();
+        let names: Vec<&str> = attributes.iter().map(|attribute| attribute.name).collect();
+        assert_eq!(names, ["BANDWIDTH", "CODECS", "URI", "RESOLUTION"]);
+        assert_eq!(attributes[0].decimal_integer().unwrap(), 1280000);
+        assert_eq!(attributes[1].quoted_string().unwrap(), "avc1.64001f,mp4a.40.2");
+        assert_eq!(attributes[2].quoted_string().unwrap(), "Media/Low.m3u8");
+        assert_eq!(attributes[3].resolution().unwrap(), (1280, 720));
+    }
+
+    #[test]
+    fn typed_values() {
+        let attributes = parse("IV=0x1a2B3,FRAME-RATE=29.97,TIME-OFFSET=-4.5,METHOD=AES-128,DEFAULT=YES,EMPTY=\"\"").unwrap();
+        assert_eq!(attributes[0].hex_sequence().unwrap(), [0x01, 0xa2, 0xb3]);
+        assert_eq!(attributes[1].decimal_float().unwrap(), 29.97);
+        assert_eq!(attributes[2].signed_float().unwrap(), -4.5);
+        assert!(attributes[2].decimal_float().is_err());
+        assert_eq!(attributes[3].enumerated_string().unwrap(), "AES-128");
+        assert!(attributes[4].yes_no().unwrap());
+        assert_eq!(attributes[5].quoted_string().unwrap(), "");
+        // values of the wrong type are reported
+        assert!(attributes[3].quoted_string().is_err());
+        assert!(attributes[5].enumerated_string().is_err());
+        assert!(attributes[1].decimal_integer().is_err());
+        assert!(attributes[3].resolution().is_err());
+    }
+
+    #[test]
+    fn malformed_lists() {
+        for list in [
+            "CODECS=\"avc1.64001f",
+            "NAME=\"a\"b",
+            "BANDWIDTH",
+            "bandwidth=1",
+            "BANDWIDTH=1,",
+            "BANDWIDTH=1,,AUDIO=\"a\"",
+            "BANDWIDTH=",
+            "BANDWIDTH=1,BANDWIDTH=2",
+            "URI=\"a\nb\"",
+        ] {
+            assert!(parse(list).is_err(), "{}", list);
+        }
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn attributes_are_written_as_lexed() {
+        let list = "BANDWIDTH=1280000,CODECS=\"avc1.64001f,mp4a.40.2\",X-EMPTY=\"\"";
+        let written: Vec<String> = parse(list).unwrap().iter().map(|attribute| attribute.to_string()).collect();
+        assert_eq!(written.join(","), list);
+        assert_eq!(Quoted("a\"b").to_string(), "\"a\"b\"");
+        assert!(check_quotable(["ok", "a\"b"]).is_err());
+        assert!(check_quotable(["line\r\nbreak"]).is_err());
+        assert!(check_quotable(["ok", "also, ok"]).is_ok());
+    }
+}
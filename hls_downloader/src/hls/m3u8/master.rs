@@ -1,35 +1,36 @@
+use std::{fmt, io};
 
-use std::io::Read;
-
-use hyper::{Client, StatusCode, body::{HttpBody, Buf}};
+use super::attribute::{self, Attribute, Quoted};
 
 
 type PlaylistFormatError = Box< dyn std::error::Error>;
 
-#[derive(Debug)]
-enum VariatnStreamAttribute {
-    Bandwidth(u32),
-    AvgBandwidth(u32),
+#[derive(Debug, Clone, PartialEq)]
+pub enum VariatnStreamAttribute {
+    Bandwidth(u64),
+    AvgBandwidth(u64),
     Codec(String),
     Resolution((u32,u32)),
-    FrameRate(f32),
-    HdcpLevel(HDCP),
+    FrameRate(f64),
+    HdcpLevel(Hdcp),
     Audio(String),
     Video(String),
     Subtitles(String),
-    ClosedCaptions(String)
+    ClosedCaptions(String),
+    /// Attribute outside the model, as written.
+    Other(String)
 }
 
-#[derive(Debug)]
-enum MediaType {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
     Audio,
     Video,
     Subtitles, //< Subtitles
     CCs,       //< Closed-captions
 }
 
-#[derive(Debug)]
-enum MediaPlaylistAttribute {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MediaPlaylistAttribute {
     Type(MediaType),
     Uri(String),
     GroupId(String),
@@ -39,33 +40,76 @@ enum MediaPlaylistAttribute {
     Default(bool),
     AutoSelect(bool),
     Forced(bool),
-    InstreamId(String)
+    InstreamId(String),
+    /// Attribute outside the model, as written.
+    Other(String)
 }
 
-#[derive(Debug)]
-enum HDCP {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hdcp {
     Type0,
     None
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Tag {
     Version(u8),
     MediaPlaylist (Vec<MediaPlaylistAttribute>),
-    VariantStream { attributes: Vec<VariatnStreamAttribute>, uri: String }
+    VariantStream { attributes: Vec<VariatnStreamAttribute>, uri: String },
+    /// Tag outside the model or comment, the whole line as written.
+    Other(String)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Playlist {
     base_url: String,
-    tags: Vec<Tag>
+    /// Tags in playlist order, written back in the same order.
+    pub tags: Vec<Tag>,
+}
+
+/// Variant stream of a [`Playlist`], with the attributes it is told apart by.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Variant<'p> {
+    pub uri: &'p str,
+    pub bandwidth: Option<u64>,
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<&'p str>,
+    pub frame_rate: Option<f64>,
+    /// `GROUP-ID`s of the renditions the variant refers to.
+    pub audio: Option<&'p str>,
+    pub video: Option<&'p str>,
+    pub subtitles: Option<&'p str>,
+    /// `None` for `CLOSED-CAPTIONS=NONE` too.
+    pub closed_captions: Option<&'p str>,
+    playlist: &'p Playlist,
+}
+
+/// `EXT-X-MEDIA` rendition of a [`Playlist`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rendition<'p> {
+    pub media_type: MediaType,
+    pub group_id: &'p str,
+    pub name: &'p str,
+    pub language: Option<&'p str>,
+    /// `None` when the rendition comes with the variant stream.
+    pub uri: Option<&'p str>,
+    pub default: bool,
+    pub autoselect: bool,
+}
+
+/// Renditions sharing a type and `GROUP-ID`, in playlist order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenditionGroup<'p> {
+    pub media_type: MediaType,
+    pub group_id: &'p str,
+    pub renditions: Vec<Rendition<'p>>,
 }
 
-impl TryFrom<&str> for HDCP {
+impl TryFrom<&str> for Hdcp {
     type Error = PlaylistFormatError;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value.to_uppercase().as_str() {
+        match value {
             "TYPE-0" => Ok(Self::Type0),
             "NONE" => Ok(Self::None),
             _ => Err(PlaylistFormatError::from(format!("invalid hdcp level {}", value)))
@@ -77,7 +121,7 @@ impl TryFrom<&str> for MediaType {
     type Error = PlaylistFormatError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value.trim().to_uppercase().as_str() {
+        match value {
             "AUDIO" => Ok(Self::Audio),
             "VIDEO" => Ok(Self::Video),
             "SUBTITLES" => Ok(Self::Subtitles),
@@ -87,155 +131,512 @@ impl TryFrom<&str> for MediaType {
     }
 }
 
-impl TryFrom<&str> for VariatnStreamAttribute {
-    type Error = PlaylistFormatError;
+impl VariatnStreamAttribute {
+    fn parse(attr: &Attribute) -> Result<Self, PlaylistFormatError> {
+        Ok(match attr.name {
+            "BANDWIDTH" => Self::Bandwidth(attr.decimal_integer()?),
+            "AVERAGE-BANDWIDTH" => Self::AvgBandwidth(attr.decimal_integer()?),
+            "CODECS" => Self::Codec(attr.quoted_string()?.to_string()),
+            "FRAME-RATE" => Self::FrameRate(attr.decimal_float()?),
+            "HDCP-LEVEL" => Self::HdcpLevel(Hdcp::try_from(attr.enumerated_string()?)?),
+            "AUDIO" => Self::Audio(attr.quoted_string()?.to_string()),
+            "VIDEO" => Self::Video(attr.quoted_string()?.to_string()),
+            "SUBTITLES" => Self::Subtitles(attr.quoted_string()?.to_string()),
+            // CLOSED-CAPTIONS is either a group id or NONE
+            "CLOSED-CAPTIONS" => Self::ClosedCaptions(attr.quoted_string().or_else(|_| attr.enumerated_string())?.to_string()),
+            "RESOLUTION" => Self::Resolution(attr.resolution()?),
+            _ => Self::Other(attr.to_string())
+        })
+    }
+}
 
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value.trim().to_uppercase().split_once("=") {
-            Some(("BANDWIDTH", v)) => Ok(Self::Bandwidth(v.trim().parse().unwrap())),
-            Some(("AVERAGE-BANDWIDTH", v)) => Ok(Self::AvgBandwidth(v.trim().parse().unwrap())),
-            Some(("CODECS", codec)) => Ok(Self::Codec(codec.to_string().replace("\"", ""))),
-            Some(("FRAME-RATE",rate)) => Ok(Self::FrameRate(rate.parse().unwrap())),
-            Some(("HDCP-LEVEL", level)) => Ok(Self::HdcpLevel(HDCP::try_from(level).unwrap())),
-            Some(("AUDIO", audio_gid)) => Ok(Self::Audio(audio_gid.to_string().replace("\"", ""))),
-            Some(("VIDEO", video_gid)) => Ok(Self::Video(video_gid.to_string().replace("\"", ""))),
-            Some(("SUBTITLES", gid)) => Ok(Self::Subtitles(gid.to_string().replace("\"", ""))),
-            Some(("CLOSED-CAPTIONS", gid)) => Ok(Self::ClosedCaptions(gid.to_string().replace("\"", ""))),
-            Some(("RESOLUTION", res)) => {
-                let lower_res = res.to_lowercase();
-                if lower_res.contains("x") {
-                    let (width, height) = lower_res.split_once("x").unwrap();
-                    Ok(VariatnStreamAttribute::Resolution((width.parse().unwrap(), height.parse().unwrap())))    
-                } else {
-                    Err(PlaylistFormatError::from(format!("invalid resolution format {}", lower_res)))
-                }
-            },
-            _ => Err(PlaylistFormatError::from(format!("invalid attribute {} for variant stream", value)))
+impl MediaPlaylistAttribute {
+    fn parse(attr: &Attribute) -> Result<Self, PlaylistFormatError> {
+        Ok(match attr.name {
+            "TYPE" => Self::Type(MediaType::try_from(attr.enumerated_string()?)?),
+            "GROUP-ID" => Self::GroupId(attr.quoted_string()?.to_string()),
+            "LANGUAGE" => Self::Language(attr.quoted_string()?.to_string()),
+            "ASSOC-LANGUAGE" => Self::AssocLanguage(attr.quoted_string()?.to_string()),
+            "NAME" => Self::Name(attr.quoted_string()?.to_string()),
+            "DEFAULT" => Self::Default(attr.yes_no()?),
+            "URI" => Self::Uri(attr.quoted_string()?.to_string()),
+            "AUTOSELECT" => Self::AutoSelect(attr.yes_no()?),
+            "FORCED" => Self::Forced(attr.yes_no()?),
+            "INSTREAM-ID" => Self::InstreamId(attr.quoted_string()?.to_string()),
+            _ => Self::Other(attr.to_string())
+        })
+    }
+}
+
+/// Lexes `attrs` and converts the attributes with `convert`.
+fn parse_attributes<T>(attrs: &str, convert: fn(&Attribute) -> Result<T, PlaylistFormatError>) -> Result<Vec<T>, PlaylistFormatError> {
+    attribute::parse(attrs)?.iter().map(convert).collect()
+}
+
+impl<'p> Variant<'p> {
+    fn from_tag(tag: &'p Tag, playlist: &'p Playlist) -> Option<Self> {
+        let Tag::VariantStream { attributes, uri } = tag else {
+            return None;
+        };
+        let mut variant = Variant {
+            uri,
+            bandwidth: None,
+            resolution: None,
+            codecs: None,
+            frame_rate: None,
+            audio: None,
+            video: None,
+            subtitles: None,
+            closed_captions: None,
+            playlist,
+        };
+        for attribute in attributes {
+            match attribute {
+                VariatnStreamAttribute::Bandwidth(bandwidth) => variant.bandwidth = Some(*bandwidth),
+                VariatnStreamAttribute::Resolution(resolution) => variant.resolution = Some(*resolution),
+                VariatnStreamAttribute::Codec(codecs) => variant.codecs = Some(codecs),
+                VariatnStreamAttribute::FrameRate(rate) => variant.frame_rate = Some(*rate),
+                VariatnStreamAttribute::Audio(group_id) => variant.audio = Some(group_id),
+                VariatnStreamAttribute::Video(group_id) => variant.video = Some(group_id),
+                VariatnStreamAttribute::Subtitles(group_id) => variant.subtitles = Some(group_id),
+                VariatnStreamAttribute::ClosedCaptions(group_id) if group_id != "NONE" => variant.closed_captions = Some(group_id),
+                _ => {}
+            }
+        }
+        Some(variant)
+    }
+
+    /// `GROUP-ID` the variant refers to for renditions of `media_type`.
+    pub fn group_id(&self, media_type: MediaType) -> Option<&'p str> {
+        match media_type {
+            MediaType::Audio => self.audio,
+            MediaType::Video => self.video,
+            MediaType::Subtitles => self.subtitles,
+            MediaType::CCs => self.closed_captions,
         }
     }
+
+    /// Renditions of `media_type` the variant refers to.
+    pub fn group(&self, media_type: MediaType) -> Option<RenditionGroup<'p>> {
+        self.playlist.group(media_type, self.group_id(media_type)?)
+    }
+
+    pub fn audio_group(&self) -> Option<RenditionGroup<'p>> {
+        self.group(MediaType::Audio)
+    }
+
+    pub fn subtitle_group(&self) -> Option<RenditionGroup<'p>> {
+        self.group(MediaType::Subtitles)
+    }
 }
 
-impl TryFrom<&str> for MediaPlaylistAttribute {
-    type Error = PlaylistFormatError;
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value.trim().to_string().split_once("=") {
-            Some(("TYPE", mtype)) => Ok(Self::Type(MediaType::try_from(mtype)?)),
-            Some(("GROUP-ID", gid)) => Ok(Self::GroupId(gid.to_uppercase().replace("\"", ""))),
-            Some(("LANGUAGE", lang)) => Ok(Self::Language(lang.to_uppercase().replace("\"", ""))),
-            Some(("ASSOC-LANGUAGE", alang)) => Ok(Self::AssocLanguage(alang.to_uppercase().replace("\"", ""))),
-            Some(("NAME", name)) => Ok(Self::Name(name.to_uppercase().replace("\"", ""))),
-            Some(("DEFAULT", is_default)) => Ok(Self::Default(is_default == "YES")),
-            Some(("URI", uri)) => Ok(Self::Uri(uri.to_string().replace("\"", ""))),
-            Some(("AUTOSELECT", is_autosel)) => Ok(Self::AutoSelect(is_autosel == "YES")),
-            Some(("FORCED", is_forced)) => Ok(Self::Forced(is_forced == "YES")),
-            Some(("INSTREAM-ID", sid)) => Ok(Self::InstreamId(sid.to_uppercase().replace("\"",""))),
-            _ => Err(PlaylistFormatError::from(format!("unknown attribute {}", value)))
+impl<'p> Rendition<'p> {
+    fn from_tag(tag: &'p Tag) -> Option<Self> {
+        match tag {
+            Tag::MediaPlaylist(attributes) => Self::from_attributes(attributes).ok(),
+            _ => None,
+        }
+    }
+
+    /// Fails on a missing `TYPE`, `GROUP-ID` or `NAME`, which are required.
+    fn from_attributes(attributes: &'p [MediaPlaylistAttribute]) -> Result<Self, PlaylistFormatError> {
+        let (mut media_type, mut group_id, mut name) = (None, None, None);
+        let (mut language, mut uri, mut default, mut autoselect) = (None, None, false, false);
+        for attribute in attributes {
+            match attribute {
+                MediaPlaylistAttribute::Type(value) => media_type = Some(*value),
+                MediaPlaylistAttribute::GroupId(value) => group_id = Some(value.as_str()),
+                MediaPlaylistAttribute::Name(value) => name = Some(value.as_str()),
+                MediaPlaylistAttribute::Language(value) => language = Some(value.as_str()),
+                MediaPlaylistAttribute::Uri(value) => uri = Some(value.as_str()),
+                MediaPlaylistAttribute::Default(value) => default = *value,
+                MediaPlaylistAttribute::AutoSelect(value) => autoselect = *value,
+                _ => {}
+            }
         }
+        let missing = |name: &str| PlaylistFormatError::from(format!("EXT-X-MEDIA without {}", name));
+        Ok(Rendition {
+            media_type: media_type.ok_or_else(|| missing("TYPE"))?,
+            group_id: group_id.ok_or_else(|| missing("GROUP-ID"))?,
+            name: name.ok_or_else(|| missing("NAME"))?,
+            language,
+            uri,
+            default,
+            autoselect,
+        })
     }
 }
 
+impl<'p> RenditionGroup<'p> {
+    /// The `DEFAULT=YES` rendition, if any.
+    pub fn default(&self) -> Option<&Rendition<'p>> {
+        self.renditions.iter().find(|rendition| rendition.default)
+    }
+}
 
 impl Tag {
-    fn parse(tag_str: &str) -> Result<Self, PlaylistFormatError> {
-        if tag_str.contains("EXT-X-VERSION") {
-            let v: Vec<&str> = tag_str.split(":").collect();
-            return Ok(Self::Version(v[1].trim().parse().unwrap()));
-        } else if tag_str.contains("EXT-X-MEDIA") {
-            if let Some((_, attrs)) = tag_str.split_once(":") {
-                let attributes: Vec<MediaPlaylistAttribute> = attrs.split(",")
-                    .map(|attr| MediaPlaylistAttribute::try_from(attr))
-                    .filter(|attr| attr.is_ok())
-                    .map(|attr| attr.unwrap())
-                    .collect();
-
-                return Ok(Self::MediaPlaylist(attributes));
+    /// Parses a tag line without its `#`, `None` for tags which aren't part
+    /// of the model. `uri` is the URI line following `EXT-X-STREAM-INF`.
+    fn parse(tag_str: &str, uri: Option<&str>) -> Result<Option<Self>, PlaylistFormatError> {
+        let (name, value) = tag_str.split_once(':').unwrap_or((tag_str, ""));
+        match name {
+            "EXT-X-VERSION" => {
+                Ok(Some(Self::Version(value.parse().map_err(|_| PlaylistFormatError::from(format!("invalid version {}", value)))?)))
             }
-        } else if tag_str.contains("EXT-X-STREAM-INF") {
-            if let Some((_, value)) = tag_str.split_once(":") {
-                if let Some((cs_attrs, uri)) = value.split_once("\n") {
-                    let attributes: Vec<VariatnStreamAttribute> = cs_attrs.split(",")
-                        .map(|attr| VariatnStreamAttribute::try_from(attr))
-                        .filter(|attr| attr.is_ok())
-                        .map(|attr| attr.unwrap())
-                        .collect();
-
-                    return Ok(Self::VariantStream { attributes, uri: uri.trim().to_string() })
-                }
+            "EXT-X-MEDIA" => {
+                let attributes = parse_attributes(value, MediaPlaylistAttribute::parse)?;
+                Rendition::from_attributes(&attributes)?;
+                Ok(Some(Self::MediaPlaylist(attributes)))
             }
+            "EXT-X-STREAM-INF" => {
+                let uri = uri.ok_or_else(|| PlaylistFormatError::from("EXT-X-STREAM-INF without uri"))?;
+                let attributes = parse_attributes(value, VariatnStreamAttribute::parse)?;
+                Ok(Some(Self::VariantStream { attributes, uri: uri.to_string() }))
+            }
+            _ => Ok(None),
         }
-        Err(PlaylistFormatError::from("invalid master playlist tag"))
     }
 }
 
 
 impl Playlist {
 
-    pub fn get_variants(&self) -> Vec<&Tag> {
-        let mut variants = Vec::new();
-        for tag in &self.tags {
-            match tag {
-                Tag::VariantStream { .. } => {
-                    variants.push(tag);
-                }
-                _ => {
-                    continue;
+    /// Parses the master playlist `text`, which was loaded from `base_url`.
+    pub fn parse(text: &str, base_url: &str) -> Result<Self, PlaylistFormatError> {
+        let mut lines = text.lines().enumerate().map(|(n, line)| (n + 1, line.trim())).filter(|(_, line)| !line.is_empty());
+        match lines.next() {
+            Some((_, "#EXTM3U")) => {}
+            _ => return Err(PlaylistFormatError::from("master playlist must start with #EXTM3U")),
+        }
+
+        let mut tags = Vec::new();
+        while let Some((n, line)) = lines.next() {
+            // URI lines are only expected right after EXT-X-STREAM-INF
+            let Some(tag_str) = line.strip_prefix('#') else {
+                return Err(PlaylistFormatError::from(format!("line {}: unexpected uri {}", n, line)));
+            };
+            let uri = match tag_str.starts_with("EXT-X-STREAM-INF:") {
+                true => lines.next().map(|(_, line)| line).filter(|line| !line.starts_with('#')),
+                false => None,
+            };
+            let tag = Tag::parse(tag_str, uri).map_err(|e| PlaylistFormatError::from(format!("line {}: {}", n, e)))?;
+            tags.push(tag.unwrap_or_else(|| Tag::Other(line.to_string())));
+        }
+        let playlist = Playlist { base_url: base_url.to_string(), tags };
+        playlist.validate_groups()?;
+        Ok(playlist)
+    }
+
+    /// Checks that the groups variants refer to exist and that none has more
+    /// than one `DEFAULT=YES` rendition.
+    fn validate_groups(&self) -> Result<(), PlaylistFormatError> {
+        let groups = self.rendition_groups();
+        for variant in self.variants() {
+            for media_type in [MediaType::Audio, MediaType::Video, MediaType::Subtitles, MediaType::CCs] {
+                let Some(group_id) = variant.group_id(media_type) else { continue };
+                if !groups.iter().any(|group| group.media_type == media_type && group.group_id == group_id) {
+                    return Err(PlaylistFormatError::from(format!("variant {} refers to missing {} group {}", variant.uri, media_type, group_id)));
                 }
             }
-            
         }
-        variants
+        for group in &groups {
+            if group.renditions.iter().filter(|rendition| rendition.default).count() > 1 {
+                return Err(PlaylistFormatError::from(format!("{} group {} has more than one DEFAULT=YES rendition", group.media_type, group.group_id)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Location the playlist was loaded from.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn variants(&self) -> Vec<Variant<'_>> {
+        self.tags.iter().filter_map(|tag| Variant::from_tag(tag, self)).collect()
+    }
+
+    /// Renditions grouped by type and `GROUP-ID`, in order of first
+    /// appearance, as the tags are now.
+    pub fn rendition_groups(&self) -> Vec<RenditionGroup<'_>> {
+        let mut groups: Vec<RenditionGroup> = Vec::new();
+        for rendition in self.tags.iter().filter_map(Rendition::from_tag) {
+            match groups.iter_mut().find(|group| group.media_type == rendition.media_type && group.group_id == rendition.group_id) {
+                Some(group) => group.renditions.push(rendition),
+                None => groups.push(RenditionGroup { media_type: rendition.media_type, group_id: rendition.group_id, renditions: vec![rendition] }),
+            }
+        }
+        groups
+    }
+
+    /// Renditions of `media_type` in the group `group_id`, found in a single
+    /// pass over the tags.
+    pub fn group(&self, media_type: MediaType, group_id: &str) -> Option<RenditionGroup<'_>> {
+        let renditions: Vec<Rendition> = self.tags.iter()
+            .filter_map(Rendition::from_tag)
+            .filter(|rendition| rendition.media_type == media_type && rendition.group_id == group_id)
+            .collect();
+        let group_id = renditions.first()?.group_id;
+        Some(RenditionGroup { media_type, group_id, renditions })
     }
 }
 
-impl TryFrom<&str> for Playlist {
-    type Error = PlaylistFormatError;
-    fn try_from(master_playlist_url: &str) -> Result<Self, Self::Error> {
+impl Playlist {
+    /// Writes the playlist in M3U8 format.
+    pub fn write_to(&self, w: impl io::Write) -> io::Result<()> {
+        super::write_playlist(w, self, self.quoted_strings())
+    }
 
-        let chunked_url: Vec<&str>  = master_playlist_url.split("/").collect();
-        let base_url = match chunked_url.split_last() {
-            Some((_, url_chunks)) => url_chunks.join("/"),
-            _ => String::new()
-        };
+    /// Values written as quoted-strings.
+    fn quoted_strings(&self) -> impl Iterator<Item = &str> {
+        self.tags.iter().flat_map(|tag| -> Vec<&str> {
+            match tag {
+                Tag::MediaPlaylist(attributes) => attributes.iter().filter_map(|attribute| match attribute {
+                    MediaPlaylistAttribute::Uri(value)
+                    | MediaPlaylistAttribute::GroupId(value)
+                    | MediaPlaylistAttribute::Language(value)
+                    | MediaPlaylistAttribute::AssocLanguage(value)
+                    | MediaPlaylistAttribute::Name(value)
+                    | MediaPlaylistAttribute::InstreamId(value) => Some(value.as_str()),
+                    _ => None,
+                }).collect(),
+                Tag::VariantStream { attributes, .. } => attributes.iter().filter_map(|attribute| match attribute {
+                    VariatnStreamAttribute::Codec(value)
+                    | VariatnStreamAttribute::Audio(value)
+                    | VariatnStreamAttribute::Video(value)
+                    | VariatnStreamAttribute::Subtitles(value) => Some(value.as_str()),
+                    VariatnStreamAttribute::ClosedCaptions(value) if value != "NONE" => Some(value.as_str()),
+                    _ => None,
+                }).collect(),
+                _ => Vec::new(),
+            }
+        })
+    }
+}
 
-        let rt = tokio::runtime::Runtime::new()?;
-
-        let https = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .https_only()
-            .enable_http1()
-            .build();
-        
-        let client: Client<_, hyper::Body> = Client::builder().build(https);
-        let mut res = rt.block_on(client.get(master_playlist_url.parse()?))?;
-        let playlist = match res.status() {
-            StatusCode::OK => {
-                let mut body_message = String::new();
-                while let Some(data) = futures::executor::block_on(res.body_mut().data()) {
-                    let body = match data {
-                        Ok(raw) => raw,
-                        Err(e) => {
-                            println!("error on downloading playlist {}", e);
-                            std::process::exit(1);
-                        }
-                    };
-                    let mut reader = body.reader();
-                    if let Ok(sz) = reader.read_to_string(&mut body_message) {
-                        println!("{} bytes of string is read", sz);
-                    }
-                }
-                body_message
-            },
-            code => {
-                println!("can't get HLS playlist (code: {})", code);
-                std::process::exit(1);
+impl fmt::Display for Playlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "#EXTM3U")?;
+        for tag in &self.tags {
+            writeln!(f, "{}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tag::Version(version) => write!(f, "#EXT-X-VERSION:{}", version),
+            Tag::MediaPlaylist(attributes) => {
+                write!(f, "#EXT-X-MEDIA:")?;
+                write_attributes(f, attributes)
             }
-        };
-        let tags: Vec<Tag> = playlist.split("#")
-                                          .filter(|s| {s.contains("EXT")})
-                                          .map(|tag| Tag::parse(tag))
-                                          .filter(|tag| tag.is_ok())
-                                          .map(|some_tag| some_tag.unwrap())
-                                          .into_iter().collect();
-        Ok(Playlist { tags, base_url })
+            Tag::VariantStream { attributes, uri } => {
+                write!(f, "#EXT-X-STREAM-INF:")?;
+                write_attributes(f, attributes)?;
+                write!(f, "\n{}", uri)
+            }
+            Tag::Other(line) => f.write_str(line),
+        }
+    }
+}
+
+fn write_attributes<T: fmt::Display>(f: &mut fmt::Formatter<'_>, attributes: &[T]) -> fmt::Result {
+    for (i, attribute) in attributes.iter().enumerate() {
+        if i > 0 {
+            write!(f, ",")?;
+        }
+        write!(f, "{}", attribute)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for VariatnStreamAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bandwidth(bandwidth) => write!(f, "BANDWIDTH={}", bandwidth),
+            Self::AvgBandwidth(bandwidth) => write!(f, "AVERAGE-BANDWIDTH={}", bandwidth),
+            Self::Codec(codecs) => write!(f, "CODECS={}", Quoted(codecs)),
+            Self::Resolution((width, height)) => write!(f, "RESOLUTION={}x{}", width, height),
+            Self::FrameRate(rate) => write!(f, "FRAME-RATE={}", rate),
+            Self::HdcpLevel(level) => write!(f, "HDCP-LEVEL={}", level),
+            Self::Audio(gid) => write!(f, "AUDIO={}", Quoted(gid)),
+            Self::Video(gid) => write!(f, "VIDEO={}", Quoted(gid)),
+            Self::Subtitles(gid) => write!(f, "SUBTITLES={}", Quoted(gid)),
+            Self::ClosedCaptions(gid) if gid == "NONE" => write!(f, "CLOSED-CAPTIONS=NONE"),
+            Self::ClosedCaptions(gid) => write!(f, "CLOSED-CAPTIONS={}", Quoted(gid)),
+            Self::Other(attribute) => f.write_str(attribute),
+        }
+    }
+}
+
+impl fmt::Display for MediaPlaylistAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |b: &bool| if *b { "YES" } else { "NO" };
+        match self {
+            Self::Type(mtype) => write!(f, "TYPE={}", mtype),
+            Self::Uri(uri) => write!(f, "URI={}", Quoted(uri)),
+            Self::GroupId(gid) => write!(f, "GROUP-ID={}", Quoted(gid)),
+            Self::Language(lang) => write!(f, "LANGUAGE={}", Quoted(lang)),
+            Self::AssocLanguage(alang) => write!(f, "ASSOC-LANGUAGE={}", Quoted(alang)),
+            Self::Name(name) => write!(f, "NAME={}", Quoted(name)),
+            Self::Default(is_default) => write!(f, "DEFAULT={}", yes_no(is_default)),
+            Self::AutoSelect(is_autosel) => write!(f, "AUTOSELECT={}", yes_no(is_autosel)),
+            Self::Forced(is_forced) => write!(f, "FORCED={}", yes_no(is_forced)),
+            Self::InstreamId(sid) => write!(f, "INSTREAM-ID={}", Quoted(sid)),
+            Self::Other(attribute) => f.write_str(attribute),
+        }
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Audio => "AUDIO",
+            Self::Video => "VIDEO",
+            Self::Subtitles => "SUBTITLES",
+            Self::CCs => "CLOSED-CAPTIONS",
+        })
+    }
+}
+
+impl fmt::Debug for Variant<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Variant")
+            .field("uri", &self.uri)
+            .field("bandwidth", &self.bandwidth)
+            .field("resolution", &self.resolution)
+            .field("codecs", &self.codecs)
+            .field("frame_rate", &self.frame_rate)
+            .field("audio", &self.audio)
+            .field("video", &self.video)
+            .field("subtitles", &self.subtitles)
+            .field("closed_captions", &self.closed_captions)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for Hdcp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Type0 => "TYPE-0",
+            Self::None => "NONE",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variant_attributes_keep_commas_and_case() {
+        let tag = Tag::parse("EXT-X-STREAM-INF:BANDWIDTH=1280000,CODECS=\"avc1.64001f,mp4a.40.2\",AUDIO=\"aac-Stereo\",RESOLUTION=1280x720,X-CUSTOM=1", Some("video/Low.m3u8"));
+        match tag.unwrap() {
+            Some(Tag::VariantStream { attributes, uri }) => {
+                assert_eq!(uri, "video/Low.m3u8");
+                assert_eq!(attributes.len(), 5);
+                assert_eq!(attributes[4], VariatnStreamAttribute::Other("X-CUSTOM=1".into()));
+                assert!(matches!(&attributes[1], VariatnStreamAttribute::Codec(codecs) if codecs == "avc1.64001f,mp4a.40.2"));
+                assert!(matches!(&attributes[2], VariatnStreamAttribute::Audio(group) if group == "aac-Stereo"));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_attributes_are_reported() {
+        assert!(Tag::parse("EXT-X-STREAM-INF:BANDWIDTH=fast", Some("low.m3u8")).is_err());
+        assert!(Tag::parse("EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac,NAME=\"English\"", None).is_err());
+        assert!(Tag::parse("EXT-X-MEDIA:TYPE=AUDIO,DEFAULT=maybe", None).is_err());
+        assert!(Tag::parse("EXT-X-INDEPENDENT-SEGMENTS", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn playlist_parse() {
+        let text = "#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud1\",NAME=\"English\",DEFAULT=YES,URI=\"a1/prog_index.m3u8\"
+# a comment
+#EXT-X-STREAM-INF:BANDWIDTH=2177116,CODECS=\"avc1.640020,mp4a.40.2\",AUDIO=\"aud1\"
+v5/prog_index.m3u8#fragment
+#EXT-X-STREAM-INF:BANDWIDTH=8001000,RESOLUTION=1920x1080
+
+v9/prog_index.m3u8
+";
+        let playlist = Playlist::parse(text, "https://example.com/master.m3u8").unwrap();
+        assert_eq!(playlist.base_url(), "https://example.com/master.m3u8");
+        assert_eq!(playlist.tags.len(), 6);
+        assert_eq!(playlist.tags[1], Tag::Other("#EXT-X-INDEPENDENT-SEGMENTS".into()));
+        assert_eq!(playlist.tags[3], Tag::Other("# a comment".into()));
+        let uris: Vec<&str> = playlist.variants().into_iter().map(|variant| variant.uri).collect();
+        assert_eq!(uris, ["v5/prog_index.m3u8#fragment", "v9/prog_index.m3u8"]);
+
+        let error = Playlist::parse("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\n", "").unwrap_err();
+        assert!(error.to_string().starts_with("line 2:"), "{}", error);
+        assert!(Playlist::parse("#EXT-X-VERSION:3\n", "").is_err());
+    }
+
+    #[test]
+    fn renditions_are_grouped_and_checked() {
+        let playlist = Playlist::parse(&std::fs::read_to_string("fixtures/m3u8/master_advanced.m3u8").unwrap(), "").unwrap();
+        let groups: Vec<(MediaType, &str, usize)> = playlist.rendition_groups().iter().map(|group| (group.media_type, group.group_id, group.renditions.len())).collect();
+        assert_eq!(groups, [(MediaType::Audio, "aud1", 1), (MediaType::Audio, "aud2", 1), (MediaType::Subtitles, "sub1", 1), (MediaType::CCs, "cc1", 1)]);
+
+        let variants = playlist.variants();
+        assert_eq!(variants.len(), 2);
+        let audio = variants[1].audio_group().unwrap();
+        assert_eq!(audio.group_id, "aud2");
+        assert_eq!(audio.default().and_then(|rendition| rendition.uri), Some("a2/prog_index.m3u8"));
+        let subtitles = variants[1].subtitle_group().unwrap();
+        assert_eq!((subtitles.renditions[0].name, subtitles.renditions[0].language), ("English", Some("en")));
+        assert_eq!(variants[0].group(MediaType::CCs).unwrap().renditions[0].uri, None);
+        assert!(variants[0].group(MediaType::Video).is_none());
+
+        let missing = "#EXTM3U
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"aac\",NAME=\"English\",URI=\"en.m3u8\"
+#EXT-X-STREAM-INF:BANDWIDTH=1,AUDIO=\"aac\",CLOSED-CAPTIONS=NONE
+low.m3u8
+";
+        let error = Playlist::parse(missing, "").unwrap_err();
+        assert_eq!(error.to_string(), "variant low.m3u8 refers to missing AUDIO group aac");
+
+        let defaults = "#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",NAME=\"English\",DEFAULT=YES,URI=\"en.m3u8\"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",NAME=\"Deutsch\",DEFAULT=YES,URI=\"de.m3u8\"
+";
+        let error = Playlist::parse(defaults, "").unwrap_err();
+        assert_eq!(error.to_string(), "AUDIO group aac has more than one DEFAULT=YES rendition");
+
+        let unnamed = "#EXTM3U\n#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",URI=\"en.m3u8\"\n";
+        let error = Playlist::parse(unnamed, "").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: EXT-X-MEDIA without NAME");
+        let error = Playlist::parse("#EXTM3U\n#EXT-X-MEDIA:GROUP-ID=\"aac\",NAME=\"English\"\n", "").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: EXT-X-MEDIA without TYPE");
+    }
+
+    #[test]
+    fn groups_follow_edited_tags() {
+        let mut playlist = Playlist::parse(&std::fs::read_to_string("fixtures/m3u8/master_advanced.m3u8").unwrap(), "").unwrap();
+        let first_media = playlist.tags.iter().position(|tag| matches!(tag, Tag::MediaPlaylist(_))).unwrap();
+        playlist.tags.insert(0, Tag::Other("# edited".into()));
+        playlist.tags.remove(first_media + 1);
+        let added = Tag::parse("EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud2\",NAME=\"Deutsch\",LANGUAGE=\"de\",URI=\"de.m3u8\"", None).unwrap().unwrap();
+        playlist.tags.insert(1, added);
+
+        let variants = playlist.variants();
+        let names: Vec<&str> = variants[1].audio_group().unwrap().renditions.iter().map(|rendition| rendition.name).collect();
+        assert_eq!(names, ["Deutsch", "English"]);
+        assert!(variants[0].audio_group().is_none());
+        assert_eq!(variants[1].subtitle_group().unwrap().renditions[0].language, Some("en"));
+    }
+
+    #[test]
+    fn unquotable_strings_fail_to_write() {
+        let playlist = Playlist { base_url: String::new(), tags: vec![Tag::MediaPlaylist(vec![MediaPlaylistAttribute::Name("say \"hi\"".into())])] };
+        assert!(playlist.write_to(Vec::new()).is_err());
+        assert_eq!(playlist.to_string(), "#EXTM3U\n#EXT-X-MEDIA:NAME=\"say \"hi\"\"\n");
     }
 }
@@ -1,5 +1,645 @@
+use std::{fmt, io, str::FromStr};
 
+use super::attribute::{self, Quoted};
 
+type PlaylistFormatError = Box<dyn std::error::Error>;
+
+/// Tags outside the model which apply to the whole playlist rather than to
+/// the segment they precede.
+const PLAYLIST_TAGS: [&str; 6] = [
+    "#EXT-X-INDEPENDENT-SEGMENTS",
+    "#EXT-X-I-FRAMES-ONLY",
+    "#EXT-X-ALLOW-CACHE",
+    "#EXT-X-DEFINE",
+    "#EXT-X-SERVER-CONTROL",
+    "#EXT-X-PART-INF",
+];
+
+/// `EXT-X-PLAYLIST-TYPE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistType {
+    /// Segments may only be appended.
+    Event,
+    /// The playlist never changes.
+    Vod,
+}
+
+/// Preferred point to start playing at, from `EXT-X-START`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Start {
+    /// Seconds from the beginning of the playlist, or from the end of its
+    /// last segment when negative.
+    pub time_offset: f64,
+    /// Whether to start at that point exactly rather than with the segment
+    /// containing it.
+    pub precise: bool,
+}
+
+/// `METHOD` of an `EXT-X-KEY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyMethod {
+    None,
+    Aes128,
+    SampleAes,
+}
+
+/// How segments are encrypted, from `EXT-X-KEY`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    pub method: KeyMethod,
+    pub uri: Option<String>,
+    pub iv: Option<[u8; 16]>,
+    /// `KEYFORMAT`; `None` stands for the default `identity`.
+    pub key_format: Option<String>,
+    pub key_format_versions: Option<String>,
+}
+
+/// Sub-range of a resource, with its offset resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub length: u64,
+    pub offset: u64,
+}
+
+/// Media initialization section, from `EXT-X-MAP`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Map {
+    pub uri: String,
+    pub byte_range: Option<ByteRange>,
+}
+
+/// Media segment with the state of the playlist at its position applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub uri: String,
+    /// `EXTINF` duration, in seconds.
+    pub duration: f64,
+    pub title: Option<String>,
+    /// Media sequence number.
+    pub sequence: u64,
+    /// Discontinuity sequence number.
+    pub discontinuity_sequence: u64,
+    /// Whether an `EXT-X-DISCONTINUITY` precedes the segment.
+    pub discontinuity: bool,
+    pub byte_range: Option<ByteRange>,
+    /// Keys in effect; several with different `KEYFORMAT`s may apply at once.
+    pub keys: Vec<Key>,
+    pub map: Option<Map>,
+    /// `EXT-X-PROGRAM-DATE-TIME` as written, an ISO 8601 date and time.
+    pub program_date_time: Option<String>,
+    /// Whether the segment is marked missing with `EXT-X-GAP`.
+    pub gap: bool,
+    /// Tags outside the model and comments preceding the segment, as written.
+    pub tags: Vec<String>,
+}
+
+/// Media playlist (RFC 8216, section 4.3.3).
+#[derive(Debug, Clone, PartialEq)]
 pub struct Playlist {
-    
-}
\ No newline at end of file
+    pub version: Option<u8>,
+    /// `EXT-X-TARGETDURATION`, in seconds.
+    pub target_duration: u64,
+    /// Media sequence number of the first segment.
+    pub media_sequence: u64,
+    /// Discontinuity sequence number of the first segment.
+    pub discontinuity_sequence: u64,
+    pub playlist_type: Option<PlaylistType>,
+    pub start: Option<Start>,
+    /// Whether `EXT-X-ENDLIST` says no more segments will be added.
+    pub end_list: bool,
+    pub segments: Vec<Segment>,
+    /// Playlist-wide tags outside the model, as written.
+    pub tags: Vec<String>,
+    /// Tags outside the model and comments after the last segment.
+    pub trailing_tags: Vec<String>,
+}
+
+/// Tags applying to the next segment only.
+#[derive(Default)]
+struct PendingSegment {
+    inf: Option<(f64, Option<String>)>,
+    byte_range: Option<(u64, Option<u64>)>,
+    discontinuity: bool,
+    program_date_time: Option<String>,
+    gap: bool,
+    tags: Vec<String>,
+}
+
+impl Segment {
+    /// Key to decrypt the segment with: the `identity` one, if several
+    /// formats are offered.
+    pub fn key(&self) -> Option<&Key> {
+        self.keys.iter().find(|key| key.key_format.as_deref().unwrap_or("identity") == "identity")
+    }
+}
+
+impl Playlist {
+    pub fn parse(text: &str) -> Result<Self, PlaylistFormatError> {
+        let mut lines = text.lines().enumerate().map(|(n, line)| (n + 1, line.trim())).filter(|(_, line)| !line.is_empty());
+        match lines.next() {
+            Some((_, "#EXTM3U")) => {}
+            _ => return Err(PlaylistFormatError::from("media playlist must start with #EXTM3U")),
+        }
+
+        let mut version = None;
+        let mut target_duration = None;
+        let mut media_sequence = 0;
+        let mut discontinuity_sequence = 0;
+        let mut playlist_type = None;
+        let mut start = None;
+        let mut end_list = false;
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut tags = Vec::new();
+
+        let mut pending = PendingSegment::default();
+        let mut keys: Vec<Key> = Vec::new();
+        let mut map = None;
+        let mut discontinuities = 0;
+        // end of the last sub-range, for ranges continuing in the same resource
+        let mut last_range: Option<(String, u64)> = None;
+
+        for (n, line) in lines {
+            let at_line = |e: PlaylistFormatError| PlaylistFormatError::from(format!("line {}: {}", n, e));
+            if !line.starts_with('#') {
+                let (duration, title) = pending.inf.take().ok_or_else(|| at_line(format!("segment {} has no #EXTINF", line).into()))?;
+                let byte_range = match pending.byte_range.take() {
+                    Some((length, Some(offset))) => Some(ByteRange { length, offset }),
+                    Some((length, None)) => match &last_range {
+                        Some((uri, end)) if uri == line => Some(ByteRange { length, offset: *end }),
+                        _ => return Err(at_line("#EXT-X-BYTERANGE without offset doesn't follow a range of the same resource".into())),
+                    },
+                    None => None,
+                };
+                last_range = byte_range.map(|range| (line.to_string(), range.offset + range.length));
+                if pending.discontinuity {
+                    discontinuities += 1;
+                }
+                segments.push(Segment {
+                    uri: line.to_string(),
+                    duration,
+                    title,
+                    sequence: media_sequence + segments.len() as u64,
+                    discontinuity_sequence: discontinuity_sequence + discontinuities,
+                    discontinuity: pending.discontinuity,
+                    byte_range,
+                    keys: keys.clone(),
+                    map: map.clone(),
+                    program_date_time: pending.program_date_time.take(),
+                    gap: pending.gap,
+                    tags: std::mem::take(&mut pending.tags),
+                });
+                pending = PendingSegment::default();
+                continue;
+            }
+
+            let (tag, value) = line.split_once(':').unwrap_or((line, ""));
+            match tag {
+                "#EXT-X-VERSION" => version = Some(value.parse().map_err(|_| at_line(format!("invalid version {}", value).into()))?),
+                "#EXT-X-TARGETDURATION" => target_duration = Some(parse_integer(value).map_err(at_line)?),
+                "#EXT-X-MEDIA-SEQUENCE" => media_sequence = parse_integer(value).map_err(at_line)?,
+                "#EXT-X-DISCONTINUITY-SEQUENCE" => discontinuity_sequence = parse_integer(value).map_err(at_line)?,
+                "#EXT-X-ENDLIST" => end_list = true,
+                "#EXT-X-PLAYLIST-TYPE" => {
+                    playlist_type = Some(match value {
+                        "EVENT" => PlaylistType::Event,
+                        "VOD" => PlaylistType::Vod,
+                        _ => return Err(at_line(format!("unknown playlist type {}", value).into())),
+                    })
+                }
+                "#EXT-X-START" => start = Some(Start::try_from(value).map_err(at_line)?),
+                "#EXTINF" => {
+                    let (duration, title) = value.split_once(',').unwrap_or((value, ""));
+                    let duration = attribute::parse_float(duration.trim()).ok_or_else(|| at_line(format!("invalid duration {}", duration).into()))?;
+                    let title = Some(title.trim().to_string()).filter(|title| !title.is_empty());
+                    pending.inf = Some((duration, title));
+                }
+                "#EXT-X-BYTERANGE" => pending.byte_range = Some(parse_byte_range(value).map_err(at_line)?),
+                "#EXT-X-DISCONTINUITY" => pending.discontinuity = true,
+                "#EXT-X-PROGRAM-DATE-TIME" => pending.program_date_time = Some(value.to_string()),
+                "#EXT-X-GAP" => pending.gap = true,
+                "#EXT-X-KEY" => {
+                    // a key replaces the one of its KEYFORMAT, NONE all of them
+                    let key = Key::try_from(value).map_err(at_line)?;
+                    match key.method {
+                        KeyMethod::None => keys.clear(),
+                        _ => {
+                            keys.retain(|other| other.key_format != key.key_format);
+                            keys.push(key);
+                        }
+                    }
+                }
+                "#EXT-X-MAP" => map = Some(Map::try_from(value).map_err(at_line)?),
+                _ if PLAYLIST_TAGS.contains(&tag) => tags.push(line.to_string()),
+                _ => pending.tags.push(line.to_string()),
+            }
+        }
+
+        Ok(Playlist {
+            version,
+            target_duration: target_duration.ok_or_else(|| PlaylistFormatError::from("missing #EXT-X-TARGETDURATION"))?,
+            media_sequence,
+            discontinuity_sequence,
+            playlist_type,
+            start,
+            end_list,
+            segments,
+            tags,
+            trailing_tags: pending.tags,
+        })
+    }
+
+    /// Sum of the segment durations, in seconds.
+    pub fn duration(&self) -> f64 {
+        self.segments.iter().map(|segment| segment.duration).sum()
+    }
+}
+
+impl Playlist {
+    /// Writes the playlist in M3U8 format. Sequence numbers are written from
+    /// the playlist's, so those of the segments are ignored.
+    pub fn write_to(&self, w: impl io::Write) -> io::Result<()> {
+        let keys = self.segments.iter().flat_map(|segment| &segment.keys);
+        let keys = keys.flat_map(|key| [&key.uri, &key.key_format, &key.key_format_versions]).flatten();
+        let maps = self.segments.iter().filter_map(|segment| segment.map.as_ref()).map(|map| &map.uri);
+        super::write_playlist(w, self, keys.chain(maps).map(String::as_str))
+    }
+}
+
+impl fmt::Display for Playlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "#EXTM3U")?;
+        if let Some(version) = self.version {
+            writeln!(f, "#EXT-X-VERSION:{}", version)?;
+        }
+        writeln!(f, "#EXT-X-TARGETDURATION:{}", self.target_duration)?;
+        writeln!(f, "#EXT-X-MEDIA-SEQUENCE:{}", self.media_sequence)?;
+        if self.discontinuity_sequence != 0 {
+            writeln!(f, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", self.discontinuity_sequence)?;
+        }
+        match self.playlist_type {
+            Some(PlaylistType::Event) => writeln!(f, "#EXT-X-PLAYLIST-TYPE:EVENT")?,
+            Some(PlaylistType::Vod) => writeln!(f, "#EXT-X-PLAYLIST-TYPE:VOD")?,
+            None => {}
+        }
+        if let Some(start) = &self.start {
+            writeln!(f, "#EXT-X-START:{}", start)?;
+        }
+        for tag in &self.tags {
+            writeln!(f, "{}", tag)?;
+        }
+
+        let mut keys: &[Key] = &[];
+        let mut map = None;
+        for segment in &self.segments {
+            for tag in &segment.tags {
+                writeln!(f, "{}", tag)?;
+            }
+            if segment.discontinuity {
+                writeln!(f, "#EXT-X-DISCONTINUITY")?;
+            }
+            if segment.keys != keys {
+                // keys only replace those of their KEYFORMAT, so dropping
+                // one takes clearing them all
+                let dropped = keys.iter().any(|old| !segment.keys.iter().any(|key| key.key_format == old.key_format));
+                if segment.keys.is_empty() || dropped {
+                    writeln!(f, "#EXT-X-KEY:METHOD=NONE")?;
+                }
+                for key in &segment.keys {
+                    writeln!(f, "#EXT-X-KEY:{}", key)?;
+                }
+                keys = &segment.keys;
+            }
+            // a map stays in effect until replaced, so removing one can't be
+            // written
+            if let Some(segment_map) = &segment.map {
+                if map != Some(segment_map) {
+                    writeln!(f, "#EXT-X-MAP:{}", segment_map)?;
+                    map = Some(segment_map);
+                }
+            }
+            if let Some(date_time) = &segment.program_date_time {
+                writeln!(f, "#EXT-X-PROGRAM-DATE-TIME:{}", date_time)?;
+            }
+            if segment.gap {
+                writeln!(f, "#EXT-X-GAP")?;
+            }
+            if let Some(range) = segment.byte_range {
+                writeln!(f, "#EXT-X-BYTERANGE:{}", range)?;
+            }
+            writeln!(f, "#EXTINF:{},{}", segment.duration, segment.title.as_deref().unwrap_or(""))?;
+            writeln!(f, "{}", segment.uri)?;
+        }
+
+        for tag in &self.trailing_tags {
+            writeln!(f, "{}", tag)?;
+        }
+        if self.end_list {
+            writeln!(f, "#EXT-X-ENDLIST")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for KeyMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "NONE",
+            Self::Aes128 => "AES-128",
+            Self::SampleAes => "SAMPLE-AES",
+        })
+    }
+}
+
+/// Writes the attribute list of an `EXT-X-KEY`.
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "METHOD={}", self.method)?;
+        if let Some(uri) = &self.uri {
+            write!(f, ",URI={}", Quoted(uri))?;
+        }
+        if let Some(iv) = &self.iv {
+            write!(f, ",IV=0x")?;
+            for byte in iv {
+                write!(f, "{:02X}", byte)?;
+            }
+        }
+        if let Some(key_format) = &self.key_format {
+            write!(f, ",KEYFORMAT={}", Quoted(key_format))?;
+        }
+        if let Some(versions) = &self.key_format_versions {
+            write!(f, ",KEYFORMATVERSIONS={}", Quoted(versions))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the attribute list of an `EXT-X-START`.
+impl fmt::Display for Start {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TIME-OFFSET={}", self.time_offset)?;
+        if self.precise {
+            f.write_str(",PRECISE=YES")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `<length>@<offset>`.
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.length, self.offset)
+    }
+}
+
+/// Writes the attribute list of an `EXT-X-MAP`.
+impl fmt::Display for Map {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "URI={}", Quoted(&self.uri))?;
+        if let Some(range) = &self.byte_range {
+            write!(f, ",BYTERANGE=\"{}\"", range)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Playlist {
+    type Err = PlaylistFormatError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Playlist::parse(text)
+    }
+}
+
+impl TryFrom<&str> for KeyMethod {
+    type Error = PlaylistFormatError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "NONE" => Ok(Self::None),
+            "AES-128" => Ok(Self::Aes128),
+            "SAMPLE-AES" => Ok(Self::SampleAes),
+            _ => Err(PlaylistFormatError::from(format!("unknown key method {}", value))),
+        }
+    }
+}
+
+impl TryFrom<&str> for Key {
+    type Error = PlaylistFormatError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut method = None;
+        let mut key = Key { method: KeyMethod::None, uri: None, iv: None, key_format: None, key_format_versions: None };
+        for attr in attribute::parse(value)? {
+            match attr.name {
+                "METHOD" => method = Some(KeyMethod::try_from(attr.enumerated_string()?)?),
+                "URI" => key.uri = Some(attr.quoted_string()?.to_string()),
+                "IV" => {
+                    let bytes = attr.hex_sequence()?;
+                    if bytes.len() > 16 {
+                        return Err(PlaylistFormatError::from(format!("IV longer than 128 bits: {} bytes", bytes.len())));
+                    }
+                    let mut iv = [0; 16];
+                    iv[16 - bytes.len()..].copy_from_slice(&bytes);
+                    key.iv = Some(iv);
+                }
+                "KEYFORMAT" => key.key_format = Some(attr.quoted_string()?.to_string()),
+                "KEYFORMATVERSIONS" => key.key_format_versions = Some(attr.quoted_string()?.to_string()),
+                _ => {}
+            }
+        }
+        key.method = method.ok_or_else(|| PlaylistFormatError::from("#EXT-X-KEY without METHOD"))?;
+        if key.method != KeyMethod::None && key.uri.is_none() {
+            return Err(PlaylistFormatError::from("#EXT-X-KEY without URI"));
+        }
+        Ok(key)
+    }
+}
+
+impl TryFrom<&str> for Start {
+    type Error = PlaylistFormatError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut time_offset = None;
+        let mut precise = false;
+        for attr in attribute::parse(value)? {
+            match attr.name {
+                "TIME-OFFSET" => time_offset = Some(attr.signed_float()?),
+                "PRECISE" => precise = attr.yes_no()?,
+                _ => {}
+            }
+        }
+        let time_offset = time_offset.ok_or_else(|| PlaylistFormatError::from("#EXT-X-START without TIME-OFFSET"))?;
+        Ok(Start { time_offset, precise })
+    }
+}
+
+impl TryFrom<&str> for Map {
+    type Error = PlaylistFormatError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut uri = None;
+        let mut byte_range = None;
+        for attr in attribute::parse(value)? {
+            match attr.name {
+                "URI" => uri = Some(attr.quoted_string()?.to_string()),
+                "BYTERANGE" => {
+                    let (length, offset) = parse_byte_range(attr.quoted_string()?)?;
+                    byte_range = Some(ByteRange { length, offset: offset.unwrap_or(0) });
+                }
+                _ => {}
+            }
+        }
+        Ok(Map { uri: uri.ok_or_else(|| PlaylistFormatError::from("#EXT-X-MAP without URI"))?, byte_range })
+    }
+}
+
+fn parse_integer(value: &str) -> Result<u64, PlaylistFormatError> {
+    value.trim().parse().map_err(|_| PlaylistFormatError::from(format!("invalid integer {}", value)))
+}
+
+/// Parses `<length>[@<offset>]`.
+fn parse_byte_range(value: &str) -> Result<(u64, Option<u64>), PlaylistFormatError> {
+    match value.split_once('@') {
+        Some((length, offset)) => Ok((parse_integer(length)?, Some(parse_integer(offset)?))),
+        None => Ok((parse_integer(value)?, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOD: &str = "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:10
+#EXT-X-MEDIA-SEQUENCE:100
+#EXT-X-DISCONTINUITY-SEQUENCE:3
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example/k1?a=1,b=2\",IV=0x0000000000000000000000000000ABCD
+#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00.000Z
+#EXTINF:9.009,first segment
+#EXT-X-BYTERANGE:1000@720
+main.mp4
+#EXTINF:9.009,
+#EXT-X-BYTERANGE:2000
+main.mp4
+# a comment
+#EXT-X-DISCONTINUITY
+#EXT-X-KEY:METHOD=NONE
+#EXT-X-MAP:URI=\"init2.mp4\"
+#EXTINF:3.003
+seg3.mp4
+#EXT-X-GAP
+#EXTINF:10,
+seg4.mp4
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn vod_playlist() {
+        let playlist = Playlist::parse(VOD).unwrap();
+        assert_eq!(playlist.version, Some(7));
+        assert_eq!(playlist.target_duration, 10);
+        assert_eq!(playlist.playlist_type, Some(PlaylistType::Vod));
+        assert!(playlist.end_list);
+        assert_eq!(playlist.segments.len(), 4);
+        assert!((playlist.duration() - 31.021).abs() < 1e-9);
+
+        let sequences: Vec<(u64, u64)> = playlist.segments.iter().map(|s| (s.sequence, s.discontinuity_sequence)).collect();
+        assert_eq!(sequences, [(100, 3), (101, 3), (102, 4), (103, 4)]);
+
+        let [first, second, third, fourth] = &playlist.segments[..] else { unreachable!() };
+        assert_eq!(first.title.as_deref(), Some("first segment"));
+        assert_eq!(first.program_date_time.as_deref(), Some("2024-01-01T00:00:00.000Z"));
+        assert_eq!(first.byte_range, Some(ByteRange { length: 1000, offset: 720 }));
+        assert_eq!(second.byte_range, Some(ByteRange { length: 2000, offset: 1720 }));
+        assert_eq!(second.title, None);
+        assert_eq!(second.program_date_time, None);
+
+        let key = first.key().unwrap();
+        assert_eq!(key.method, KeyMethod::Aes128);
+        assert_eq!(key.uri.as_deref(), Some("https://keys.example/k1?a=1,b=2"));
+        assert_eq!(key.iv.unwrap()[14..], [0xab, 0xcd]);
+        assert_eq!(second.keys, first.keys);
+        assert_eq!(first.map, Some(Map { uri: "init.mp4".into(), byte_range: Some(ByteRange { length: 720, offset: 0 }) }));
+
+        assert!(third.discontinuity && !fourth.discontinuity);
+        assert!(third.keys.is_empty());
+        assert_eq!(third.map.as_ref().unwrap().uri, "init2.mp4");
+        assert!(fourth.gap && !third.gap);
+        assert_eq!(fourth.map, third.map);
+    }
+
+    #[test]
+    fn keys_of_several_formats() {
+        let playlist: Playlist = "#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://key\",KEYFORMAT=\"com.apple.streamingkeydelivery\",KEYFORMATVERSIONS=\"1\"
+#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"https://keys.example/k\"
+#EXTINF:6,
+a.ts
+#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"https://keys.example/k2\"
+#EXTINF:6,
+b.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:6,
+c.ts
+"
+        .parse()
+        .unwrap();
+        assert_eq!(playlist.segments[0].keys.len(), 2);
+        assert_eq!(playlist.segments[0].key().unwrap().uri.as_deref(), Some("https://keys.example/k"));
+        // rotating the identity key keeps the FairPlay one
+        let uris: Vec<_> = playlist.segments[1].keys.iter().map(|key| key.uri.as_deref()).collect();
+        assert_eq!(uris, [Some("skd://key"), Some("https://keys.example/k2")]);
+        assert!(playlist.segments[2].keys.is_empty());
+        assert!(!playlist.end_list);
+        assert_eq!(Playlist::parse(&playlist.to_string()).unwrap(), playlist);
+
+        let mut edited = playlist.clone();
+        edited.segments[1].keys.remove(0);
+        assert_eq!(Playlist::parse(&edited.to_string()).unwrap(), edited);
+    }
+
+    #[test]
+    fn unquotable_strings_fail_to_write() {
+        let mut playlist = Playlist::parse(VOD).unwrap();
+        playlist.segments[0].keys.push(Key { method: KeyMethod::Aes128, uri: Some("k\n1".into()), iv: None, key_format: None, key_format_versions: None });
+        assert!(playlist.to_string().contains("URI=\"k\n1\""));
+        assert_eq!(playlist.write_to(Vec::new()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        playlist.segments[0].keys.last_mut().unwrap().uri = Some("k1".into());
+        let mut written = Vec::new();
+        playlist.write_to(&mut written).unwrap();
+        assert_eq!(written, playlist.to_string().into_bytes());
+    }
+
+    #[test]
+    fn start_offsets_may_be_negative() {
+        let text = std::fs::read_to_string("fixtures/m3u8/media_live_ads.m3u8").unwrap();
+        let playlist = Playlist::parse(&text).unwrap();
+        assert_eq!(playlist.start, Some(Start { time_offset: -12.0, precise: true }));
+        assert!(!playlist.tags.iter().any(|tag| tag.starts_with("#EXT-X-START")));
+        assert!(playlist.to_string().contains("\n#EXT-X-START:TIME-OFFSET=-12,PRECISE=YES\n"));
+        assert_eq!(Playlist::parse(VOD).unwrap().start, None);
+    }
+
+    #[test]
+    fn malformed_playlists() {
+        assert!(Playlist::parse("#EXT-X-TARGETDURATION:10\n").is_err());
+        assert!(Playlist::parse("#EXTM3U\n#EXTINF:10,\na.ts\n").is_err());
+        let no_inf = Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:10\na.ts\n").unwrap_err();
+        assert!(no_inf.to_string().starts_with("line 3:"), "{}", no_inf);
+        assert!(Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-BYTERANGE:100\n#EXTINF:10,\na.ts\n").is_err());
+        assert!(Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-KEY:METHOD=AES-128\n").is_err());
+        assert!(Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-KEY:METHOD=AES-128,URI=\"k\",IV=12\n").is_err());
+        assert!(Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-KEY:METHOD=\"AES-128\",URI=\"k\"\n").is_err());
+        assert!(Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-MAP:URI=init.mp4\n").is_err());
+        assert!(Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-START:PRECISE=YES\n").is_err());
+        assert!(Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-START:TIME-OFFSET=--1\n").is_err());
+        for duration in ["-1", "inf", "NaN", "1e3", ""] {
+            let text = format!("#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXTINF:{},\na.ts\n", duration);
+            assert!(Playlist::parse(&text).is_err(), "{}", duration);
+        }
+    }
+}
@@ -1,5 +1,5 @@
 
-/**
+/*
  * https://www.rfc-editor.org/rfc/rfc8216.html
 */
 
@@ -1,4 +1,4 @@
-/** 
+/*
  * Test URL
  *  - https://devstreaming-cdn.apple.com/videos/streaming/examples/img_bipbop_adv_example_fmp4/master.m3u8
  *  - https://demo.unified-streaming.com/k8s/features/stable/video/tears-of-steel/tears-of-steel.ism/.m3u8
@@ -8,14 +8,29 @@
 extern crate hyper;
 extern crate futures;
 extern crate tokio;
-extern crate hyper_rustls;
 extern crate clap;
-extern crate webpki_roots;
 
+mod decrypt;
+mod download;
+mod fetch;
 mod hls;
+mod record;
+mod select;
+#[cfg(test)]
+mod test_server;
+mod tls;
+mod ts;
+
+use std::{io::IsTerminal, path::{Path, PathBuf}, time::Duration};
 
 use clap::Parser;
-use hls::m3u8::master::Playlist;
+use download::{Downloader, Output, SegmentReport};
+use fetch::{Fetcher, Source};
+use futures::future::try_join_all;
+use hls::m3u8::{self, master, media};
+use record::{Event, Recorder};
+use select::{Bandwidth, RenditionSelector, Resolution, Selection, VariantSelector};
+use url_stream::{HttpVersion, RetryPolicy, Timeouts, UrlStreamClient};
 
 
 
@@ -23,22 +38,304 @@ use hls::m3u8::master::Playlist;
 #[derive(Parser)]
 struct Args {
     
+    /// Playlist to load: an http(s) URL, a file, or - for stdin
     #[arg(short = 'H')]
-    hostname: String,
-}
+    input: String,
+
+    /// Give up on a request without a response after SECONDS
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    response_timeout: u64,
+
+    /// Give up on a response whose body stalls for SECONDS
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    read_timeout: u64,
+
+    /// PEM file of an additional CA to trust (repeatable)
+    #[arg(long = "cacert")]
+    ca_files: Vec<PathBuf>,
+
+    /// Trust the bundled webpki roots instead of the platform's
+    #[arg(long)]
+    webpki_roots: bool,
+
+    /// PEM file of the client certificate chain to present
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// PEM file of the client certificate's private key
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// Require the server to send a certificate whose public key has this
+    /// base64 SHA-256 hash (repeatable, any one matching is enough)
+    #[arg(long = "pin-sha256", value_name = "BASE64", value_parser = tls::parse_pin)]
+    pins: Vec<[u8; 32]>,
+
+    /// Speak HTTP/1.1 only instead of negotiating HTTP/2
+    #[arg(long, conflicts_with = "http2_prior_knowledge")]
+    http1: bool,
+
+    /// Speak HTTP/2 without negotiating it, also over plain http (h2c)
+    #[arg(long)]
+    http2_prior_knowledge: bool,
+
+    /// Keep at most N requests in flight at once to a host, which share a
+    /// connection over HTTP/2
+    #[arg(long, value_name = "N")]
+    max_streams: Option<usize>,
+
+    /// Take the variant with the max or min bandwidth of those left by the other criteria
+    #[arg(long, value_name = "max|min", default_value = "max")]
+    bandwidth: Bandwidth,
+
+    /// Take a variant of this resolution: WIDTHxHEIGHT, or just the height as in 720p
+    #[arg(long)]
+    resolution: Option<Resolution>,
 
-const TEST_HOST_URL: &'static str = "https://demo.unified-streaming.com/k8s/features/stable/video/tears-of-steel/tears-of-steel.ism/.m3u8";
+    /// Take a variant with a codec of this family (avc, hevc, av1, vp9, aac, ac3, ec3) or sample entry (avc1, mp4a, ...)
+    #[arg(long)]
+    codec: Option<String>,
 
-fn main() {
+    /// Take a variant of this frame rate, give or take 0.1
+    #[arg(long, value_name = "FPS")]
+    frame_rate: Option<f64>,
+
+    /// Audio rendition to download along: default, autoselect, lang:CODE or name:NAME
+    #[arg(long, value_name = "SELECTOR")]
+    audio: Option<RenditionSelector>,
+
+    /// Subtitles rendition to download along: default, autoselect, lang:CODE or name:NAME
+    #[arg(long, value_name = "SELECTOR")]
+    subtitles: Option<RenditionSelector>,
+
+    /// Write the parsed playlist back out as M3U8 instead of describing it
+    #[arg(long, conflicts_with_all = ["output", "output_dir"])]
+    emit: bool,
+
+    /// Download the segments into FILE, one after the other (- for stdout)
+    #[arg(short, long, value_name = "FILE", conflicts_with = "output_dir")]
+    output: Option<PathBuf>,
+
+    /// Download the segments into DIR, one file each
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Number of segments downloaded at once
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+
+    /// Times a failed segment is tried again
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Pause before the first retry of a segment, growing with the next ones
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 500)]
+    retry_delay: u64,
+
+    /// Keep reloading a live playlist, downloading new segments until it ends
+    #[arg(long)]
+    record: bool,
+
+    /// Stop recording after SECONDS
+    #[arg(long, value_name = "SECONDS", requires = "record")]
+    stop_after: Option<u64>,
+
+    /// Start a new numbered output file every MINUTES when recording
+    #[arg(long, value_name = "MINUTES", requires = "record", conflicts_with = "output_dir")]
+    rotate: Option<u64>,
+}
+
+#[tokio::main]
+async fn main() {
 
     let args = Args::parse();
-    let playlist = Playlist::try_from(args.hostname.as_str()).unwrap();
-    for stream in playlist.get_variants() {
-        println!("{:?}", stream);
+    if let Err(e) = run(args).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let client_auth = args.cert.as_deref().zip(args.key.as_deref());
+    let tls = tls::client_config(&args.ca_files, args.webpki_roots, client_auth, &args.pins)?;
+    let version = match (args.http1, args.http2_prior_knowledge) {
+        (true, _) => HttpVersion::Http1,
+        (_, true) => HttpVersion::Http2PriorKnowledge,
+        _ => HttpVersion::Auto,
+    };
+    let timeouts = Timeouts::new()
+        .first_byte(Duration::from_secs(args.response_timeout))
+        .read_idle(Duration::from_secs(args.read_timeout));
+    // segments are tried again by the downloader
+    let mut client = UrlStreamClient::builder().tls(tls).http_version(version).timeouts(timeouts).retry(RetryPolicy::none());
+    if let Some(max) = args.max_streams {
+        client = client.max_connections_per_host(max).http2_max_concurrent_streams(max);
     }
+    let fetcher = Fetcher::new(client.build());
+    let source = Source::parse(&args.input);
+    let (data, location) = fetcher.load_playlist(&source).await.map_err(|e| format!("can't load {}: {}", source, e))?;
+    let text = String::from_utf8(data).map_err(|_| format!("{} isn't UTF-8 text", source))?;
 
-    
-    
-    println!("{:?}", playlist);
+    let output = match (args.output, args.output_dir) {
+        (Some(file), _) => Some(Output::File(file)),
+        (_, Some(dir)) => Some(Output::Dir(dir)),
+        _ => None,
+    };
+    if args.record && output.is_none() {
+        return Err("--record needs --output or --output-dir".into());
+    }
+    if let Some(output) = output {
+        let playlists = if m3u8::is_media_playlist(&text) {
+            vec![(None, media::Playlist::parse(&text)?, location)]
+        } else {
+            let master = master::Playlist::parse(&text, &location)?;
+            let variant = VariantSelector { bandwidth: args.bandwidth, resolution: args.resolution, codec: args.codec, frame_rate: args.frame_rate };
+            let interactive = variant.is_default() && args.audio.is_none() && args.subtitles.is_none() && source != Source::Stdin && std::io::stdin().is_terminal();
+            let selection = match interactive {
+                true => select::prompt(&master, std::io::stdin().lock(), std::io::stderr())?,
+                false => select::select(&master, &variant, args.audio.as_ref(), args.subtitles.as_ref())?,
+            };
+            media_playlists(&fetcher, &selection, master.base_url()).await?
+        };
+        let mut jobs = Vec::new();
+        for (label, playlist, location) in playlists {
+            let output = match label {
+                Some(label) => rendition_output(&output, &label, &playlist)?,
+                None => output.clone(),
+            };
+            jobs.push((playlist, location, output));
+        }
+
+        let downloader = Downloader::new(&fetcher).jobs(args.jobs).retries(args.retries).retry_delay(Duration::from_millis(args.retry_delay));
+        if !args.record {
+            try_join_all(jobs.iter().map(|(playlist, location, output)| downloader.download(playlist, location, output, report_part))).await?;
+            return Ok(());
+        }
+
+        let mut recorder = Recorder::new(&fetcher, &downloader).retries(args.retries);
+        if let Some(seconds) = args.stop_after {
+            recorder = recorder.stop_after(Duration::from_secs(seconds));
+        }
+        if let Some(minutes) = args.rotate {
+            if output == Output::File(PathBuf::from("-")) {
+                return Err("can't rotate stdout".into());
+            }
+            recorder = recorder.rotate_every(Duration::from_secs(minutes * 60));
+        }
+        let recorder = &recorder;
+        try_join_all(jobs.iter().map(|(playlist, location, output)| {
+            recorder.record(playlist.clone(), location, output, move |event| report_event(location, event))
+        }))
+        .await?;
+        return Ok(());
+    }
+
+    if m3u8::is_media_playlist(&text) {
+        let playlist = media::Playlist::parse(&text)?;
+        if args.emit {
+            return Ok(playlist.write_to(std::io::stdout().lock())?);
+        }
+        for segment in &playlist.segments {
+            println!("{}", describe_segment(segment));
+        }
+        println!("{} segments, {:.3}s", playlist.segments.len(), playlist.duration());
+    } else {
+        let playlist = master::Playlist::parse(&text, &location)?;
+        if args.emit {
+            return Ok(playlist.write_to(std::io::stdout().lock())?);
+        }
+        select::list(&playlist, std::io::stdout().lock())?;
+    }
+    Ok(())
+}
+
+/// One line telling what `segment` is.
+fn describe_segment(segment: &media::Segment) -> String {
+    let mut line = format!("{:>6} {} {:.3}s", segment.sequence, segment.uri, segment.duration);
+    if let Some(range) = segment.byte_range {
+        line.push_str(&format!(" bytes {}", range));
+    }
+    if let Some(key) = segment.key() {
+        line.push_str(&format!(" {}", key.method));
+    }
+    if segment.discontinuity {
+        line.push_str(" after a discontinuity");
+    }
+    if segment.gap {
+        line.push_str(" gap");
+    }
+    line
+}
+
+/// Tells on stderr how fetching a segment went.
+fn report_part(report: &SegmentReport) {
+    let retries = match report.attempts {
+        1 => String::new(),
+        attempts => format!(" after {} attempts", attempts),
+    };
+    eprintln!("{} {}: {} bytes in {:.2?}{}", report.sequence, report.uri, report.bytes, report.elapsed, retries);
+}
+
+/// Tells on stderr how recording `location` goes.
+fn report_event(location: &str, event: Event) {
+    match event {
+        Event::Part(report) => report_part(report),
+        Event::Discontinuity(sequence) => eprintln!("discontinuity before {}", sequence),
+        Event::Reset { expected, found } => eprintln!("media sequence reset: expected {}, found {}", expected, found),
+        Event::Missed { from, to } => eprintln!("segments {} to {} expired before they were downloaded", from, to - 1),
+        Event::Skipped(e) => eprintln!("{}, left out", e),
+        Event::Rotated(path) => eprintln!("recording to {}", path.display()),
+        Event::ReloadFailed(e) => eprintln!("can't reload {}: {}, trying again", location, e),
+    }
+}
+
+/// Media playlists of `selection` from the master playlist loaded from
+/// `base_url`: the variant stream's, then those of its renditions that
+/// aren't part of it, labelled. Returns each with the location it was
+/// loaded from, after redirects.
+async fn media_playlists(
+    fetcher: &Fetcher,
+    selection: &Selection<'_>,
+    base_url: &str,
+) -> Result<Vec<(Option<String>, media::Playlist, String)>, Box<dyn std::error::Error>> {
+    let renditions = [selection.audio, selection.subtitles].into_iter().flatten();
+    let mut uris = vec![(None, selection.variant.uri)];
+    uris.extend(renditions.filter_map(|rendition| Some((Some(label(&rendition)), rendition.uri?))));
+    let mut playlists = Vec::new();
+    for (label, uri) in uris {
+        let uri = fetch::resolve(base_url, uri);
+        let (data, location) = fetcher.load_playlist(&Source::parse(&uri)).await.map_err(|e| format!("can't load {}: {}", uri, e))?;
+        let text = String::from_utf8(data).map_err(|_| format!("{} isn't UTF-8 text", location))?;
+        playlists.push((label, media::Playlist::parse(&text)?, location));
+    }
+    Ok(playlists)
+}
 
+/// Name telling `rendition` apart in output paths, like `audio-en`.
+fn label(rendition: &master::Rendition) -> String {
+    let name = rendition.language.unwrap_or(rendition.name);
+    let name: String = name.chars().map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    format!("{}-{}", rendition.media_type.to_string().to_lowercase(), name)
+}
+
+/// Where the rendition `label` goes when the variant stream goes to
+/// `output`: a subdirectory of that name, or a file named after the
+/// variant's with the label and the extension of the rendition's segments.
+fn rendition_output(output: &Output, label: &str, playlist: &media::Playlist) -> Result<Output, String> {
+    match output {
+        Output::Dir(dir) => Ok(Output::Dir(dir.join(label))),
+        Output::File(file) if file == Path::new("-") => Err("can't write renditions to stdout along with the variant".into()),
+        Output::File(file) => {
+            let segment = playlist.segments.first().and_then(|segment| segment.uri.split(['?', '#']).next());
+            let extension = segment.and_then(|uri| Path::new(uri).extension()).or(file.extension());
+            let mut name = file.file_stem().unwrap_or_default().to_os_string();
+            name.push(".");
+            name.push(label);
+            if let Some(extension) = extension {
+                name.push(".");
+                name.push(extension);
+            }
+            Ok(Output::File(file.with_file_name(name)))
+        }
+    }
 }
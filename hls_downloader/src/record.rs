@@ -0,0 +1,465 @@
+//! Recording live media playlists as they grow.
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    download::{DownloadError, Downloader, Output, SegmentReport, Sink},
+    fetch::{FetchError, Fetcher, Source},
+    hls::m3u8::media::Playlist,
+};
+
+/// What happened while recording.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A segment or initialization section was written.
+    Part(&'a SegmentReport),
+    /// The segment with this media sequence number follows a discontinuity.
+    Discontinuity(u64),
+    /// The media sequence started over, as when the encoder restarts.
+    Reset { expected: u64, found: u64 },
+    /// Segments `from..to` left the playlist before they were downloaded.
+    Missed { from: u64, to: u64 },
+    /// A segment couldn't be downloaded, retries exhausted, and was left out.
+    Skipped(&'a DownloadError),
+    /// Output goes to this file from now on.
+    Rotated(&'a Path),
+    /// Reloading the playlist failed, it's tried again later.
+    ReloadFailed(&'a FetchError),
+}
+
+#[derive(Debug)]
+pub enum RecordError {
+    /// The playlist couldn't be reloaded, retries exhausted.
+    Reload { location: String, error: FetchError },
+    /// The reloaded playlist is malformed.
+    Playlist { location: String, error: String },
+    Download(DownloadError),
+}
+
+/// Follows a live media playlist, downloading segments as they're added.
+pub struct Recorder<'a> {
+    fetcher: &'a Fetcher,
+    downloader: &'a Downloader<'a>,
+    stop_after: Option<Duration>,
+    rotate_every: Option<Duration>,
+    retries: u32,
+}
+
+/// Output file being written.
+struct Recording {
+    sink: Sink,
+    /// When to move on to the next file.
+    rotate_at: Option<Instant>,
+    empty: bool,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Reload { location, error } => write!(f, "can't reload {}: {}", location, error),
+            RecordError::Playlist { location, error } => write!(f, "{}: {}", location, error),
+            RecordError::Download(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RecordError::Reload { error, .. } => Some(error),
+            RecordError::Playlist { .. } => None,
+            RecordError::Download(e) => Some(e),
+        }
+    }
+}
+
+impl From<DownloadError> for RecordError {
+    fn from(e: DownloadError) -> Self {
+        RecordError::Download(e)
+    }
+}
+
+impl From<io::Error> for RecordError {
+    fn from(e: io::Error) -> Self {
+        RecordError::Download(DownloadError::Output(e))
+    }
+}
+
+impl<'a> Recorder<'a> {
+    /// Downloads segments through `downloader`, until the playlist ends.
+    pub fn new(fetcher: &'a Fetcher, downloader: &'a Downloader<'a>) -> Self {
+        Recorder { fetcher, downloader, stop_after: None, rotate_every: None, retries: 3 }
+    }
+
+    /// Stops recording after `limit`, even if the playlist goes on.
+    pub fn stop_after(mut self, limit: Duration) -> Self {
+        self.stop_after = Some(limit);
+        self
+    }
+
+    /// Starts a new file every `period` when recording to a file, numbering
+    /// them: `live.ts` becomes `live-000.ts`, `live-001.ts` and so on.
+    pub fn rotate_every(mut self, period: Duration) -> Self {
+        self.rotate_every = Some(period);
+        self
+    }
+
+    /// Times in a row reloading the playlist may fail with a transient
+    /// error before recording gives up.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Records the media playlist at `location` to `output`, starting with
+    /// `playlist` just loaded from there, until `EXT-X-ENDLIST` or the time
+    /// limit. Segments which fail for good are reported and left out, and
+    /// the output is finished however recording ends.
+    ///
+    /// The playlist is reloaded as RFC 8216, section 6.3.4 asks: a target
+    /// duration after loading it, or half that when it hadn't changed.
+    pub async fn record(&self, playlist: Playlist, location: &str, output: &Output, mut on_event: impl FnMut(Event)) -> Result<(), RecordError> {
+        let mut files = 0;
+        let mut recording = self.open(output, &mut files, &mut on_event).await?;
+        let result = self.follow(playlist, location, output, &mut files, &mut recording, &mut on_event).await;
+        // what was recorded is kept whichever way recording ended
+        let finished = recording.sink.finish().await;
+        result?;
+        Ok(finished?)
+    }
+
+    /// Records to `recording` until the playlist ends or the time limit,
+    /// leaving it open.
+    async fn follow(
+        &self,
+        mut playlist: Playlist,
+        location: &str,
+        output: &Output,
+        files: &mut u32,
+        recording: &mut Recording,
+        on_event: &mut impl FnMut(Event),
+    ) -> Result<(), RecordError> {
+        let started = Instant::now();
+        let deadline = self.stop_after.map(|limit| started + limit);
+        let mut loaded_at = started;
+        let mut changed = true;
+        // where the last load was redirected to, which segments resolve against
+        let mut base_url = location.to_string();
+        // media sequence number and URI of the last segment seen
+        let mut last: Option<(u64, String)> = None;
+        let mut recorded = false;
+        loop {
+            let (start, event) = resume(&playlist, last.as_ref());
+            if let Some(event) = event {
+                if let Event::Reset { .. } = event {
+                    recording.sink.reset();
+                }
+                on_event(event);
+            }
+            for segment in &playlist.segments[start..] {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Ok(());
+                }
+                if !recording.empty && recording.rotate_at.is_some_and(|at| Instant::now() >= at) {
+                    let next = self.open(output, files, on_event).await?;
+                    std::mem::replace(recording, next).sink.finish().await?;
+                }
+                if segment.discontinuity && recorded {
+                    on_event(Event::Discontinuity(segment.sequence));
+                }
+                let downloaded = self.downloader
+                    .download_segments(std::slice::from_ref(segment), &base_url, &mut recording.sink, |report| on_event(Event::Part(report)))
+                    .await;
+                match downloaded {
+                    Ok(()) => recording.empty = false,
+                    // a live recording goes on without the segment
+                    Err(error @ (DownloadError::Segment { .. } | DownloadError::Decrypt { .. })) => on_event(Event::Skipped(&error)),
+                    Err(error) => return Err(error.into()),
+                }
+                recorded = true;
+            }
+            last = playlist.segments.last().map(|segment| (segment.sequence, segment.uri.clone())).or(last);
+            if playlist.end_list {
+                return Ok(());
+            }
+
+            let target_duration = Duration::from_secs(playlist.target_duration.max(1));
+            let reload_at = loaded_at + if changed { target_duration } else { target_duration / 2 };
+            if deadline.is_some_and(|deadline| reload_at >= deadline) {
+                return Ok(());
+            }
+            tokio::time::sleep_until(reload_at).await;
+            let mut failures = 0;
+            let (reloaded, reloaded_from) = loop {
+                loaded_at = Instant::now();
+                match self.load(location).await {
+                    Err(RecordError::Reload { error, .. }) if error.is_transient() && failures < self.retries => {
+                        failures += 1;
+                        on_event(Event::ReloadFailed(&error));
+                        tokio::time::sleep(target_duration / 2).await;
+                    }
+                    reloaded => break reloaded?,
+                }
+            };
+            changed = reloaded != playlist;
+            playlist = reloaded;
+            base_url = reloaded_from;
+        }
+    }
+
+    /// Loads the playlist at `location`, with where redirects led.
+    async fn load(&self, location: &str) -> Result<(Playlist, String), RecordError> {
+        let (data, base_url) = self
+            .fetcher
+            .load_playlist(&Source::parse(location))
+            .await
+            .map_err(|error| RecordError::Reload { location: location.to_string(), error })?;
+        let text = String::from_utf8(data).map_err(|_| RecordError::Playlist { location: location.to_string(), error: "not UTF-8 text".into() })?;
+        let playlist = Playlist::parse(&text).map_err(|e| RecordError::Playlist { location: location.to_string(), error: e.to_string() })?;
+        Ok((playlist, base_url))
+    }
+
+    /// Opens `output`, or its next numbered file when rotating files.
+    async fn open(&self, output: &Output, files: &mut u32, on_event: &mut impl FnMut(Event)) -> io::Result<Recording> {
+        match (output, self.rotate_every) {
+            (Output::File(path), Some(period)) => {
+                let path = numbered(path, *files);
+                *files += 1;
+                on_event(Event::Rotated(&path));
+                let sink = Sink::open(&Output::File(path)).await?;
+                Ok(Recording { sink, rotate_at: Some(Instant::now() + period), empty: true })
+            }
+            _ => Ok(Recording { sink: Sink::open(output).await?, rotate_at: None, empty: true }),
+        }
+    }
+}
+
+/// Index in `playlist` of the first segment not recorded yet, after the
+/// `last` one, with what happened in between if it doesn't follow.
+fn resume(playlist: &Playlist, last: Option<&(u64, String)>) -> (usize, Option<Event<'static>>) {
+    let Some((sequence, uri)) = last else {
+        return (0, None);
+    };
+    let first = playlist.media_sequence;
+    let end = first + playlist.segments.len() as u64;
+    let expected = sequence + 1;
+    // sequence numbers going back, or a known one now naming another segment
+    let reused = playlist.segments.iter().any(|segment| segment.sequence == *sequence && segment.uri != *uri);
+    if end < expected || reused {
+        (0, Some(Event::Reset { expected, found: first }))
+    } else if first > expected {
+        (0, Some(Event::Missed { from: expected, to: first }))
+    } else {
+        ((expected - first) as usize, None)
+    }
+}
+
+/// `path` numbered `index`, before its extension.
+fn numbered(path: &Path, index: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{:03}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}-{:03}", stem, index),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+    };
+
+    use hyper::{Body, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::test_server::{fetcher, serve, temp_path};
+
+    /// Live playlist by reload: unchanged once, then sliding with a
+    /// discontinuity, then ending.
+    const RELOADS: [&str; 4] = [
+        "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXTINF:1,\n0.ts\n#EXTINF:1,\n1.ts\n",
+        "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXTINF:1,\n0.ts\n#EXTINF:1,\n1.ts\n",
+        "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:1\n#EXTINF:1,\n1.ts\n#EXT-X-DISCONTINUITY\n#EXTINF:1,\n2.ts\n",
+        "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:2\n#EXT-X-DISCONTINUITY\n#EXTINF:1,\n2.ts\n#EXTINF:1,\n3.ts\n#EXT-X-ENDLIST\n",
+    ];
+
+    /// Live playlist by reload, restarting its media sequence, with a
+    /// discontinuity in the first load.
+    const RESTARTS: [&str; 2] = [
+        "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXTINF:1,\n0.ts\n#EXT-X-DISCONTINUITY\n#EXTINF:1,\n1.ts\n",
+        "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXTINF:1,\n5.ts\n#EXTINF:1,\n6.ts\n#EXT-X-ENDLIST\n",
+    ];
+
+    /// Stand-in origin of `/live.m3u8`, which follows [`RELOADS`], of
+    /// `/restart.m3u8`, which follows [`RESTARTS`], and of `/endless.m3u8`,
+    /// which never ends. Counts playlist loads.
+    fn origin() -> (SocketAddr, Arc<AtomicU32>) {
+        let loads = Arc::new(AtomicU32::new(0));
+        let counter = loads.clone();
+        let addr = serve(move |req: Request<Body>| {
+            let loads = loads.clone();
+            async move {
+                let path = req.uri().path();
+                match path {
+                    "/live.m3u8" => {
+                        let load = loads.fetch_add(1, Ordering::SeqCst) as usize;
+                        Response::new(Body::from(RELOADS[load.min(RELOADS.len() - 1)]))
+                    }
+                    "/restart.m3u8" => {
+                        let load = loads.fetch_add(1, Ordering::SeqCst) as usize;
+                        Response::new(Body::from(RESTARTS[load.min(RESTARTS.len() - 1)]))
+                    }
+                    "/endless.m3u8" => {
+                        let load = loads.fetch_add(1, Ordering::SeqCst);
+                        Response::new(Body::from(format!("#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:{}\n#EXTINF:1,\n{}.ts\n", load, load)))
+                    }
+                    _ if path.ends_with(".ts") => Response::new(Body::from(format!("{};", &path[1..path.len() - 3]))),
+                    _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+                }
+            }
+        });
+        (addr, counter)
+    }
+
+    /// Events as text, to compare them once the reports they borrow are gone.
+    fn describe(event: Event) -> String {
+        match event {
+            Event::Part(report) => format!("part {}", report.sequence),
+            Event::Rotated(path) => format!("rotated {}", path.file_name().unwrap().to_string_lossy()),
+            event => format!("{:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn live_playlists_are_followed_to_the_end() {
+        let (addr, loads) = origin();
+        let location = format!("http://{}/live.m3u8", addr);
+        let fetcher = fetcher();
+        let downloader = Downloader::new(&fetcher);
+        let playlist = Playlist::parse(RELOADS[0]).unwrap();
+        loads.store(1, Ordering::SeqCst);
+
+        let path = temp_path("live.ts");
+        let mut events = Vec::new();
+        let started = Instant::now();
+        let recorder = Recorder::new(&fetcher, &downloader).rotate_every(Duration::ZERO);
+        recorder.record(playlist, &location, &Output::File(path.clone()), |event| events.push(describe(event))).await.unwrap();
+
+        // a target duration after changes, half of it after none
+        assert!(started.elapsed() >= Duration::from_millis(2500));
+        assert_eq!(loads.load(Ordering::SeqCst), 4);
+        let name = |index| numbered(&path, index).file_name().unwrap().to_string_lossy().into_owned();
+        // each segment gets a file of its own, the period being over at once
+        let expected = [
+            format!("rotated {}", name(0)),
+            "part 0".into(),
+            format!("rotated {}", name(1)),
+            "part 1".into(),
+            format!("rotated {}", name(2)),
+            "Discontinuity(2)".into(),
+            "part 2".into(),
+            format!("rotated {}", name(3)),
+            "part 3".into(),
+        ];
+        assert_eq!(events, expected);
+        for (index, content) in ["0;", "1;", "2;", "3;"].into_iter().enumerate() {
+            let path = numbered(&path, index as u32);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_keep_what_was_recorded() {
+        let (addr, loads) = origin();
+        let location = format!("http://{}/restart.m3u8", addr);
+        let fetcher = fetcher();
+        let downloader = Downloader::new(&fetcher);
+        let playlist = Playlist::parse(RESTARTS[0]).unwrap();
+        loads.store(1, Ordering::SeqCst);
+
+        let dir = temp_path("restart");
+        let mut events = Vec::new();
+        let recorder = Recorder::new(&fetcher, &downloader);
+        recorder.record(playlist, &location, &Output::Dir(dir.clone()), |event| events.push(describe(event))).await.unwrap();
+
+        assert_eq!(events, ["part 0", "Discontinuity(1)", "part 1", "Reset { expected: 2, found: 0 }", "part 0", "part 1"]);
+        let mut files: Vec<String> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        files.sort();
+        assert_eq!(files, ["0.ts", "1.ts", "r1-0.ts", "r1-1.ts"]);
+        assert_eq!(std::fs::read_to_string(dir.join("1.ts")).unwrap(), "1;");
+        assert_eq!(std::fs::read_to_string(dir.join("r1-1.ts")).unwrap(), "6;");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_segments_are_skipped_and_output_kept() {
+        let (addr, _) = origin();
+        let fetcher = fetcher();
+        let downloader = Downloader::new(&fetcher).retry_delay(Duration::from_millis(10));
+        let recorder = Recorder::new(&fetcher, &downloader);
+        let playlist = Playlist::parse("#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXTINF:1,\n0.ts\n#EXTINF:1,\nmissing.mp4\n#EXTINF:1,\n2.ts\n").unwrap();
+
+        // reloading fails for good once the segments are in
+        let path = temp_path("gone.ts");
+        let mut events = Vec::new();
+        let location = format!("http://{}/gone.m3u8", addr);
+        let error = recorder.record(playlist, &location, &Output::File(path.clone()), |event| events.push(describe(event))).await.unwrap_err();
+        assert!(matches!(error, RecordError::Reload { error: FetchError::Status(StatusCode::NOT_FOUND), .. }), "{}", error);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], "part 0");
+        assert!(events[1].starts_with("Skipped(Segment { sequence: 1, uri: \"missing.mp4\""), "{}", events[1]);
+        assert_eq!(events[2], "part 2");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0;2;");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recording_stops_after_the_limit() {
+        let (addr, _) = origin();
+        let location = format!("http://{}/endless.m3u8", addr);
+        let fetcher = fetcher();
+        let downloader = Downloader::new(&fetcher);
+        let recorder = Recorder::new(&fetcher, &downloader).stop_after(Duration::from_millis(1500));
+        let (playlist, _) = recorder.load(&location).await.unwrap();
+
+        let path = temp_path("endless.ts");
+        recorder.record(playlist, &location, &Output::File(path.clone()), |_| {}).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0;1;");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reloads_resume_after_the_last_segment() {
+        let playlist = |sequence: u64, uris: &[&str]| {
+            let mut text = format!("#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:{}\n", sequence);
+            for uri in uris {
+                text.push_str(&format!("#EXTINF:4,\n{}\n", uri));
+            }
+            Playlist::parse(&text).unwrap()
+        };
+        let last = Some((11, "b.ts".to_string()));
+        let resume = |playlist, last: &Option<(u64, String)>| {
+            let (start, event) = resume(&playlist, last.as_ref());
+            (start, event.map(describe))
+        };
+        assert_eq!(resume(playlist(10, &["a.ts", "b.ts"]), &None), (0, None));
+        assert_eq!(resume(playlist(10, &["a.ts", "b.ts"]), &last), (2, None));
+        assert_eq!(resume(playlist(11, &["b.ts", "c.ts"]), &last), (1, None));
+        assert_eq!(resume(playlist(14, &["e.ts"]), &last), (0, Some("Missed { from: 12, to: 14 }".into())));
+        assert_eq!(resume(playlist(0, &["x.ts"]), &last), (0, Some("Reset { expected: 12, found: 0 }".into())));
+        // a sequence number seen before now names another segment
+        assert_eq!(resume(playlist(10, &["x.ts", "y.ts"]), &last), (0, Some("Reset { expected: 12, found: 10 }".into())));
+    }
+}
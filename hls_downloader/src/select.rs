@@ -0,0 +1,418 @@
+//! Choosing the variant stream and the renditions to download from a
+//! master playlist.
+
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+    str::FromStr,
+};
+
+use crate::hls::m3u8::master::{MediaType, Playlist, Rendition, RenditionGroup, Variant};
+
+/// Codec families, with the `CODECS` sample entry names they cover.
+const CODEC_FAMILIES: [(&str, &[&str]); 10] = [
+    ("avc", &["avc1", "avc3"]),
+    ("h264", &["avc1", "avc3"]),
+    ("hevc", &["hvc1", "hev1"]),
+    ("h265", &["hvc1", "hev1"]),
+    ("av1", &["av01"]),
+    ("vp9", &["vp09"]),
+    ("aac", &["mp4a"]),
+    ("ac3", &["ac-3"]),
+    ("eac3", &["ec-3"]),
+    ("ec3", &["ec-3"]),
+];
+/// Frame rates this close are taken as the same, so that 30 picks 29.97.
+const FRAME_RATE_TOLERANCE: f64 = 0.1;
+
+/// Which of the variants left by the other criteria to take.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Bandwidth {
+    #[default]
+    Max,
+    Min,
+}
+
+/// `WIDTHxHEIGHT`, or just the height as in `720` or `720p`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Exact(u32, u32),
+    Height(u32),
+}
+
+/// Criteria for the variant stream, all of which it must meet.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VariantSelector {
+    pub bandwidth: Bandwidth,
+    pub resolution: Option<Resolution>,
+    /// Codec family, like `avc` or `aac`, or a sample entry name like `avc1`.
+    pub codec: Option<String>,
+    pub frame_rate: Option<f64>,
+}
+
+/// How to pick a rendition within the group of the variant stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenditionSelector {
+    /// `LANGUAGE`, matching subtags too: `en` takes `en-US`.
+    Language(String),
+    Name(String),
+    Default,
+    AutoSelect,
+}
+
+/// What to download from a master playlist.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Selection<'p> {
+    pub variant: Variant<'p>,
+    pub audio: Option<Rendition<'p>>,
+    pub subtitles: Option<Rendition<'p>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectError {
+    EmptyPlaylist,
+    NoMatchingVariant,
+    /// The variant stream doesn't refer to a group of this type.
+    NoGroup(MediaType),
+    NoMatchingRendition(MediaType),
+}
+
+impl fmt::Display for SelectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectError::EmptyPlaylist => f.write_str("master playlist has no variants"),
+            SelectError::NoMatchingVariant => f.write_str("no variant matches"),
+            SelectError::NoGroup(media_type) => write!(f, "variant has no {} renditions", media_type),
+            SelectError::NoMatchingRendition(media_type) => write!(f, "no {} rendition of the variant matches", media_type),
+        }
+    }
+}
+
+impl std::error::Error for SelectError {}
+
+impl FromStr for Bandwidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(Bandwidth::Max),
+            "min" => Ok(Bandwidth::Min),
+            _ => Err(format!("expected max or min, not {}", s)),
+        }
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected WIDTHxHEIGHT or HEIGHT, not {}", s);
+        match s.split_once('x') {
+            Some((width, height)) => Ok(Resolution::Exact(width.parse().map_err(|_| invalid())?, height.parse().map_err(|_| invalid())?)),
+            None => s.strip_suffix('p').unwrap_or(s).parse().map(Resolution::Height).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl FromStr for RenditionSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s.eq_ignore_ascii_case("default") => Ok(RenditionSelector::Default),
+            _ if s.eq_ignore_ascii_case("autoselect") => Ok(RenditionSelector::AutoSelect),
+            Some(("lang" | "language", language)) if !language.is_empty() => Ok(RenditionSelector::Language(language.to_string())),
+            Some(("name", name)) if !name.is_empty() => Ok(RenditionSelector::Name(name.to_string())),
+            _ => Err(format!("expected default, autoselect, lang:CODE or name:NAME, not {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Variant<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((width, height)) = self.resolution {
+            write!(f, "{}x{} ", width, height)?;
+        }
+        match self.bandwidth {
+            Some(bandwidth) => write!(f, "{} kbit/s", bandwidth / 1000)?,
+            None => f.write_str("? kbit/s")?,
+        }
+        if let Some(rate) = self.frame_rate {
+            write!(f, " {} fps", rate)?;
+        }
+        if let Some(codecs) = self.codecs {
+            write!(f, " {}", codecs)?;
+        }
+        write!(f, " ({})", self.uri)
+    }
+}
+
+impl fmt::Display for Rendition<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)?;
+        if let Some(language) = self.language {
+            write!(f, " [{}]", language)?;
+        }
+        if self.default {
+            f.write_str(" default")?;
+        }
+        if self.uri.is_none() {
+            f.write_str(" (in the variant)")?;
+        }
+        Ok(())
+    }
+}
+
+impl VariantSelector {
+    /// Whether only the default bandwidth criterion is set.
+    pub fn is_default(&self) -> bool {
+        *self == VariantSelector::default()
+    }
+
+    fn matches(&self, variant: &Variant) -> bool {
+        let resolution = match self.resolution {
+            Some(Resolution::Exact(width, height)) => variant.resolution == Some((width, height)),
+            Some(Resolution::Height(height)) => variant.resolution.is_some_and(|(_, h)| h == height),
+            None => true,
+        };
+        let codec = match &self.codec {
+            Some(family) => variant.codecs.is_some_and(|codecs| codec_matches(family, codecs)),
+            None => true,
+        };
+        let frame_rate = match self.frame_rate {
+            Some(rate) => variant.frame_rate.is_some_and(|r| (r - rate).abs() < FRAME_RATE_TOLERANCE),
+            None => true,
+        };
+        resolution && codec && frame_rate
+    }
+
+    /// The variant meeting the criteria with the highest, or lowest,
+    /// bandwidth.
+    pub fn select<'p>(&self, variants: &[Variant<'p>]) -> Result<Variant<'p>, SelectError> {
+        if variants.is_empty() {
+            return Err(SelectError::EmptyPlaylist);
+        }
+        let matching = variants.iter().filter(|variant| self.matches(variant));
+        let bandwidth = |variant: &&Variant| variant.bandwidth.unwrap_or(0);
+        let selected = match self.bandwidth {
+            Bandwidth::Max => matching.max_by_key(bandwidth),
+            Bandwidth::Min => matching.min_by_key(bandwidth),
+        };
+        selected.copied().ok_or(SelectError::NoMatchingVariant)
+    }
+}
+
+impl RenditionSelector {
+    fn matches(&self, rendition: &Rendition) -> bool {
+        match self {
+            RenditionSelector::Language(language) => rendition.language.is_some_and(|l| {
+                l.eq_ignore_ascii_case(language) || l.get(..language.len() + 1).is_some_and(|prefix| prefix.eq_ignore_ascii_case(&format!("{}-", language)))
+            }),
+            RenditionSelector::Name(name) => rendition.name.eq_ignore_ascii_case(name),
+            RenditionSelector::Default => rendition.default,
+            RenditionSelector::AutoSelect => rendition.autoselect,
+        }
+    }
+}
+
+/// Whether `codecs`, a `CODECS` value, has one of `family`.
+fn codec_matches(family: &str, codecs: &str) -> bool {
+    let family = family.to_ascii_lowercase();
+    let own = [family.as_str()];
+    let names = CODEC_FAMILIES.iter().find(|(name, _)| *name == family).map_or(&own[..], |(_, names)| names);
+    codecs.split(',').any(|codec| names.contains(&codec.trim().split('.').next().unwrap_or_default()))
+}
+
+/// Rendition picked by `selector` from `group`, the variant's renditions of
+/// `media_type`. Without a selector, audio is the group's default
+/// rendition, if any, and subtitles none.
+fn select_rendition<'p>(group: Option<RenditionGroup<'p>>, media_type: MediaType, selector: Option<&RenditionSelector>) -> Result<Option<Rendition<'p>>, SelectError> {
+    match selector {
+        Some(selector) => {
+            let group = group.ok_or(SelectError::NoGroup(media_type))?;
+            let rendition = group.renditions.into_iter().find(|rendition| selector.matches(rendition));
+            rendition.map(Some).ok_or(SelectError::NoMatchingRendition(media_type))
+        }
+        None if media_type == MediaType::Audio => Ok(group.and_then(|group| group.default().copied())),
+        None => Ok(None),
+    }
+}
+
+/// Picks the variant stream and renditions to download from `playlist`.
+pub fn select<'p>(
+    playlist: &'p Playlist,
+    variant: &VariantSelector,
+    audio: Option<&RenditionSelector>,
+    subtitles: Option<&RenditionSelector>,
+) -> Result<Selection<'p>, SelectError> {
+    let variant = variant.select(&playlist.variants())?;
+    Ok(Selection {
+        variant,
+        audio: select_rendition(variant.audio_group(), MediaType::Audio, audio)?,
+        subtitles: select_rendition(variant.subtitle_group(), MediaType::Subtitles, subtitles)?,
+    })
+}
+
+/// Lists the variants of `playlist` on `output`, each followed by the
+/// renditions it refers to.
+pub fn list(playlist: &Playlist, mut output: impl Write) -> io::Result<()> {
+    for (n, variant) in playlist.variants().iter().enumerate() {
+        writeln!(output, "{:>3}) {}", n + 1, variant)?;
+        for media_type in [MediaType::Audio, MediaType::Video, MediaType::Subtitles, MediaType::CCs] {
+            for rendition in variant.group(media_type).map(|group| group.renditions).unwrap_or_default() {
+                writeln!(output, "       {}: {}", media_type.to_string().to_lowercase(), rendition)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lists the variants of `playlist` and the renditions of the one chosen on
+/// `output`, asking which to download on `input`. An empty answer takes
+/// what [`select`] would without criteria.
+pub fn prompt<'p>(playlist: &'p Playlist, mut input: impl BufRead, mut output: impl Write) -> io::Result<Selection<'p>> {
+    let invalid = |e: SelectError| io::Error::new(io::ErrorKind::InvalidData, e);
+    let variants = playlist.variants();
+    let default = VariantSelector::default().select(&variants).map_err(invalid)?;
+    let mut ask = |question: &str, choices: &[String], default: Option<usize>| -> io::Result<Option<usize>> {
+        for (n, choice) in choices.iter().enumerate() {
+            writeln!(output, "{:>3}) {}", n + 1, choice)?;
+        }
+        loop {
+            match default {
+                Some(default) => write!(output, "{} [1-{}, default {}]: ", question, choices.len(), default + 1)?,
+                None => write!(output, "{} [1-{}, default none]: ", question, choices.len())?,
+            }
+            output.flush()?;
+            let mut answer = String::new();
+            if input.read_line(&mut answer)? == 0 || answer.trim().is_empty() {
+                return Ok(default);
+            }
+            match answer.trim().parse::<usize>() {
+                Ok(n) if (1..=choices.len()).contains(&n) => return Ok(Some(n - 1)),
+                _ => writeln!(output, "{} isn't one of the choices", answer.trim())?,
+            }
+        }
+    };
+
+    let choices: Vec<String> = variants.iter().map(Variant::to_string).collect();
+    let index = ask("variant", &choices, variants.iter().position(|variant| *variant == default))?;
+    let variant = index.map_or(default, |index| variants[index]);
+    let mut selection = Selection { variant, audio: None, subtitles: None };
+    for (media_type, group) in [(MediaType::Audio, variant.audio_group()), (MediaType::Subtitles, variant.subtitle_group())] {
+        let default = select_rendition(group.clone(), media_type, None).map_err(invalid)?;
+        let group = group.map(|group| group.renditions).unwrap_or_default();
+        let chosen = match group.len() {
+            0 => None,
+            // a lone rendition leaves nothing to choose
+            1 if media_type == MediaType::Audio => Some(group[0]),
+            _ => {
+                let choices: Vec<String> = group.iter().map(Rendition::to_string).collect();
+                let index = ask(&media_type.to_string().to_lowercase(), &choices, group.iter().position(|rendition| Some(*rendition) == default))?;
+                index.map(|index| group[index])
+            }
+        };
+        match media_type {
+            MediaType::Audio => selection.audio = chosen,
+            _ => selection.subtitles = chosen,
+        }
+    }
+    Ok(selection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",LANGUAGE=\"en-US\",NAME=\"English\",AUTOSELECT=YES,DEFAULT=YES,URI=\"audio/en.m3u8\"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",LANGUAGE=\"fr\",NAME=\"Français\",AUTOSELECT=YES,URI=\"audio/fr.m3u8\"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"ec3\",LANGUAGE=\"en\",NAME=\"English 5.1\",DEFAULT=YES,URI=\"audio/en-51.m3u8\"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",LANGUAGE=\"de\",NAME=\"Deutsch\",URI=\"subs/de.m3u8\"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",LANGUAGE=\"en\",NAME=\"English\",FORCED=NO,URI=\"subs/en.m3u8\"
+#EXT-X-STREAM-INF:BANDWIDTH=800000,CODECS=\"avc1.4d401e,mp4a.40.2\",RESOLUTION=640x360,FRAME-RATE=29.970,AUDIO=\"aac\",SUBTITLES=\"subs\"
+360p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=3000000,CODECS=\"avc1.64001f,mp4a.40.2\",RESOLUTION=1280x720,FRAME-RATE=59.940,AUDIO=\"aac\",SUBTITLES=\"subs\"
+720p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=6000000,CODECS=\"hvc1.2.4.L123.B0,ec-3\",RESOLUTION=1920x1080,FRAME-RATE=29.970,AUDIO=\"ec3\"
+1080p.m3u8
+";
+
+    fn playlist() -> Playlist {
+        Playlist::parse(MASTER, "https://example.com/master.m3u8").unwrap()
+    }
+
+    fn selector(s: &str) -> RenditionSelector {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn variants_are_filtered_then_picked_by_bandwidth() {
+        let playlist = playlist();
+        let variants = playlist.variants();
+        let uri = |selector: VariantSelector| selector.select(&variants).map(|variant| variant.uri);
+        assert_eq!(uri(VariantSelector::default()), Ok("1080p.m3u8"));
+        assert_eq!(uri(VariantSelector { bandwidth: Bandwidth::Min, ..Default::default() }), Ok("360p.m3u8"));
+        assert_eq!(uri(VariantSelector { resolution: Some("720p".parse().unwrap()), ..Default::default() }), Ok("720p.m3u8"));
+        assert_eq!(uri(VariantSelector { resolution: Some("640x360".parse().unwrap()), ..Default::default() }), Ok("360p.m3u8"));
+        assert_eq!(uri(VariantSelector { codec: Some("h264".into()), ..Default::default() }), Ok("720p.m3u8"));
+        assert_eq!(uri(VariantSelector { codec: Some("hevc".into()), ..Default::default() }), Ok("1080p.m3u8"));
+        assert_eq!(uri(VariantSelector { codec: Some("avc1".into()), bandwidth: Bandwidth::Min, ..Default::default() }), Ok("360p.m3u8"));
+        assert_eq!(uri(VariantSelector { frame_rate: Some(30.0), codec: Some("aac".into()), ..Default::default() }), Ok("360p.m3u8"));
+        assert_eq!(uri(VariantSelector { codec: Some("av1".into()), ..Default::default() }), Err(SelectError::NoMatchingVariant));
+        assert_eq!(VariantSelector::default().select(&[]), Err(SelectError::EmptyPlaylist));
+        assert!("720i".parse::<Resolution>().is_err());
+    }
+
+    #[test]
+    fn renditions_come_from_the_variant_group() {
+        let playlist = playlist();
+        let name = |variant: &str, audio: Option<&str>, subtitles: Option<&str>| {
+            let variant = VariantSelector { resolution: Some(variant.parse().unwrap()), ..Default::default() };
+            let selection = select(&playlist, &variant, audio.map(selector).as_ref(), subtitles.map(selector).as_ref())?;
+            Ok((selection.audio.map(|rendition| rendition.name), selection.subtitles.map(|rendition| rendition.name)))
+        };
+        assert_eq!(name("720", None, None), Ok((Some("English"), None)));
+        assert_eq!(name("720", Some("lang:fr"), Some("name:deutsch")), Ok((Some("Français"), Some("Deutsch"))));
+        // en takes en-US, but e doesn't
+        assert_eq!(name("720", Some("lang:en"), Some("lang:en")), Ok((Some("English"), Some("English"))));
+        assert_eq!(name("720", Some("lang:e"), None), Err(SelectError::NoMatchingRendition(MediaType::Audio)));
+        // each variant has its own audio group
+        assert_eq!(name("1080", Some("default"), None), Ok((Some("English 5.1"), None)));
+        assert_eq!(name("1080", Some("autoselect"), None), Err(SelectError::NoMatchingRendition(MediaType::Audio)));
+        assert_eq!(name("1080", None, Some("default")), Err(SelectError::NoGroup(MediaType::Subtitles)));
+        assert!("lang:".parse::<RenditionSelector>().is_err());
+        assert!("english".parse::<RenditionSelector>().is_err());
+    }
+
+    #[test]
+    fn variants_are_listed_with_their_renditions() {
+        let mut listing = Vec::new();
+        list(&playlist(), &mut listing).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 3 + 4 + 4 + 1, "{}", listing);
+        assert_eq!(lines[0], "  1) 640x360 800 kbit/s 29.97 fps avc1.4d401e,mp4a.40.2 (360p.m3u8)");
+        assert_eq!(lines[1], "       audio: English [en-US] default");
+        assert_eq!(lines[4], "       subtitles: English [en]");
+        assert_eq!(lines[11], "       audio: English 5.1 [en] default");
+    }
+
+    #[test]
+    fn choices_are_prompted_for() {
+        let playlist = playlist();
+        let mut listing = Vec::new();
+        let selection = prompt(&playlist, "4\n2\n\n2\n".as_bytes(), &mut listing).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        assert!(listing.contains("  2) 1280x720 3000 kbit/s 59.94 fps avc1.64001f,mp4a.40.2 (720p.m3u8)\n"), "{}", listing);
+        assert!(listing.contains("4 isn't one of the choices"), "{}", listing);
+        assert!(listing.contains("subtitles [1-2, default none]: "), "{}", listing);
+        assert_eq!(selection.variant.uri, "720p.m3u8");
+        assert_eq!(selection.audio.map(|rendition| rendition.name), Some("English"));
+        assert_eq!(selection.subtitles.map(|rendition| rendition.name), Some("English"));
+
+        // end of input takes the defaults
+        let selection = prompt(&playlist, "".as_bytes(), Vec::new()).unwrap();
+        assert_eq!(selection, select(&playlist, &VariantSelector::default(), None, None).unwrap());
+    }
+}
@@ -0,0 +1,91 @@
+//! Stand-in servers and fixtures shared by the tests.
+
+use std::{convert::Infallible, future::Future, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use hyper::{
+    server::conn::Http,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+
+use url_stream::{HttpVersion, RetryPolicy, TlsConfig, UrlStreamClient, UrlStreamClientBuilder};
+
+use crate::fetch::Fetcher;
+
+/// Serves `handler` on an ephemeral local port of the current runtime.
+pub fn serve<F, R>(handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let make_service = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let res = handler(req);
+                async move { Ok::<_, Infallible>(res.await) }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+/// Serves `handler` over TLS for `localhost` with a freshly generated
+/// certificate, offering the `alpn` protocols. Returns the address and the
+/// PEM file of the certificate, for clients to trust.
+pub fn serve_tls<F, R>(alpn: &[&[u8]], handler: F) -> (SocketAddr, PathBuf)
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let chain = vec![rustls::Certificate(cert.serialize_der().unwrap())];
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(chain, rustls::PrivateKey(cert.serialize_private_key_der()))
+        .unwrap();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+    tokio::spawn(async move {
+        while let Ok((tcp, _)) = listener.accept().await {
+            let (acceptor, handler) = (acceptor.clone(), handler.clone());
+            tokio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(tcp).await {
+                    let service = service_fn(move |req| {
+                        let res = handler(req);
+                        async move { Ok::<_, Infallible>(res.await) }
+                    });
+                    let _ = Http::new().serve_connection(stream, service).await;
+                }
+            });
+        }
+    });
+
+    let pem = temp_path(&format!("localhost-{}.pem", addr.port()));
+    std::fs::write(&pem, cert.serialize_pem().unwrap()).unwrap();
+    (addr, pem)
+}
+
+/// Client speaking HTTP/1.1, trusting the bundled roots, without retries.
+pub fn client() -> UrlStreamClientBuilder {
+    UrlStreamClient::builder().tls(TlsConfig::new().webpki_roots()).http_version(HttpVersion::Http1).retry(RetryPolicy::none())
+}
+
+/// Fetcher through [`client`].
+pub fn fetcher() -> Fetcher {
+    Fetcher::new(client().build())
+}
+
+/// Path in the temporary directory unique to this test process.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("hls_downloader-{}-{}", std::process::id(), name))
+}
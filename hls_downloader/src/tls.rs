@@ -0,0 +1,57 @@
+use std::{error::Error, fs, path::{Path, PathBuf}};
+
+use base64::Engine;
+use url_stream::TlsConfig;
+
+/// Builds the TLS settings used to fetch playlists.
+///
+/// Trusts the platform's roots, or the bundled webpki roots when
+/// `webpki_roots` is set, plus every certificate in the `ca_files` PEM
+/// files. `client_auth` names the PEM files of a client certificate chain
+/// and its private key. With `pins`, servers must also send a certificate
+/// whose public key has one of these hashes (see [`parse_pin`]).
+pub fn client_config(ca_files: &[PathBuf], webpki_roots: bool, client_auth: Option<(&Path, &Path)>, pins: &[[u8; 32]]) -> Result<TlsConfig, Box<dyn Error>> {
+    let mut tls = TlsConfig::new();
+    if webpki_roots {
+        tls = tls.webpki_roots();
+    }
+    for file in ca_files {
+        tls = tls.add_root_pem(&fs::read(file)?).map_err(|e| format!("{}: {}", file.display(), e))?;
+    }
+    if let Some((cert_file, key_file)) = client_auth {
+        tls = tls.client_auth_pem(&fs::read(cert_file)?, &fs::read(key_file)?)
+            .map_err(|e| format!("{}, {}: {}", cert_file.display(), key_file.display(), e))?;
+    }
+    for pin in pins {
+        tls = tls.pin_spki_sha256(*pin);
+    }
+    Ok(tls)
+}
+
+/// Parses the base64 SHA-256 hash of a `SubjectPublicKeyInfo`, as printed by
+/// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`.
+pub fn parse_pin(pin: &str) -> Result<[u8; 32], String> {
+    let pin = pin.strip_prefix("sha256//").unwrap_or(pin);
+    let hash = base64::engine::general_purpose::STANDARD.decode(pin).map_err(|e| format!("invalid base64: {}", e))?;
+    hash.try_into().map_err(|hash: Vec<u8>| format!("a SHA-256 hash is 32 bytes, not {}", hash.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_and_files() {
+        let pin = parse_pin("sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=").unwrap();
+        assert_eq!(pin, [0; 32]);
+        assert_eq!(parse_pin("AAAA").unwrap_err(), "a SHA-256 hash is 32 bytes, not 3");
+        assert!(parse_pin("not base64!").is_err());
+
+        assert!(client_config(&[], true, None, &[pin]).is_ok());
+        let empty = crate::test_server::temp_path("empty.pem");
+        fs::write(&empty, "").unwrap();
+        let error = client_config(std::slice::from_ref(&empty), true, None, &[]).unwrap_err();
+        assert!(error.to_string().starts_with(&empty.display().to_string()), "{}", error);
+        fs::remove_file(empty).unwrap();
+    }
+}
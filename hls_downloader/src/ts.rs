@@ -0,0 +1,459 @@
+//! MPEG transport streams (ISO/IEC 13818-1), just enough to rewrite the
+//! elementary streams of a segment and the PMT describing them.
+
+use std::{collections::HashMap, fmt};
+
+pub const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TsError {
+    /// No sync byte at the offset, or a truncated packet there.
+    Sync(usize),
+    /// A PSI section on the PID is malformed or spans packets.
+    Section(u16),
+    /// A rewritten PES packet on the PID no longer fits its packets.
+    PesGrown(u16),
+}
+
+/// Elementary stream as the PMT declares it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stream {
+    pub stream_type: u8,
+    pub pid: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Descriptor {
+    pub tag: u8,
+    pub data: Vec<u8>,
+}
+
+/// A transport packet, split in its parts.
+struct Packet<'a> {
+    header: [u8; 4],
+    /// Adaptation field, without its length byte.
+    adaptation: Option<&'a [u8]>,
+    payload: &'a [u8],
+}
+
+/// Packets of a PES packet being collected.
+struct Pes {
+    /// Slots of the packets in the output.
+    slots: Vec<usize>,
+    data: Vec<u8>,
+}
+
+impl fmt::Display for TsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsError::Sync(offset) => write!(f, "lost sync at byte {}", offset),
+            TsError::Section(pid) => write!(f, "bad PSI section on PID {}", pid),
+            TsError::PesGrown(pid) => write!(f, "rewritten PES packet on PID {} is larger than the original", pid),
+        }
+    }
+}
+
+impl std::error::Error for TsError {}
+
+impl<'a> Packet<'a> {
+    fn parse(data: &'a [u8], offset: usize) -> Result<Self, TsError> {
+        if data.len() != PACKET_SIZE || data[0] != SYNC_BYTE {
+            return Err(TsError::Sync(offset));
+        }
+        let header = [data[0], data[1], data[2], data[3]];
+        let (adaptation, payload) = match (data[3] >> 4) & 0b11 {
+            0b10 | 0b11 => {
+                let end = 5 + data[4] as usize;
+                if end > PACKET_SIZE {
+                    return Err(TsError::Sync(offset));
+                }
+                let payload = if data[3] & 0x10 != 0 { &data[end..] } else { &[][..] };
+                (Some(&data[5..end]), payload)
+            }
+            0b01 => (None, &data[4..]),
+            _ => (None, &[][..]),
+        };
+        Ok(Packet { header, adaptation, payload })
+    }
+
+    fn pid(&self) -> u16 {
+        u16::from_be_bytes([self.header[1] & 0x1f, self.header[2]])
+    }
+
+    fn payload_start(&self) -> bool {
+        self.header[1] & 0x40 != 0
+    }
+
+    fn continuity_counter(&self) -> u8 {
+        self.header[3] & 0x0f
+    }
+
+    /// Whether the adaptation field carries more than stuffing.
+    fn has_adaptation_fields(&self) -> bool {
+        self.adaptation.is_some_and(|adaptation| adaptation.first().is_some_and(|&flags| flags != 0))
+    }
+
+    /// Writes the packet with `payload` instead, stuffing the adaptation
+    /// field to fill the space left.
+    fn write(&self, payload: &[u8], continuity_counter: u8, out: &mut Vec<u8>) {
+        let space = PACKET_SIZE - 4 - payload.len();
+        let control = match (self.adaptation.is_some() || space > 0, !payload.is_empty()) {
+            (true, true) => 0b11,
+            (true, false) => 0b10,
+            (false, _) => 0b01,
+        };
+        let start = out.len();
+        out.extend_from_slice(&[self.header[0], self.header[1], self.header[2], (self.header[3] & 0xc0) | control << 4 | continuity_counter]);
+        if space > 0 {
+            out.push((space - 1) as u8);
+            if space > 1 {
+                match self.adaptation {
+                    Some(adaptation) if !adaptation.is_empty() => out.extend_from_slice(adaptation),
+                    _ => out.push(0),
+                }
+                out.resize(start + 4 + space, 0xff);
+            }
+        }
+        out.extend_from_slice(payload);
+    }
+}
+
+/// Rewrites the elementary streams of `segment`.
+///
+/// `edit_stream` may change each stream the PMT declares, and tells
+/// whether its PES packets are to be rewritten. `edit_pes` is then given
+/// the original stream type and the payload of each such PES packet, which
+/// it may shorten. Rewritten PES packets take the place of the originals,
+/// keeping the adaptation fields of their packets, so PCRs stay where they
+/// were.
+pub fn rewrite(
+    segment: &[u8],
+    mut edit_stream: impl FnMut(&mut Stream) -> bool,
+    mut edit_pes: impl FnMut(u8, &mut Vec<u8>),
+) -> Result<Vec<u8>, TsError> {
+    if !segment.len().is_multiple_of(PACKET_SIZE) {
+        return Err(TsError::Sync(segment.len() - segment.len() % PACKET_SIZE));
+    }
+    let packets = segment
+        .chunks(PACKET_SIZE)
+        .enumerate()
+        .map(|(i, data)| Packet::parse(data, i * PACKET_SIZE))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Rewritten packets, `None` for those a shorter PES packet no longer needs.
+    let mut slots: Vec<Option<Vec<u8>>> = vec![None; packets.len()];
+    let mut pmt_pids = Vec::new();
+    // stream type by PID of the streams to rewrite
+    let mut edited = HashMap::new();
+    let mut pending: HashMap<u16, Pes> = HashMap::new();
+    let mut continuity_counters: HashMap<u16, u8> = HashMap::new();
+    for (i, packet) in packets.iter().enumerate() {
+        let pid = packet.pid();
+        if pid == PAT_PID && packet.payload_start() {
+            pmt_pids = parse_pat(section(packet.payload, pid)?, pid)?;
+        } else if pmt_pids.contains(&pid) && packet.payload_start() {
+            let section = section(packet.payload, pid)?;
+            let mut streams = parse_pmt(section, pid)?;
+            for stream in &mut streams {
+                let stream_type = stream.stream_type;
+                if edit_stream(stream) {
+                    edited.insert(stream.pid, stream_type);
+                }
+            }
+            // pointer field and whatever it skips
+            let mut payload = packet.payload[..1 + packet.payload[0] as usize].to_vec();
+            write_pmt(section, &streams, &mut payload);
+            if payload.len() > packet.payload.len() {
+                return Err(TsError::Section(pid));
+            }
+            payload.resize(packet.payload.len(), 0xff);
+            let mut out = Vec::with_capacity(PACKET_SIZE);
+            packet.write(&payload, packet.continuity_counter(), &mut out);
+            slots[i] = Some(out);
+            continue;
+        } else if let Some(&stream_type) = edited.get(&pid) {
+            if packet.payload_start() {
+                if let Some(pes) = pending.remove(&pid) {
+                    write_pes(&packets, pes, stream_type, &mut edit_pes, &mut continuity_counters, &mut slots)?;
+                }
+            }
+            if packet.payload_start() || pending.contains_key(&pid) {
+                let pes = pending.entry(pid).or_insert(Pes { slots: Vec::new(), data: Vec::new() });
+                pes.slots.push(i);
+                pes.data.extend_from_slice(packet.payload);
+                continue;
+            }
+        }
+        slots[i] = Some(segment[i * PACKET_SIZE..(i + 1) * PACKET_SIZE].to_vec());
+    }
+    for (pid, pes) in pending {
+        write_pes(&packets, pes, edited[&pid], &mut edit_pes, &mut continuity_counters, &mut slots)?;
+    }
+    Ok(slots.into_iter().flatten().flatten().collect())
+}
+
+/// PSI section starting in `payload`, which must hold all of it.
+fn section(payload: &[u8], pid: u16) -> Result<&[u8], TsError> {
+    let pointer = *payload.first().ok_or(TsError::Section(pid))? as usize;
+    let section = payload.get(1 + pointer..).filter(|section| section.len() >= 3).ok_or(TsError::Section(pid))?;
+    let length = 3 + (u16::from_be_bytes([section[1] & 0x0f, section[2]]) as usize);
+    section.get(..length).filter(|section| section.len() >= 12).ok_or(TsError::Section(pid))
+}
+
+/// PIDs of the PMTs listed in a PAT section.
+fn parse_pat(section: &[u8], pid: u16) -> Result<Vec<u16>, TsError> {
+    if section[0] != 0x00 {
+        return Err(TsError::Section(pid));
+    }
+    let programs = &section[8..section.len() - 4];
+    Ok(programs
+        .chunks_exact(4)
+        .filter(|program| program[..2] != [0, 0])
+        .map(|program| u16::from_be_bytes([program[2] & 0x1f, program[3]]))
+        .collect())
+}
+
+fn parse_pmt(section: &[u8], pid: u16) -> Result<Vec<Stream>, TsError> {
+    let program_info_length = u16::from_be_bytes([section[10] & 0x0f, section[11]]) as usize;
+    if section[0] != 0x02 || 12 + program_info_length > section.len() - 4 {
+        return Err(TsError::Section(pid));
+    }
+    let mut streams = Vec::new();
+    let mut rest = &section[12 + program_info_length..section.len() - 4];
+    while !rest.is_empty() {
+        let info_length = rest.get(3..5).map(|length| u16::from_be_bytes([length[0] & 0x0f, length[1]]) as usize);
+        let info = info_length.and_then(|length| rest.get(5..5 + length)).ok_or(TsError::Section(pid))?;
+        let mut descriptors = Vec::new();
+        let mut info_rest = info;
+        while !info_rest.is_empty() {
+            let data = info_rest.get(1).and_then(|&length| info_rest.get(2..2 + length as usize)).ok_or(TsError::Section(pid))?;
+            descriptors.push(Descriptor { tag: info_rest[0], data: data.to_vec() });
+            info_rest = &info_rest[2 + data.len()..];
+        }
+        streams.push(Stream { stream_type: rest[0], pid: u16::from_be_bytes([rest[1] & 0x1f, rest[2]]), descriptors });
+        rest = &rest[5 + info.len()..];
+    }
+    Ok(streams)
+}
+
+/// Writes `section` declaring `streams` instead, with its length and CRC
+/// updated.
+fn write_pmt(section: &[u8], streams: &[Stream], out: &mut Vec<u8>) {
+    let program_info_length = u16::from_be_bytes([section[10] & 0x0f, section[11]]) as usize;
+    let start = out.len();
+    out.extend_from_slice(&section[..12 + program_info_length]);
+    for stream in streams {
+        let info_length: usize = stream.descriptors.iter().map(|descriptor| 2 + descriptor.data.len()).sum();
+        out.push(stream.stream_type);
+        out.extend_from_slice(&(0xe000 | stream.pid).to_be_bytes());
+        out.extend_from_slice(&(0xf000 | info_length as u16).to_be_bytes());
+        for descriptor in &stream.descriptors {
+            out.extend_from_slice(&[descriptor.tag, descriptor.data.len() as u8]);
+            out.extend_from_slice(&descriptor.data);
+        }
+    }
+    let section_length = (out.len() - start - 3 + 4) as u16;
+    out[start + 1] = (section[1] & 0xf0) | (section_length >> 8) as u8;
+    out[start + 2] = section_length as u8;
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Rewrites the payload of a PES packet and lays it out in the slots of its
+/// packets.
+fn write_pes(
+    packets: &[Packet],
+    mut pes: Pes,
+    stream_type: u8,
+    edit_pes: &mut impl FnMut(u8, &mut Vec<u8>),
+    continuity_counters: &mut HashMap<u16, u8>,
+    slots: &mut [Option<Vec<u8>>],
+) -> Result<(), TsError> {
+    let first = &packets[pes.slots[0]];
+    let pid = first.pid();
+    // start code, stream id, length, flags and the header data length
+    if pes.data.len() >= 9 && pes.data[..3] == [0, 0, 1] {
+        let header_length = 9 + pes.data[8] as usize;
+        if header_length <= pes.data.len() {
+            let mut payload = pes.data.split_off(header_length);
+            let original_length = payload.len();
+            edit_pes(stream_type, &mut payload);
+            if payload.len() > original_length {
+                return Err(TsError::PesGrown(pid));
+            }
+            let packet_length = u16::from_be_bytes([pes.data[4], pes.data[5]]);
+            if packet_length != 0 {
+                let packet_length = packet_length.saturating_sub((original_length - payload.len()) as u16);
+                pes.data[4..6].copy_from_slice(&packet_length.to_be_bytes());
+            }
+            pes.data.extend(payload);
+        }
+    }
+
+    let mut rest = &pes.data[..];
+    for &i in &pes.slots {
+        let packet = &packets[i];
+        let counter = continuity_counters.entry(pid).or_insert(first.continuity_counter());
+        let taken = packet.payload.len().min(rest.len());
+        if taken > 0 {
+            let mut out = Vec::with_capacity(PACKET_SIZE);
+            packet.write(&rest[..taken], *counter, &mut out);
+            slots[i] = Some(out);
+            *counter = (*counter + 1) & 0x0f;
+            rest = &rest[taken..];
+        } else if packet.has_adaptation_fields() {
+            // no payload left for the packet, but its PCR or flags are kept
+            let mut out = Vec::with_capacity(PACKET_SIZE);
+            packet.write(&[], counter.wrapping_sub(1) & 0x0f, &mut out);
+            slots[i] = Some(out);
+        }
+    }
+    Ok(())
+}
+
+/// CRC-32/MPEG-2 of PSI sections.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Packetizes `pes` after a PAT and a PMT declaring `streams`, putting a
+/// PCR in the first packet of the first stream.
+#[cfg(test)]
+pub fn mux(streams: &[Stream], pes: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut pat = vec![0x00, 0xb0, 0, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xe1, 0x00];
+    pat[2] = (pat.len() - 3 + 4) as u8;
+    let crc = crc32(&pat);
+    pat.extend_from_slice(&crc.to_be_bytes());
+    let mut pmt = Vec::new();
+    write_pmt(&[0x02, 0xb0, 0, 0x00, 0x01, 0xc1, 0x00, 0x00, 0xe1, 0x01, 0xf0, 0x00], streams, &mut pmt);
+
+    let mut out = Vec::new();
+    for (pid, section) in [(PAT_PID, pat), (0x100, pmt)] {
+        let end = out.len() + PACKET_SIZE;
+        out.extend_from_slice(&[SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x10, 0]);
+        out.extend_from_slice(&section);
+        out.resize(end, 0xff);
+    }
+    let mut counters: HashMap<u16, u8> = HashMap::new();
+    let mut packetize = |pid: u16, data: &[u8], pcr: bool, out: &mut Vec<u8>| {
+        let mut rest = data;
+        let mut first = true;
+        while !rest.is_empty() {
+            let adaptation: &[u8] = if first && pcr { &[0x10, 0, 0, 0, 0, 0x7e, 0] } else { &[] };
+            let capacity = PACKET_SIZE - 4 - if adaptation.is_empty() { 0 } else { 1 + adaptation.len() };
+            let taken = capacity.min(rest.len());
+            let counter = counters.entry(pid).or_insert(0);
+            let header = [SYNC_BYTE, (if first { 0x40 } else { 0 }) | (pid >> 8) as u8, pid as u8, 0x10];
+            let packet = Packet { header, adaptation: (!adaptation.is_empty()).then_some(adaptation), payload: &[] };
+            packet.write(&rest[..taken], *counter, out);
+            *counter = (*counter + 1) & 0x0f;
+            rest = &rest[taken..];
+            first = false;
+        }
+    };
+    for (i, (pid, data)) in pes.iter().enumerate() {
+        packetize(*pid, data, i == 0, &mut out);
+    }
+    out
+}
+
+/// PES packet of `stream_id` carrying `payload`.
+#[cfg(test)]
+pub fn pes(stream_id: u8, payload: &[u8]) -> Vec<u8> {
+    let length = if stream_id >= 0xe0 { 0 } else { (3 + payload.len()) as u16 };
+    let mut pes = vec![0, 0, 1, stream_id];
+    pes.extend_from_slice(&length.to_be_bytes());
+    pes.extend_from_slice(&[0x80, 0x00, 0x00]);
+    pes.extend_from_slice(payload);
+    pes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn streams() -> Vec<Stream> {
+        vec![
+            Stream { stream_type: 0x1b, pid: 0x101, descriptors: vec![Descriptor { tag: 0x0f, data: b"zavc".to_vec() }] },
+            Stream { stream_type: 0x0f, pid: 0x102, descriptors: vec![] },
+        ]
+    }
+
+    /// PES packets of every stream, split back out of `segment`.
+    fn demux(segment: &[u8]) -> (Vec<Stream>, HashMap<u16, Vec<u8>>) {
+        let mut streams = Vec::new();
+        let mut data: HashMap<u16, Vec<u8>> = HashMap::new();
+        let mut counters: HashMap<u16, u8> = HashMap::new();
+        for (i, packet) in segment.chunks(PACKET_SIZE).enumerate() {
+            let packet = Packet::parse(packet, i * PACKET_SIZE).unwrap();
+            if packet.pid() == 0x100 {
+                let section = section(packet.payload, 0x100).unwrap();
+                assert_eq!(crc32(section), 0, "CRC of the PMT");
+                streams = parse_pmt(section, 0x100).unwrap();
+            } else if packet.pid() != PAT_PID && !packet.payload.is_empty() {
+                if let Some(counter) = counters.insert(packet.pid(), packet.continuity_counter()) {
+                    assert_eq!(packet.continuity_counter(), (counter + 1) & 0x0f, "continuity of packet {}", i);
+                }
+                data.entry(packet.pid()).or_default().extend_from_slice(packet.payload);
+            }
+        }
+        (streams, data)
+    }
+
+    #[test]
+    fn shortened_pes_packets_are_repacketized() {
+        let video = pes(0xe0, &[7; 1000]);
+        let audio = pes(0xc0, &[9; 300]);
+        let segment = mux(&streams(), &[(0x101, video.clone()), (0x102, audio.clone()), (0x101, video.clone())]);
+
+        let rewritten = rewrite(
+            &segment,
+            |stream| {
+                stream.descriptors.clear();
+                stream.stream_type == 0x1b
+            },
+            |stream_type, payload| {
+                assert_eq!(stream_type, 0x1b);
+                payload.truncate(400);
+            },
+        )
+        .unwrap();
+
+        // 1000 bytes took 6 packets, 400 take 3, and the first keeps its PCR
+        assert_eq!(rewritten.len(), segment.len() - 6 * PACKET_SIZE);
+        let first = Packet::parse(&rewritten[2 * PACKET_SIZE..3 * PACKET_SIZE], 0).unwrap();
+        assert_eq!(first.adaptation.unwrap()[0], 0x10);
+        let (streams, data) = demux(&rewritten);
+        assert!(streams.iter().all(|stream| stream.descriptors.is_empty()));
+        assert_eq!(data[&0x101], [pes(0xe0, &[7; 400]), pes(0xe0, &[7; 400])].concat());
+        assert_eq!(data[&0x102], audio);
+    }
+
+    #[test]
+    fn pes_lengths_follow_the_payload() {
+        let segment = mux(&streams(), &[(0x102, pes(0xc0, &[9; 300]))]);
+        let rewritten = rewrite(&segment, |stream| stream.pid == 0x102, |_, payload| payload.truncate(100)).unwrap();
+        let (_, data) = demux(&rewritten);
+        assert_eq!(data[&0x102], pes(0xc0, &[9; 100]));
+    }
+
+    #[test]
+    fn broken_segments_are_reported() {
+        let segment = mux(&streams(), &[(0x101, pes(0xe0, &[7; 10]))]);
+        assert_eq!(rewrite(&segment[..100], |_| true, |_, _| {}), Err(TsError::Sync(0)));
+        let mut shifted = segment.clone();
+        shifted[PACKET_SIZE] = 0;
+        assert_eq!(rewrite(&shifted, |_| true, |_, _| {}), Err(TsError::Sync(PACKET_SIZE)));
+        assert_eq!(rewrite(&segment, |_| true, |_, payload| payload.push(0)), Err(TsError::PesGrown(0x101)));
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use base64::Engine;
+use hyper::{
+    header::{self, HeaderValue},
+    HeaderMap,
+};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// Credentials a stream authenticates with, bound to the origin of the URL
+/// it was opened with. They are never sent to another origin.
+#[derive(Clone)]
+pub(crate) struct Auth {
+    origin: url::Origin,
+    method: AuthMethod,
+}
+
+#[derive(Clone)]
+enum AuthMethod {
+    /// From the URL userinfo; Basic or Digest, as the server asks.
+    Password { user: String, password: String },
+    Bearer(String),
+}
+
+/// Authentication schemes servers asked for, by origin, so later requests
+/// authenticate up front instead of after a 401.
+#[derive(Default)]
+pub(crate) struct AuthCache {
+    schemes: Mutex<HashMap<String, Scheme>>,
+}
+
+#[derive(Clone)]
+enum Scheme {
+    Basic,
+    Digest(Arc<DigestChallenge>),
+}
+
+/// `WWW-Authenticate: Digest` challenge (RFC 7616).
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    /// Whether `qop=auth` was offered; otherwise the RFC 2069 form is used.
+    qop_auth: bool,
+    nonce_count: AtomicU32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Algorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl fmt::Debug for AuthCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthCache").field("origins", &self.schemes.lock().unwrap().len()).finish()
+    }
+}
+
+impl Auth {
+    /// Credentials for a stream opened on `url`: its userinfo, else the
+    /// client's bearer token.
+    pub(crate) fn for_url(url: &url::Url, bearer: Option<&str>) -> Option<Auth> {
+        let method = if !url.username().is_empty() {
+            let decode = |s: &str| percent_encoding::percent_decode_str(s).decode_utf8_lossy().into_owned();
+            AuthMethod::Password { user: decode(url.username()), password: decode(url.password().unwrap_or("")) }
+        } else {
+            AuthMethod::Bearer(bearer?.to_string())
+        };
+        Some(Auth { origin: url.origin(), method })
+    }
+
+    pub(crate) fn applies_to(&self, url: &url::Url) -> bool {
+        url.origin() == self.origin
+    }
+
+    /// `Authorization` to send up front for a request to `url`, if the
+    /// scheme is already known.
+    pub(crate) fn authorization(&self, cache: &AuthCache, url: &url::Url) -> Option<HeaderValue> {
+        if !self.applies_to(url) {
+            return None;
+        }
+        match &self.method {
+            AuthMethod::Bearer(token) => HeaderValue::from_str(&format!("Bearer {}", token)).ok(),
+            AuthMethod::Password { user, password } => {
+                let scheme = cache.schemes.lock().unwrap().get(&url.origin().ascii_serialization()).cloned()?;
+                match scheme {
+                    Scheme::Basic => basic(user, password),
+                    Scheme::Digest(challenge) => challenge.authorization(user, password, &request_uri(url), &cnonce()),
+                }
+            }
+        }
+    }
+
+    /// Records the schemes offered by a 401 response to `url`. Returns
+    /// whether the request is worth repeating with credentials.
+    pub(crate) fn challenged(&self, cache: &AuthCache, url: &url::Url, headers: &HeaderMap, sent: Option<&HeaderValue>) -> bool {
+        if !self.applies_to(url) || !matches!(self.method, AuthMethod::Password { .. }) {
+            return false;
+        }
+        let challenges = parse_challenges(headers);
+        let digest = challenges.iter().find(|(scheme, _)| scheme.eq_ignore_ascii_case("digest"));
+        let scheme = match digest.and_then(|(_, params)| DigestChallenge::from_params(params)) {
+            // a repeated 401 for a digest answer is only worth another try
+            // when the nonce merely went stale
+            Some(challenge) if sent.is_none() || stale(digest) => Scheme::Digest(Arc::new(challenge)),
+            Some(_) => return false,
+            None if sent.is_none() && challenges.iter().any(|(scheme, _)| scheme.eq_ignore_ascii_case("basic")) => Scheme::Basic,
+            None => return false,
+        };
+        cache.schemes.lock().unwrap().insert(url.origin().ascii_serialization(), scheme);
+        true
+    }
+}
+
+/// `url` without its userinfo, as sent on the wire and used as cache key.
+pub(crate) fn without_credentials(url: &url::Url) -> url::Url {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url
+}
+
+fn stale(digest: Option<&(String, HashMap<String, String>)>) -> bool {
+    digest.and_then(|(_, params)| params.get("stale")).is_some_and(|v| v.eq_ignore_ascii_case("true"))
+}
+
+fn basic(user: &str, password: &str) -> Option<HeaderValue> {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+    HeaderValue::from_str(&format!("Basic {}", encoded)).ok()
+}
+
+/// Path and query, the `uri` a digest covers.
+fn request_uri(url: &url::Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+fn cnonce() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "MD5" => Some(Algorithm::Md5),
+            "MD5-SESS" => Some(Algorithm::Md5Sess),
+            "SHA-256" => Some(Algorithm::Sha256),
+            "SHA-256-SESS" => Some(Algorithm::Sha256Sess),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Md5Sess => "MD5-sess",
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha256Sess => "SHA-256-sess",
+        }
+    }
+
+    fn hash(self, data: &str) -> String {
+        let digest = match self {
+            Algorithm::Md5 | Algorithm::Md5Sess => Md5::digest(data).to_vec(),
+            Algorithm::Sha256 | Algorithm::Sha256Sess => Sha256::digest(data).to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl DigestChallenge {
+    fn from_params(params: &HashMap<String, String>) -> Option<Self> {
+        let algorithm = match params.get("algorithm") {
+            Some(name) => Algorithm::parse(name)?,
+            None => Algorithm::Md5,
+        };
+        let qop_auth = match params.get("qop") {
+            Some(qop) => qop.split(',').any(|q| q.trim().eq_ignore_ascii_case("auth")),
+            None => false,
+        };
+        if params.contains_key("qop") && !qop_auth {
+            // only auth-int offered
+            return None;
+        }
+        Some(DigestChallenge {
+            realm: params.get("realm").cloned().unwrap_or_default(),
+            nonce: params.get("nonce")?.clone(),
+            opaque: params.get("opaque").cloned(),
+            algorithm,
+            qop_auth,
+            nonce_count: AtomicU32::new(0),
+        })
+    }
+
+    /// `Authorization` value answering the challenge for a GET of `uri`.
+    fn authorization(&self, user: &str, password: &str, uri: &str, cnonce: &str) -> Option<HeaderValue> {
+        let nc = format!("{:08x}", self.nonce_count.fetch_add(1, Ordering::Relaxed) + 1);
+        let mut ha1 = self.algorithm.hash(&format!("{}:{}:{}", user, self.realm, password));
+        if matches!(self.algorithm, Algorithm::Md5Sess | Algorithm::Sha256Sess) {
+            ha1 = self.algorithm.hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        let ha2 = self.algorithm.hash(&format!("GET:{}", uri));
+        let response = if self.qop_auth {
+            self.algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, self.nonce, nc, cnonce, ha2))
+        } else {
+            self.algorithm.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2))
+        };
+        let mut value = format!(
+            "Digest username=\"{}\", realm=\"{}\", uri=\"{}\", algorithm={}, nonce=\"{}\"",
+            quote(user), quote(&self.realm), quote(uri), self.algorithm.name(), quote(&self.nonce)
+        );
+        if self.qop_auth {
+            value.push_str(&format!(", nc={}, cnonce=\"{}\", qop=auth", nc, cnonce));
+        }
+        value.push_str(&format!(", response=\"{}\"", response));
+        if let Some(opaque) = &self.opaque {
+            value.push_str(&format!(", opaque=\"{}\"", quote(opaque)));
+        }
+        HeaderValue::from_str(&value).ok()
+    }
+}
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Parses `WWW-Authenticate` into the offered schemes and their parameters,
+/// names lowercased (RFC 7235, 4.1). Several challenges may share a header.
+fn parse_challenges(headers: &HeaderMap) -> Vec<(String, HashMap<String, String>)> {
+    let mut challenges: Vec<(String, HashMap<String, String>)> = Vec::new();
+    for value in headers.get_all(header::WWW_AUTHENTICATE) {
+        let Ok(value) = value.to_str() else { continue };
+        let mut rest = value.trim_start();
+        while !rest.is_empty() {
+            let token_end = rest.find(|c: char| c == ',' || c == '=' || c.is_whitespace()).unwrap_or(rest.len());
+            let token = &rest[..token_end];
+            let after = rest[token_end..].trim_start();
+            if let Some(after_eq) = after.strip_prefix('=').filter(|_| !token.is_empty()) {
+                // parameter of the current challenge
+                let after_eq = after_eq.trim_start();
+                let (param, remainder) = if let Some(quoted) = after_eq.strip_prefix('"') {
+                    let mut param = String::new();
+                    let mut chars = quoted.char_indices();
+                    let mut end = quoted.len();
+                    while let Some((i, c)) = chars.next() {
+                        match c {
+                            '\\' => param.extend(chars.next().map(|(_, c)| c)),
+                            '"' => {
+                                end = i + 1;
+                                break;
+                            }
+                            c => param.push(c),
+                        }
+                    }
+                    (param, &quoted[end..])
+                } else {
+                    let end = after_eq.find(',').unwrap_or(after_eq.len());
+                    (after_eq[..end].trim().to_string(), &after_eq[end..])
+                };
+                if let Some((_, params)) = challenges.last_mut() {
+                    params.insert(token.to_ascii_lowercase(), param);
+                }
+                rest = remainder;
+            } else if !token.is_empty() {
+                challenges.push((token.to_string(), HashMap::new()));
+                rest = after;
+            } else {
+                rest = &rest[1..];
+            }
+            rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        }
+    }
+    challenges
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        sync::atomic::AtomicUsize,
+    };
+
+    use hyper::{Body, Response, StatusCode};
+
+    use super::*;
+    use crate::{HttpUrlStream, UrlStreamClient};
+
+    #[test]
+    fn parse_www_authenticate() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::WWW_AUTHENTICATE,
+            "Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", algorithm=SHA-256, nonce=\"7ypf\\\"x\", Basic realm=\"simple\"".parse().unwrap(),
+        );
+        headers.append(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+        let challenges = parse_challenges(&headers);
+        let schemes: Vec<&str> = challenges.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(schemes, ["Digest", "Basic", "Bearer"]);
+        assert_eq!(challenges[0].1["qop"], "auth, auth-int");
+        assert_eq!(challenges[0].1["algorithm"], "SHA-256");
+        assert_eq!(challenges[0].1["nonce"], "7ypf\"x");
+        assert_eq!(challenges[1].1["realm"], "simple");
+    }
+
+    /// Example of RFC 7616, 3.9.1.
+    #[test]
+    fn digest_rfc_7616_example() {
+        let challenge = |algorithm: &str| {
+            let params: HashMap<String, String> = [
+                ("realm", "http-auth@example.org"),
+                ("qop", "auth, auth-int"),
+                ("algorithm", algorithm),
+                ("nonce", "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v"),
+                ("opaque", "FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+            DigestChallenge::from_params(&params).unwrap()
+        };
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+        let md5 = challenge("MD5").authorization("Mufasa", "Circle of Life", "/dir/index.html", cnonce).unwrap();
+        assert!(md5.to_str().unwrap().contains("response=\"8ca523f5e9506fed4657c9700eebdbec\""));
+        assert!(md5.to_str().unwrap().contains("nc=00000001"));
+        let sha = challenge("SHA-256").authorization("Mufasa", "Circle of Life", "/dir/index.html", cnonce).unwrap();
+        assert!(sha.to_str().unwrap().contains("response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1\""));
+    }
+
+    /// Server checking Digest credentials `user`/`pass` with `algorithm`.
+    fn serve_digest(algorithm: Algorithm, unauthorized: Arc<AtomicUsize>) -> std::net::SocketAddr {
+        crate::test_server::serve(move |req| {
+            let unauthorized = unauthorized.clone();
+            async move {
+                let authorization = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()).unwrap_or("");
+                let mut headers = HeaderMap::new();
+                if let Ok(value) = authorization.parse() {
+                    headers.insert(header::WWW_AUTHENTICATE, value);
+                }
+                let params = parse_challenges(&headers).pop().map(|(_, params)| params).unwrap_or_default();
+                let ha1 = algorithm.hash("user:media:pass");
+                let ha2 = algorithm.hash(&format!("GET:{}", req.uri().path()));
+                let expected = params.get("nc").zip(params.get("cnonce")).map(|(nc, cnonce)| {
+                    algorithm.hash(&format!("{}:n0nce:{}:{}:auth:{}", ha1, nc, cnonce, ha2))
+                });
+                if expected.is_some() && params.get("response") == expected.as_ref() && params.get("opaque").is_some_and(|o| o == "op") {
+                    return Response::new(Body::from("#EXTM3U\n"));
+                }
+                unauthorized.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(
+                        header::WWW_AUTHENTICATE,
+                        format!("Basic realm=\"media\", Digest realm=\"media\", qop=\"auth\", algorithm={}, nonce=\"n0nce\", opaque=\"op\"", algorithm.name()),
+                    )
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        })
+    }
+
+    fn read(url: &str, client: &UrlStreamClient) -> Result<String, crate::Error> {
+        let mut body = String::new();
+        HttpUrlStream::open(&url::Url::parse(url)?, client)?.read_to_string(&mut body)?;
+        Ok(body)
+    }
+
+    #[test]
+    fn digest_challenge_response() {
+        for algorithm in [Algorithm::Md5, Algorithm::Sha256] {
+            let unauthorized = Arc::new(AtomicUsize::new(0));
+            let addr = serve_digest(algorithm, unauthorized.clone());
+            let client = UrlStreamClient::new();
+            assert_eq!(read(&format!("http://user:pass@{}/master.m3u8", addr), &client).unwrap(), "#EXTM3U\n");
+            assert_eq!(unauthorized.load(std::sync::atomic::Ordering::SeqCst), 1);
+            // the challenge is remembered for the origin
+            assert_eq!(read(&format!("http://user:pass@{}/seg1.ts", addr), &client).unwrap(), "#EXTM3U\n");
+            assert_eq!(unauthorized.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+            assert!(read(&format!("http://user:wrong@{}/master.m3u8", addr), &UrlStreamClient::new()).is_err());
+            assert!(read(&format!("http://{}/master.m3u8", addr), &UrlStreamClient::new()).is_err());
+        }
+    }
+
+    /// Server answering with the `Authorization` it got, redirecting
+    /// `/redirect?to=<url>` requests.
+    fn serve_echo() -> std::net::SocketAddr {
+        crate::test_server::serve(|req| async move {
+            if let Some(to) = req.uri().query().and_then(|q| q.strip_prefix("to=")) {
+                return Response::builder().status(StatusCode::FOUND).header(header::LOCATION, to).body(Body::empty()).unwrap();
+            }
+            let authorization = req.headers().get(header::AUTHORIZATION).map(|v| v.to_str().unwrap().to_string());
+            match authorization {
+                Some(authorization) => Response::new(Body::from(authorization)),
+                None => Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(header::WWW_AUTHENTICATE, "Basic realm=\"media\"")
+                    .body(Body::empty())
+                    .unwrap(),
+            }
+        })
+    }
+
+    #[test]
+    fn basic_and_bearer_stay_on_their_origin() {
+        let (origin, other) = (serve_echo(), serve_echo());
+        let client = UrlStreamClient::new();
+        assert_eq!(read(&format!("http://user:pass@{}/a", origin), &client).unwrap(), "Basic dXNlcjpwYXNz");
+        // same-origin redirect keeps the credentials
+        assert_eq!(read(&format!("http://user:pass@{0}/redirect?to=/b", origin), &client).unwrap(), "Basic dXNlcjpwYXNz");
+        // cross-origin redirect drops them
+        assert!(read(&format!("http://user:pass@{}/redirect?to=http://{}/c", origin, other), &client).is_err());
+
+        let bearer = UrlStreamClient::builder().bearer_token("t0ken").build();
+        assert_eq!(read(&format!("http://{}/a", origin), &bearer).unwrap(), "Bearer t0ken");
+        assert!(read(&format!("http://{}/redirect?to=http://{}/c", origin, other), &bearer).is_err());
+    }
+}
@@ -0,0 +1,547 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderName, HeaderValue},
+    Body, HeaderMap, Response, StatusCode,
+};
+use sha2::{Digest, Sha256};
+
+/// Heuristic freshness is capped to a day, as suggested by RFC 9111.
+const MAX_HEURISTIC_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// On-disk cache of HTTP responses.
+///
+/// Entries are keyed by the normalized URL and the request headers named in
+/// the response's `Vary`. Freshness follows `Cache-Control`, `Expires` and
+/// `Date`; stale entries with an `ETag` or `Last-Modified` are revalidated
+/// with a conditional request. The total size of stored bodies is bounded
+/// and the least recently used entries are evicted first.
+///
+/// Cloning is cheap and clones share the same storage.
+#[derive(Clone)]
+pub struct HttpCache {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for HttpCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpCache")
+            .field("dir", &self.inner.dir)
+            .field("max_size", &self.inner.max_size)
+            .finish()
+    }
+}
+
+struct Inner {
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<HashMap<String, IndexEntry>>,
+}
+
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    size: u64,
+    accessed: u64,
+}
+
+/// Stored response metadata, kept next to the body as `<key>.meta`.
+#[derive(Clone)]
+struct Meta {
+    url: String,
+    status: StatusCode,
+    stored: u64,
+    /// Last access, in milliseconds since the epoch.
+    accessed: u64,
+    size: u64,
+    vary: Vec<(String, String)>,
+    headers: HeaderMap,
+}
+
+pub(crate) struct Entry {
+    key: String,
+    meta: Meta,
+}
+
+pub(crate) enum Lookup {
+    Miss,
+    Fresh(Entry),
+    /// Present but must be revalidated before use.
+    Stale(Entry),
+}
+
+#[derive(Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+}
+
+impl HttpCache {
+    /// Opens (or creates) a cache in `dir` holding at most `max_size` bytes
+    /// of response bodies.
+    pub fn open<P: Into<PathBuf>>(dir: P, max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut index = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("meta") => {
+                    if let Some(meta) = read_meta(&path) {
+                        let key = path.file_stem().unwrap().to_string_lossy().into_owned();
+                        index.insert(key, IndexEntry { size: meta.size, accessed: meta.accessed });
+                    }
+                }
+                Some("tmp") => {
+                    let _ = fs::remove_file(&path);
+                }
+                _ => {}
+            }
+        }
+        Ok(HttpCache { inner: Arc::new(Inner { dir, max_size, index: Mutex::new(index) }) })
+    }
+
+    /// Total size in bytes of the stored bodies.
+    pub fn size(&self) -> u64 {
+        self.inner.index.lock().unwrap().values().map(|e| e.size).sum()
+    }
+
+    /// Removes every entry.
+    pub fn clear(&self) -> io::Result<()> {
+        let mut index = self.inner.index.lock().unwrap();
+        for (key, _) in index.drain() {
+            self.remove_files(&key);
+        }
+        for entry in fs::read_dir(&self.inner.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "vary") {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn lookup(&self, url: &url::Url, request: &HeaderMap) -> Lookup {
+        let url = normalize(url);
+        let key = self.variant_key(&url, request);
+        let meta = match read_meta(&self.path(&key, "meta")) {
+            Some(meta) if meta.url == url && self.path(&key, "body").exists() => meta,
+            _ => return Lookup::Miss,
+        };
+        if meta.vary.iter().any(|(name, value)| header_str(request, name) != *value) {
+            return Lookup::Miss;
+        }
+        let entry = Entry { key, meta };
+        if is_fresh(&entry.meta, now()) {
+            Lookup::Fresh(entry)
+        } else if entry.meta.headers.contains_key(header::ETAG) || entry.meta.headers.contains_key(header::LAST_MODIFIED) {
+            Lookup::Stale(entry)
+        } else {
+            Lookup::Miss
+        }
+    }
+
+    /// Builds a response serving `entry` from disk.
+    pub(crate) fn respond(&self, mut entry: Entry) -> io::Result<Response<Body>> {
+        let file = fs::File::open(self.path(&entry.key, "body"))?;
+        entry.meta.accessed = now_millis();
+        self.touch(&entry);
+
+        let chunks = futures::stream::unfold(file, |mut file| async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            match io::Read::read(&mut file, &mut buf) {
+                Ok(0) => None,
+                Ok(len) => {
+                    buf.truncate(len);
+                    Some((Ok::<_, io::Error>(Bytes::from(buf)), file))
+                }
+                Err(e) => Some((Err(e), file)),
+            }
+        });
+        let mut res = Response::new(Body::wrap_stream(chunks.fuse()));
+        *res.status_mut() = entry.meta.status;
+        *res.headers_mut() = entry.meta.headers;
+        Ok(res)
+    }
+
+    /// Adds validators of a stale `entry` to a conditional request.
+    pub(crate) fn conditional_headers(entry: &Entry, request: &mut HeaderMap) {
+        if let Some(etag) = entry.meta.headers.get(header::ETAG) {
+            request.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(modified) = entry.meta.headers.get(header::LAST_MODIFIED) {
+            request.insert(header::IF_MODIFIED_SINCE, modified.clone());
+        }
+    }
+
+    /// Merges the headers of a `304 Not Modified` into `entry`, which
+    /// becomes fresh again.
+    pub(crate) fn revalidated(&self, mut entry: Entry, not_modified: &HeaderMap) -> io::Result<Response<Body>> {
+        for (name, value) in not_modified {
+            if name != header::CONTENT_LENGTH && name != header::TRANSFER_ENCODING {
+                entry.meta.headers.insert(name.clone(), value.clone());
+            }
+        }
+        entry.meta.stored = now();
+        write_meta(&self.path(&entry.key, "meta"), &entry.meta)?;
+        self.respond(entry)
+    }
+
+    /// Passes `res` through, storing its body as it is read when the
+    /// response may be cached.
+    pub(crate) fn store(&self, url: &url::Url, request: &HeaderMap, res: Response<Body>) -> Response<Body> {
+        let control = CacheControl::parse(res.headers());
+        let vary: Vec<String> = res.headers().get_all(header::VARY).iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        let too_large = HttpBody::size_hint(res.body()).exact().is_some_and(|len| len > self.inner.max_size);
+        if res.status() != StatusCode::OK || control.no_store || too_large || vary.iter().any(|name| name == "*") {
+            return res;
+        }
+
+        let url = normalize(url);
+        if fs::write(self.path(&url_key(&url), "vary"), vary.join("\n")).is_err() {
+            return res;
+        }
+        let key = self.variant_key(&url, request);
+        let tmp = self.inner.dir.join(format!("{}.{:08x}.tmp", key, rand::random::<u32>()));
+        let file = match fs::File::create(&tmp) {
+            Ok(file) => file,
+            Err(_) => return res,
+        };
+        let meta = Meta {
+            vary: vary.iter().map(|name| (name.clone(), header_str(request, name))).collect(),
+            url,
+            status: res.status(),
+            stored: now(),
+            accessed: now_millis(),
+            size: 0,
+            headers: res.headers().clone(),
+        };
+        let (parts, body) = res.into_parts();
+        let tee = Tee { cache: self.clone(), body, file: Some(file), tmp, key, meta };
+        Response::from_parts(parts, Body::wrap_stream(tee))
+    }
+
+    fn variant_key(&self, url: &str, request: &HeaderMap) -> String {
+        let url_key = url_key(url);
+        let names = fs::read_to_string(self.path(&url_key, "vary")).unwrap_or_default();
+        let mut hasher = Sha256::new();
+        hasher.update(url.as_bytes());
+        for name in names.lines().filter(|n| !n.is_empty()) {
+            hasher.update(format!("\n{}: {}", name, header_str(request, name)).as_bytes());
+        }
+        hex(&hasher.finalize()[..16])
+    }
+
+    fn path(&self, key: &str, extension: &str) -> PathBuf {
+        self.inner.dir.join(format!("{}.{}", key, extension))
+    }
+
+    fn touch(&self, entry: &Entry) {
+        let _ = write_meta(&self.path(&entry.key, "meta"), &entry.meta);
+        if let Some(indexed) = self.inner.index.lock().unwrap().get_mut(&entry.key) {
+            indexed.accessed = entry.meta.accessed;
+        }
+    }
+
+    fn commit(&self, tmp: &Path, key: &str, meta: &Meta) -> io::Result<()> {
+        let mut index = self.inner.index.lock().unwrap();
+        fs::rename(tmp, self.path(key, "body"))?;
+        write_meta(&self.path(key, "meta"), meta)?;
+        index.insert(key.to_string(), IndexEntry { size: meta.size, accessed: meta.accessed });
+
+        let mut total: u64 = index.values().map(|e| e.size).sum();
+        if total > self.inner.max_size {
+            let mut entries: Vec<(String, IndexEntry)> = index.iter().map(|(k, e)| (k.clone(), *e)).collect();
+            entries.sort_by_key(|(_, e)| e.accessed);
+            for (victim, e) in entries {
+                if total <= self.inner.max_size {
+                    break;
+                }
+                index.remove(&victim);
+                self.remove_files(&victim);
+                total -= e.size;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_files(&self, key: &str) {
+        let _ = fs::remove_file(self.path(key, "meta"));
+        let _ = fs::remove_file(self.path(key, "body"));
+    }
+}
+
+/// Copies a response body to a temporary file while it streams and commits
+/// it as a cache entry once the body ends cleanly.
+struct Tee {
+    cache: HttpCache,
+    body: Body,
+    file: Option<fs::File>,
+    tmp: PathBuf,
+    key: String,
+    meta: Meta,
+}
+
+impl Stream for Tee {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let next = Pin::new(&mut this.body).poll_data(cx);
+        match &next {
+            Poll::Ready(Some(Ok(data))) => {
+                this.meta.size += data.len() as u64;
+                let written = match this.file.as_mut() {
+                    Some(file) => this.meta.size <= this.cache.inner.max_size && file.write_all(data).is_ok(),
+                    None => true,
+                };
+                if !written {
+                    this.abandon();
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.abandon(),
+            Poll::Ready(None) => {
+                if let Some(file) = this.file.take() {
+                    drop(file);
+                    if this.cache.commit(&this.tmp, &this.key, &this.meta).is_err() {
+                        let _ = fs::remove_file(&this.tmp);
+                    }
+                }
+            }
+            Poll::Pending => {}
+        }
+        next
+    }
+}
+
+impl Tee {
+    fn abandon(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+impl Drop for Tee {
+    fn drop(&mut self) {
+        self.abandon();
+    }
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut control = CacheControl::default();
+        for value in headers.get_all(header::CACHE_CONTROL).iter().filter_map(|v| v.to_str().ok()) {
+            for directive in value.split(',') {
+                let (name, arg) = match directive.split_once('=') {
+                    Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                    None => (directive.trim(), None),
+                };
+                match name.to_ascii_lowercase().as_str() {
+                    "no-store" => control.no_store = true,
+                    "no-cache" => control.no_cache = true,
+                    "must-revalidate" => control.must_revalidate = true,
+                    "max-age" => control.max_age = arg.and_then(|a| a.parse().ok()),
+                    _ => {}
+                }
+            }
+        }
+        control
+    }
+}
+
+fn is_fresh(meta: &Meta, now: u64) -> bool {
+    let control = CacheControl::parse(&meta.headers);
+    if control.no_cache {
+        return false;
+    }
+    let date = http_date(&meta.headers, header::DATE).unwrap_or(meta.stored);
+    let lifetime = match control.max_age {
+        Some(max_age) => max_age,
+        None => match http_date(&meta.headers, header::EXPIRES) {
+            Some(expires) => expires.saturating_sub(date),
+            None if meta.headers.contains_key(header::EXPIRES) => 0,
+            None if control.must_revalidate => 0,
+            None => match http_date(&meta.headers, header::LAST_MODIFIED) {
+                Some(modified) => (date.saturating_sub(modified) / 10).min(MAX_HEURISTIC_LIFETIME.as_secs()),
+                None => 0,
+            },
+        },
+    };
+    let age_at_store = meta.headers.get(header::AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    let age = age_at_store + now.saturating_sub(meta.stored);
+    age < lifetime
+}
+
+/// Normalizes `url` for use as a cache key: the fragment is dropped and
+/// query parameters are sorted.
+fn normalize(url: &url::Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        pairs.sort();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+fn url_key(url: &str) -> String {
+    hex(&Sha256::digest(url.as_bytes())[..16])
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn header_str(headers: &HeaderMap, name: &str) -> String {
+    let values: Vec<&str> = headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+    values.join(", ")
+}
+
+fn http_date(headers: &HeaderMap, name: HeaderName) -> Option<u64> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let time = httpdate::parse_http_date(value).ok()?;
+    Some(time.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+fn now() -> u64 {
+    now_millis() / 1000
+}
+
+/// Access times use milliseconds so LRU order holds for rapid accesses.
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn write_meta(path: &Path, meta: &Meta) -> io::Result<()> {
+    let mut text = format!(
+        "URL {}\nSTATUS {}\nSTORED {}\nACCESSED {}\nSIZE {}\n",
+        meta.url, meta.status.as_u16(), meta.stored, meta.accessed, meta.size
+    );
+    for (name, value) in &meta.vary {
+        text += &format!("VARY {}: {}\n", name, value);
+    }
+    for (name, value) in &meta.headers {
+        if let Ok(value) = value.to_str() {
+            text += &format!("HEADER {}: {}\n", name, value);
+        }
+    }
+    let tmp = path.with_extension("meta.tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(tmp, path)
+}
+
+fn read_meta(path: &Path) -> Option<Meta> {
+    let text = fs::read_to_string(path).ok()?;
+    let mut meta = Meta {
+        url: String::new(),
+        status: StatusCode::OK,
+        stored: 0,
+        accessed: 0,
+        size: 0,
+        vary: Vec::new(),
+        headers: HeaderMap::new(),
+    };
+    for line in text.lines() {
+        let (field, value) = line.split_once(' ')?;
+        match field {
+            "URL" => meta.url = value.to_string(),
+            "STATUS" => meta.status = StatusCode::from_u16(value.parse().ok()?).ok()?,
+            "STORED" => meta.stored = value.parse().ok()?,
+            "ACCESSED" => meta.accessed = value.parse().ok()?,
+            "SIZE" => meta.size = value.parse().ok()?,
+            "VARY" => {
+                let (name, value) = value.split_once(": ").unwrap_or((value.trim_end_matches(':'), ""));
+                meta.vary.push((name.to_string(), value.to_string()));
+            }
+            "HEADER" => {
+                let (name, value) = value.split_once(": ").unwrap_or((value.trim_end_matches(':'), ""));
+                meta.headers.append(HeaderName::from_bytes(name.as_bytes()).ok()?, HeaderValue::from_str(value).ok()?);
+            }
+            _ => {}
+        }
+    }
+    Some(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(headers: &[(&'static str, &str)], stored: u64) -> Meta {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        Meta { url: String::new(), status: StatusCode::OK, stored, accessed: stored, size: 0, vary: vec![], headers: map }
+    }
+
+    #[test]
+    fn freshness_rules() {
+        let stored = 1_700_000_000;
+        assert!(is_fresh(&meta(&[("cache-control", "max-age=60")], stored), stored + 59));
+        assert!(!is_fresh(&meta(&[("cache-control", "max-age=60")], stored), stored + 60));
+        assert!(!is_fresh(&meta(&[("cache-control", "max-age=60"), ("age", "50")], stored), stored + 10));
+        assert!(!is_fresh(&meta(&[("cache-control", "no-cache, max-age=60")], stored), stored));
+        assert!(is_fresh(
+            &meta(&[("date", "Tue, 14 Nov 2023 22:13:20 GMT"), ("expires", "Tue, 14 Nov 2023 22:23:20 GMT")], stored),
+            stored + 599
+        ));
+        assert!(!is_fresh(&meta(&[("expires", "0")], stored), stored));
+        // 10% of the 10 days since Last-Modified, capped to a day
+        assert!(is_fresh(
+            &meta(&[("date", "Tue, 14 Nov 2023 22:13:20 GMT"), ("last-modified", "Sat, 04 Nov 2023 22:13:20 GMT")], stored),
+            stored + 86_399
+        ));
+        assert!(!is_fresh(&meta(&[], stored), stored));
+    }
+
+    #[test]
+    fn normalized_keys() {
+        let a = url::Url::parse("HTTPS://Example.com:443/a/b.m3u8?b=2&a=1#frag").unwrap();
+        let b = url::Url::parse("https://example.com/a/b.m3u8?a=1&b=2").unwrap();
+        assert_eq!(normalize(&a), normalize(&b));
+        assert_eq!(normalize(&b), "https://example.com/a/b.m3u8?a=1&b=2");
+    }
+
+    #[test]
+    fn meta_round_trip() {
+        let dir = std::env::temp_dir().join(format!("url_stream_meta_{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut original = meta(&[("etag", "\"abc\""), ("set-cookie", "a=1"), ("set-cookie", "b=2")], 42);
+        original.url = "https://example.com/x".to_string();
+        original.vary = vec![("accept-encoding".to_string(), "gzip".to_string())];
+        original.size = 7;
+        write_meta(&dir.join("k.meta"), &original).unwrap();
+        let parsed = read_meta(&dir.join("k.meta")).unwrap();
+        assert_eq!(parsed.url, original.url);
+        assert_eq!(parsed.size, 7);
+        assert_eq!(parsed.vary, original.vary);
+        assert_eq!(parsed.headers, original.headers);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,580 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use hyper::{header::{HeaderName, HeaderValue}, Body, Client, HeaderMap, Version};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    auth::AuthCache,
+    cache::HttpCache,
+    connector::Connector,
+    cookie::CookieJar,
+    progress::{Progress, ProgressCallback},
+    proxy::ProxyConfig,
+    retry::RetryPolicy,
+    throttle::RateLimiter,
+    timeout::{Limits, Timeouts},
+    tls::TlsConfig,
+    transport::{FixtureTransport, Fixtures, Network, SharedTransport, Transport},
+};
+
+pub(crate) type HttpsClient = Client<Connector, Body>;
+
+static GLOBAL: OnceLock<UrlStreamClient> = OnceLock::new();
+
+/// HTTP versions a client speaks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/1.1 only.
+    Http1,
+    /// HTTP/2 with servers offering it through ALPN on `https`, HTTP/1.1
+    /// with the others and on plain `http`.
+    #[default]
+    Auto,
+    /// Like `Auto` on `https`, but plain `http` speaks HTTP/2 from the
+    /// first byte (h2c with prior knowledge), for local servers known to
+    /// support it. Connections through a forwarding proxy stay HTTP/1.1.
+    Http2PriorKnowledge,
+}
+
+impl HttpVersion {
+    /// Protocols offered in the TLS handshake, most preferred first.
+    fn alpn(self) -> &'static [&'static [u8]] {
+        match self {
+            HttpVersion::Http1 => &[b"http/1.1"],
+            HttpVersion::Auto | HttpVersion::Http2PriorKnowledge => &[b"h2", b"http/1.1"],
+        }
+    }
+}
+
+/// Shared configuration and connection pool used to open URLs.
+///
+/// Connections are kept alive and reused across streams opened through the
+/// same client, so fetching many resources from one host costs a single
+/// TLS handshake per pooled connection. Cloning is cheap and clones share
+/// the pool.
+///
+/// [`with_timeouts`](Self::with_timeouts),
+/// [`with_cancellation`](Self::with_cancellation),
+/// [`with_progress`](Self::with_progress) and
+/// [`with_rate_limit`](Self::with_rate_limit) derive a client for a single
+/// request or group of requests, still sharing the pool.
+#[derive(Clone)]
+pub struct UrlStreamClient {
+    inner: Arc<Inner>,
+    timeouts: Timeouts,
+    cancel: Option<CancellationToken>,
+    progress: Option<ProgressCallback>,
+    rate_limit: Option<RateLimiter>,
+}
+
+struct Inner {
+    transport: Arc<dyn Transport>,
+    default_headers: HeaderMap,
+    retry: RetryPolicy,
+    cache: Option<HttpCache>,
+    cookies: Option<CookieJar>,
+    bearer_token: Option<String>,
+    auth: AuthCache,
+    decode_content: bool,
+    max_connections_per_host: Option<usize>,
+    max_streams_per_host: Option<usize>,
+    rate_limit: Option<RateLimiter>,
+    proxy: Arc<ProxyConfig>,
+    hosts: Mutex<HashMap<String, Host>>,
+}
+
+/// Slots of streams to a host, and whether it speaks HTTP/2.
+#[derive(Default)]
+struct Host {
+    connections: Option<Arc<Semaphore>>,
+    streams: Option<Arc<Semaphore>>,
+    http2: Option<bool>,
+}
+
+/// Builder for [`UrlStreamClient`].
+#[derive(Debug)]
+pub struct UrlStreamClientBuilder {
+    default_headers: HeaderMap,
+    retry: RetryPolicy,
+    cache: Option<HttpCache>,
+    cookies: Option<CookieJar>,
+    bearer_token: Option<String>,
+    decode_content: bool,
+    max_connections_per_host: Option<usize>,
+    max_streams_per_host: Option<usize>,
+    http_version: HttpVersion,
+    max_idle_per_host: usize,
+    idle_timeout: Option<Duration>,
+    rate_limit: Option<RateLimiter>,
+    timeouts: Timeouts,
+    tls: TlsConfig,
+    proxy: Option<ProxyConfig>,
+    transport: Option<SharedTransport>,
+    fixtures: Option<Fixtures>,
+}
+
+impl Default for UrlStreamClientBuilder {
+    fn default() -> Self {
+        UrlStreamClientBuilder {
+            default_headers: HeaderMap::new(),
+            retry: RetryPolicy::default(),
+            cache: None,
+            cookies: None,
+            bearer_token: None,
+            decode_content: true,
+            max_connections_per_host: None,
+            max_streams_per_host: None,
+            http_version: HttpVersion::default(),
+            max_idle_per_host: usize::MAX,
+            idle_timeout: Some(Duration::from_secs(90)),
+            rate_limit: None,
+            timeouts: Timeouts::default(),
+            tls: TlsConfig::default(),
+            proxy: None,
+            transport: None,
+            fixtures: None,
+        }
+    }
+}
+
+impl UrlStreamClientBuilder {
+    /// Header sent with every request unless the request sets it itself.
+    pub fn default_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.default_headers.insert(name, value);
+        self
+    }
+
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Serves and stores responses through `cache`.
+    pub fn cache(mut self, cache: HttpCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Stores cookies set by responses in `jar` and sends them back with
+    /// later requests.
+    pub fn cookie_jar(mut self, jar: CookieJar) -> Self {
+        self.cookies = Some(jar);
+        self
+    }
+
+    /// Token sent as `Authorization: Bearer` to the origin of each opened
+    /// URL. Credentials in the URL itself take precedence, and redirects to
+    /// another origin go without either.
+    pub fn bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.bearer_token = Some(token.into());
+        self
+    }
+
+    /// Whether compressed bodies are decoded (the default). When disabled
+    /// the body is returned exactly as sent, e.g. for byte-exact mirroring,
+    /// and `content_encoding()` on the stream tells how it is encoded.
+    pub fn decode_content(mut self, decode: bool) -> Self {
+        self.decode_content = decode;
+        self
+    }
+
+    /// Caps the number of streams open at once to a single HTTP/1.1 host,
+    /// each of which takes a connection. Opening one more waits until
+    /// another is dropped.
+    pub fn max_connections_per_host(mut self, max: usize) -> Self {
+        self.max_connections_per_host = Some(max.max(1));
+        self
+    }
+
+    /// Caps the number of streams open at once to a single HTTP/2 host,
+    /// all multiplexed over one connection. The server's own limit applies
+    /// too: requests beyond it wait for a stream to close.
+    pub fn http2_max_concurrent_streams(mut self, max: usize) -> Self {
+        self.max_streams_per_host = Some(max.max(1));
+        self
+    }
+
+    /// HTTP versions to speak; by default HTTP/2 is negotiated through ALPN.
+    pub fn http_version(mut self, version: HttpVersion) -> Self {
+        self.http_version = version;
+        self
+    }
+
+    /// Caps the number of idle connections kept per host.
+    pub fn max_idle_per_host(mut self, max: usize) -> Self {
+        self.max_idle_per_host = max;
+        self
+    }
+
+    /// How long an idle connection is kept before being closed; `None`
+    /// keeps it until the server closes it.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Caps the combined bandwidth of all streams opened through the client.
+    pub fn rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(limiter);
+        self
+    }
+
+    /// Default timeouts of streams opened through the client.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Root certificates, client certificate and pins used for `https`.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Proxies to go through. Defaults to [`ProxyConfig::from_env`], read
+    /// when the client is built.
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Sends requests through `transport` instead of the network, e.g. to
+    /// serve canned responses in tests.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(SharedTransport(transport));
+        self
+    }
+
+    /// Replays responses from, or records them to, `fixtures`. Recording
+    /// goes through the network or the [`transport`](Self::transport) set.
+    pub fn fixtures(mut self, fixtures: Fixtures) -> Self {
+        self.fixtures = Some(fixtures);
+        self
+    }
+
+    pub fn build(self) -> UrlStreamClient {
+        let proxy = Arc::new(self.proxy.unwrap_or_else(ProxyConfig::from_env));
+        let connector = Connector::new(self.tls.client_config(self.http_version.alpn()), proxy.clone(), self.http_version);
+        let http = Client::builder()
+            .pool_idle_timeout(self.idle_timeout)
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .build(connector);
+        let network = self.transport.unwrap_or_else(|| SharedTransport(Arc::new(Network(http))));
+        let transport = match self.fixtures {
+            Some(fixtures) => Arc::new(FixtureTransport::new(fixtures, network)),
+            None => network.0,
+        };
+
+        UrlStreamClient {
+            inner: Arc::new(Inner {
+                transport,
+                default_headers: self.default_headers,
+                retry: self.retry,
+                cache: self.cache,
+                cookies: self.cookies,
+                bearer_token: self.bearer_token,
+                auth: AuthCache::default(),
+                decode_content: self.decode_content,
+                max_connections_per_host: self.max_connections_per_host,
+                max_streams_per_host: self.max_streams_per_host,
+                rate_limit: self.rate_limit,
+                proxy,
+                hosts: Mutex::new(HashMap::new()),
+            }),
+            timeouts: self.timeouts,
+            cancel: None,
+            progress: None,
+            rate_limit: None,
+        }
+    }
+}
+
+impl Default for UrlStreamClient {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl std::fmt::Debug for UrlStreamClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UrlStreamClient")
+            .field("default_headers", &self.inner.default_headers)
+            .field("retry", &self.inner.retry)
+            .field("cache", &self.inner.cache)
+            .field("cookies", &self.inner.cookies)
+            .field("bearer_token", &self.inner.bearer_token.as_ref().map(|_| "<hidden>"))
+            .field("decode_content", &self.inner.decode_content)
+            .field("max_connections_per_host", &self.inner.max_connections_per_host)
+            .field("http2_max_concurrent_streams", &self.inner.max_streams_per_host)
+            .field("rate_limit", &self.inner.rate_limit)
+            .field("proxy", &self.inner.proxy)
+            .field("timeouts", &self.timeouts)
+            .field("cancellable", &self.cancel.is_some())
+            .field("progress", &self.progress.is_some())
+            .field("stream_rate_limit", &self.rate_limit)
+            .finish()
+    }
+}
+
+impl UrlStreamClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builder() -> UrlStreamClientBuilder {
+        UrlStreamClientBuilder::default()
+    }
+
+    /// Process-wide client used by `UrlOpen::open`.
+    pub fn global() -> &'static UrlStreamClient {
+        GLOBAL.get_or_init(UrlStreamClient::default)
+    }
+
+    /// Client sharing this one's pool and configuration, applying
+    /// `timeouts` instead.
+    pub fn with_timeouts(&self, timeouts: Timeouts) -> UrlStreamClient {
+        UrlStreamClient { timeouts, ..self.clone() }
+    }
+
+    /// Client sharing this one's pool and configuration whose streams stop
+    /// once `token` is cancelled: opening fails and reads in flight return
+    /// an error.
+    pub fn with_cancellation(&self, token: CancellationToken) -> UrlStreamClient {
+        UrlStreamClient { cancel: Some(token), ..self.clone() }
+    }
+
+    /// Client sharing this one's pool and configuration whose streams call
+    /// `callback` with their [`Progress`] each time body data arrives.
+    ///
+    /// To watch progress from elsewhere, send it into a channel:
+    /// `client.with_progress(move |p| { let _ = tx.send(*p); })`.
+    pub fn with_progress(&self, callback: impl Fn(&Progress) + Send + Sync + 'static) -> UrlStreamClient {
+        UrlStreamClient { progress: Some(ProgressCallback::new(callback)), ..self.clone() }
+    }
+
+    /// Client sharing this one's pool and configuration whose streams are
+    /// also held to `limiter`, on top of any client-wide limit. Pass a new
+    /// limiter for each stream to cap them individually, or the same one to
+    /// cap a group.
+    pub fn with_rate_limit(&self, limiter: RateLimiter) -> UrlStreamClient {
+        UrlStreamClient { rate_limit: Some(limiter), ..self.clone() }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Starts the clock on a new stream.
+    pub(crate) fn limits(&self) -> Limits {
+        Limits::start(self.timeouts, self.cancel.clone())
+    }
+
+    pub(crate) fn progress(&self) -> Option<&ProgressCallback> {
+        self.progress.as_ref()
+    }
+
+    /// Limiters a stream opened now must keep within.
+    pub(crate) fn rate_limiters(&self) -> Vec<RateLimiter> {
+        self.inner.rate_limit.iter().chain(&self.rate_limit).cloned().collect()
+    }
+
+    pub(crate) fn transport(&self) -> &dyn Transport {
+        self.inner.transport.as_ref()
+    }
+
+    pub(crate) fn default_headers(&self) -> &HeaderMap {
+        &self.inner.default_headers
+    }
+
+    pub(crate) fn proxy(&self) -> &ProxyConfig {
+        &self.inner.proxy
+    }
+
+    pub(crate) fn retry_policy(&self) -> &RetryPolicy {
+        &self.inner.retry
+    }
+
+    pub(crate) fn cache(&self) -> Option<&HttpCache> {
+        self.inner.cache.as_ref()
+    }
+
+    pub(crate) fn cookie_jar(&self) -> Option<&CookieJar> {
+        self.inner.cookies.as_ref()
+    }
+
+    pub(crate) fn bearer_token(&self) -> Option<&str> {
+        self.inner.bearer_token.as_deref()
+    }
+
+    pub(crate) fn auth_cache(&self) -> &AuthCache {
+        &self.inner.auth
+    }
+
+    pub(crate) fn decodes_content(&self) -> bool {
+        self.inner.decode_content
+    }
+
+    /// Waits for a slot for a stream to the host of `url`, if streams per
+    /// host are limited. Until the host's HTTP version is known, a slot of
+    /// each kind is taken.
+    pub(crate) async fn acquire(&self, url: &url::Url) -> Vec<OwnedSemaphorePermit> {
+        let (connections, streams) = {
+            let mut hosts = self.inner.hosts.lock().unwrap();
+            let host = hosts.entry(host_key(url)).or_default();
+            let connections = host.connections
+                .get_or_insert_with(|| Arc::new(Semaphore::new(self.inner.max_connections_per_host.unwrap_or(Semaphore::MAX_PERMITS))))
+                .clone();
+            let streams = host.streams
+                .get_or_insert_with(|| Arc::new(Semaphore::new(self.inner.max_streams_per_host.unwrap_or(Semaphore::MAX_PERMITS))))
+                .clone();
+            match host.http2 {
+                _ if self.inner.max_connections_per_host.is_none() && self.inner.max_streams_per_host.is_none() => return Vec::new(),
+                Some(true) => (None, Some(streams)),
+                Some(false) => (Some(connections), None),
+                None => (Some(connections), Some(streams)),
+            }
+        };
+        let mut permits = Vec::new();
+        for semaphore in [streams, connections].into_iter().flatten() {
+            permits.extend(semaphore.acquire_owned().await.ok());
+        }
+        permits
+    }
+
+    /// Remembers the HTTP version the host of `url` answered with.
+    pub(crate) fn record_version(&self, url: &url::Url, version: Version) {
+        if let Some(host) = self.inner.hosts.lock().unwrap().get_mut(&host_key(url)) {
+            host.http2 = Some(version == Version::HTTP_2);
+        }
+    }
+}
+
+fn host_key(url: &url::Url) -> String {
+    format!("{}://{}:{}", url.scheme(), url.host_str().unwrap_or(""), url.port_or_known_default().unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, sync::{atomic::Ordering, mpsc}};
+
+    use hyper::{header, Response};
+
+    use super::*;
+    use crate::UrlOpen;
+
+    #[test]
+    fn connections_are_reused() {
+        let (addr, connections) = crate::test_server::serve_counting(|req| async move {
+            assert_eq!(req.headers()[header::USER_AGENT], "url_stream-test");
+            Response::new(Body::from("segment"))
+        });
+        let client = UrlStreamClient::builder()
+            .default_header(header::USER_AGENT, HeaderValue::from_static("url_stream-test"))
+            .build();
+        for i in 0..5 {
+            let url = url::Url::parse(&format!("http://{}/seg{}.ts", addr, i)).unwrap();
+            let mut body = String::new();
+            url.open_with(&client).unwrap().read_to_string(&mut body).unwrap();
+            assert_eq!(body, "segment");
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn connections_per_host_are_limited() {
+        let addr = crate::test_server::serve(|_| async { Response::new(Body::from("segment")) });
+        let client = UrlStreamClient::builder().max_connections_per_host(1).build();
+        let url = url::Url::parse(&format!("http://{}/seg.ts", addr)).unwrap();
+
+        let first = url.open_with(&client).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let (second_client, second_url) = (client.clone(), url.clone());
+        std::thread::spawn(move || {
+            let stream = second_url.open_with(&second_client);
+            tx.send(stream.is_ok()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(first);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    fn version_server() -> (std::net::SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+        crate::test_server::serve_counting(|req| async move { Response::new(Body::from(format!("{:?}", req.version()))) })
+    }
+
+    /// Opens `url`, returning the stream and the HTTP version the server saw.
+    fn open_version(url: &url::Url, client: &UrlStreamClient) -> (crate::HttpUrlStream, String) {
+        let mut stream = crate::HttpUrlStream::open(url, client).unwrap();
+        let mut seen = String::new();
+        stream.read_to_string(&mut seen).unwrap();
+        (stream, seen)
+    }
+
+    #[test]
+    fn http2_negotiated_through_alpn() {
+        let serve = |alpn: &[&[u8]]| {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let chain = vec![rustls::Certificate(cert.serialize_der().unwrap())];
+            let mut config = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(chain, rustls::PrivateKey(cert.serialize_private_key_der()))
+                .unwrap();
+            config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+            let addr = crate::test_server::serve_tls(config, |req| async move { Response::new(Body::from(format!("{:?}", req.version()))) });
+            let url = url::Url::parse(&format!("https://localhost:{}/seg.ts", addr.port())).unwrap();
+            (url, TlsConfig::new().add_root_pem(cert.serialize_pem().unwrap().as_bytes()).unwrap())
+        };
+
+        let (url, tls) = serve(&[b"h2", b"http/1.1"]);
+        let (stream, seen) = open_version(&url, &UrlStreamClient::builder().tls(tls.clone()).build());
+        assert_eq!((stream.version(), seen.as_str()), (Version::HTTP_2, "HTTP/2.0"));
+        let http1 = UrlStreamClient::builder().tls(tls).http_version(HttpVersion::Http1).build();
+        assert_eq!(open_version(&url, &http1).1, "HTTP/1.1");
+
+        // servers without h2 get HTTP/1.1
+        let (url, tls) = serve(&[b"http/1.1"]);
+        let (stream, seen) = open_version(&url, &UrlStreamClient::builder().tls(tls).build());
+        assert_eq!((stream.version(), seen.as_str()), (Version::HTTP_11, "HTTP/1.1"));
+    }
+
+    #[test]
+    fn h2c_with_prior_knowledge() {
+        let (addr, connections) = version_server();
+        let url = url::Url::parse(&format!("http://{}/seg.ts", addr)).unwrap();
+        assert_eq!(open_version(&url, &UrlStreamClient::new()).1, "HTTP/1.1");
+
+        let client = UrlStreamClient::builder().http_version(HttpVersion::Http2PriorKnowledge).build();
+        let streams: Vec<_> = (0..4).map(|_| open_version(&url, &client)).collect();
+        assert!(streams.iter().all(|(stream, seen)| stream.version() == Version::HTTP_2 && seen == "HTTP/2.0"));
+        // one more connection, multiplexing all four streams
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn http2_streams_per_host_are_limited() {
+        let (addr, _) = version_server();
+        let client = UrlStreamClient::builder()
+            .http_version(HttpVersion::Http2PriorKnowledge)
+            .http2_max_concurrent_streams(2)
+            .build();
+        let url = url::Url::parse(&format!("http://{}/seg.ts", addr)).unwrap();
+
+        let first = url.open_with(&client).unwrap();
+        let _second = url.open_with(&client).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let (third_client, third_url) = (client.clone(), url.clone());
+        std::thread::spawn(move || {
+            let stream = third_url.open_with(&third_client);
+            tx.send(stream.is_ok()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(first);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use hyper::{
+    client::{
+        connect::{Connected, Connection},
+        HttpConnector,
+    },
+    Uri,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, rustls, TlsConnector};
+use tower_service::Service;
+
+use crate::{
+    client::HttpVersion,
+    proxy::ProxyConfig,
+    timeout::{self, TimeoutError, Timeouts},
+    Error,
+};
+
+/// Opens plain TCP connections for `http` and TLS connections for `https`
+/// URIs, possibly through a proxy, bounding each step by the timeouts of
+/// the request being sent. Connections are marked for HTTP/2 when TLS
+/// negotiated `h2`, or for h2c when the client speaks HTTP/2 with prior
+/// knowledge.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    http: HttpConnector,
+    tls: Arc<rustls::ClientConfig>,
+    proxy: Arc<ProxyConfig>,
+    h2c: bool,
+}
+
+/// Connection handed to hyper.
+pub(crate) enum MaybeTlsStream {
+    Plain(TcpStream),
+    /// Plain connection speaking HTTP/2 from the start.
+    H2c(TcpStream),
+    /// Connection to an HTTP proxy forwarding plain `http` requests.
+    Forwarded(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connector {
+    pub(crate) fn new(tls: rustls::ClientConfig, proxy: Arc<ProxyConfig>, version: HttpVersion) -> Self {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        Connector { http, tls: Arc::new(tls), proxy, h2c: version == HttpVersion::Http2PriorKnowledge }
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = MaybeTlsStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<MaybeTlsStream, Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.http.poll_ready(cx).map_err(Error::from)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        // Read here rather than in the returned future: hyper may finish the
+        // connection in a background task, outside the request's scope.
+        let timeouts = Timeouts::current().unwrap_or_default();
+        let mut http = self.http.clone();
+        let tls = self.tls.clone();
+        let proxy = self.proxy.for_url(uri.scheme_str(), uri.host().unwrap_or_default()).cloned();
+        let h2c = self.h2c;
+        Box::pin(async move {
+            let https = uri.scheme_str() == Some("https");
+            let host = uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+            let forwarded = !https && proxy.as_ref().is_some_and(|proxy| proxy.forwards_http());
+            let tcp = timeout::within(timeouts.connect, TimeoutError::Connect, async {
+                let proxy = match &proxy {
+                    Some(proxy) => proxy,
+                    None => return http.call(uri.clone()).await.map_err(Error::from),
+                };
+                let (proxy_host, proxy_port) = proxy.address();
+                let authority = match proxy_host.parse::<std::net::IpAddr>() {
+                    Ok(std::net::IpAddr::V6(_)) => format!("[{}]:{}", proxy_host, proxy_port),
+                    _ => format!("{}:{}", proxy_host, proxy_port),
+                };
+                let proxy_uri = Uri::builder().scheme("http").authority(authority).path_and_query("/").build()?;
+                let mut tcp = http.call(proxy_uri).await?;
+                if !forwarded {
+                    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+                    proxy.tunnel(&mut tcp, host, port).await?;
+                }
+                Ok(tcp)
+            })
+            .await?;
+            if forwarded {
+                return Ok(MaybeTlsStream::Forwarded(tcp));
+            }
+            if !https {
+                return Ok(if h2c { MaybeTlsStream::H2c(tcp) } else { MaybeTlsStream::Plain(tcp) });
+            }
+            let server_name = rustls::ServerName::try_from(host)
+                .map_err(|_| Error::from(format!("invalid server name {}", host)))?;
+            let tls = timeout::within(timeouts.tls_handshake, TimeoutError::TlsHandshake, async {
+                TlsConnector::from(tls).connect(server_name, tcp).await.map_err(Error::from)
+            })
+            .await?;
+            Ok(MaybeTlsStream::Tls(Box::new(tls)))
+        })
+    }
+}
+
+impl Connection for MaybeTlsStream {
+    fn connected(&self) -> Connected {
+        match self {
+            MaybeTlsStream::Plain(tcp) => tcp.connected(),
+            MaybeTlsStream::H2c(tcp) => tcp.connected().negotiated_h2(),
+            MaybeTlsStream::Forwarded(tcp) => tcp.connected().proxy(true),
+            MaybeTlsStream::Tls(tls) => {
+                let (tcp, session) = tls.get_ref();
+                match session.alpn_protocol() {
+                    Some(b"h2") => tcp.connected().negotiated_h2(),
+                    _ => tcp.connected(),
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) | MaybeTlsStream::H2c(tcp) | MaybeTlsStream::Forwarded(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) | MaybeTlsStream::H2c(tcp) | MaybeTlsStream::Forwarded(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) | MaybeTlsStream::H2c(tcp) | MaybeTlsStream::Forwarded(tcp) => Pin::new(tcp).poll_flush(cx),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) | MaybeTlsStream::H2c(tcp) | MaybeTlsStream::Forwarded(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{header::{self, HeaderValue}, HeaderMap};
+
+use crate::Error;
+
+/// Cookie store shared by the streams of a client (RFC 6265).
+///
+/// `Set-Cookie` headers of every response are stored, and matching cookies
+/// are sent back in the `Cookie` header of later requests. Cloning is cheap
+/// and clones share the cookies.
+#[derive(Clone, Default)]
+pub struct CookieJar {
+    cookies: Arc<Mutex<Vec<Cookie>>>,
+}
+
+/// Cookie held by a [`CookieJar`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    /// Sent to `domain` only, not to its subdomains.
+    host_only: bool,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    /// `None` for a session cookie.
+    expires: Option<SystemTime>,
+    same_site: Option<SameSite>,
+    created: SystemTime,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Debug for CookieJar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieJar").field("len", &self.len()).finish()
+    }
+}
+
+impl Cookie {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn is_http_only(&self) -> bool {
+        self.http_only
+    }
+
+    /// Expiry time; `None` for a session cookie.
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+    }
+
+    pub fn same_site(&self) -> Option<SameSite> {
+        self.same_site
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Parses a `Set-Cookie` value received from `url` (RFC 6265, 5.2 and
+    /// 5.3). Returns `None` if the cookie must be ignored.
+    fn parse(set_cookie: &str, url: &url::Url, now: SystemTime) -> Option<Cookie> {
+        let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let mut parts = set_cookie.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.is_empty() {
+            return None;
+        }
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(url.path()),
+            secure: false,
+            http_only: false,
+            expires: None,
+            same_site: None,
+            created: now,
+        };
+        let mut max_age = None;
+        for attribute in parts {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "expires" => {
+                    if let Some(expires) = parse_cookie_date(value) {
+                        cookie.expires = Some(expires);
+                    }
+                }
+                "max-age" => {
+                    if let Ok(seconds) = value.parse::<i64>() {
+                        max_age = Some(seconds);
+                    }
+                }
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    if !domain_match(&host, &domain) || (domain != host && !domain.contains('.')) {
+                        return None;
+                    }
+                    cookie.host_only = false;
+                    cookie.domain = domain;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "samesite" => {
+                    cookie.same_site = match value.to_ascii_lowercase().as_str() {
+                        "strict" => Some(SameSite::Strict),
+                        "lax" => Some(SameSite::Lax),
+                        "none" => Some(SameSite::None),
+                        _ => None,
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Max-Age wins over Expires
+        if let Some(seconds) = max_age {
+            cookie.expires = Some(match u64::try_from(seconds) {
+                Ok(seconds) if seconds > 0 => now + Duration::from_secs(seconds),
+                _ => UNIX_EPOCH,
+            });
+        }
+        if cookie.secure && url.scheme() != "https" {
+            return None;
+        }
+        Some(cookie)
+    }
+
+    fn matches(&self, url: &url::Url, host: &str) -> bool {
+        let host_matches = if self.host_only { host == self.domain } else { domain_match(host, &self.domain) };
+        host_matches && path_match(url.path(), &self.path) && (!self.secure || url.scheme() == "https")
+    }
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of cookies held, expired ones included until next used.
+    pub fn len(&self) -> usize {
+        self.cookies.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+    }
+
+    /// Snapshot of the cookies that haven't expired.
+    pub fn cookies(&self) -> Vec<Cookie> {
+        let now = SystemTime::now();
+        self.cookies.lock().unwrap().iter().filter(|c| !c.is_expired(now)).cloned().collect()
+    }
+
+    /// Stores a `Set-Cookie` value as if received from `url`. Returns
+    /// whether it was accepted.
+    pub fn set_cookie(&self, url: &url::Url, set_cookie: &str) -> bool {
+        let now = SystemTime::now();
+        let cookie = match Cookie::parse(set_cookie, url, now) {
+            Some(cookie) => cookie,
+            None => return false,
+        };
+        let mut cookies = self.cookies.lock().unwrap();
+        let existing = cookies.iter().position(|c| c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path);
+        let created = existing.map(|i| cookies.remove(i).created);
+        if !cookie.is_expired(now) {
+            cookies.push(Cookie { created: created.unwrap_or(cookie.created), ..cookie });
+        }
+        true
+    }
+
+    /// Value of the `Cookie` header for a request to `url`, if any cookie
+    /// applies (RFC 6265, 5.4).
+    pub fn cookie_header(&self, url: &url::Url) -> Option<String> {
+        let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|c| !c.is_expired(now));
+        let mut matching: Vec<&Cookie> = cookies.iter().filter(|c| c.matches(url, &host)).collect();
+        if matching.is_empty() {
+            return None;
+        }
+        matching.sort_by(|a, b| b.path.len().cmp(&a.path.len()).then(a.created.cmp(&b.created)));
+        Some(matching.iter().map(|c| format!("{}={}", c.name, c.value)).collect::<Vec<_>>().join("; "))
+    }
+
+    /// Stores the `Set-Cookie` headers of a response from `url`.
+    pub(crate) fn store_response(&self, url: &url::Url, headers: &HeaderMap) {
+        for value in headers.get_all(header::SET_COOKIE) {
+            if let Ok(value) = value.to_str() {
+                self.set_cookie(url, value);
+            }
+        }
+    }
+
+    /// Sets the `Cookie` header of a request to `url`, unless the request
+    /// carries one already.
+    pub(crate) fn add_request_header(&self, url: &url::Url, headers: &mut HeaderMap) {
+        if headers.contains_key(header::COOKIE) {
+            return;
+        }
+        if let Some(value) = self.cookie_header(url).and_then(|v| HeaderValue::from_str(&v).ok()) {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+
+    /// Adds the cookies of a Netscape `cookies.txt` file, as written by
+    /// curl and browsers' export tools. Expired cookies are skipped.
+    pub fn load_netscape<R: BufRead>(&self, reader: R) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let mut loaded = Vec::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                Some(rest) => (rest, true),
+                None => (line.as_str(), false),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 7 {
+                return Err(Error::from(format!("malformed cookies.txt line {}", number + 1)));
+            }
+            let expires: u64 = fields[4].trim().parse().map_err(|_| format!("malformed expiry on cookies.txt line {}", number + 1))?;
+            let cookie = Cookie {
+                name: fields[5].to_string(),
+                value: fields[6].trim_end_matches('\r').to_string(),
+                domain: fields[0].trim_start_matches('.').to_ascii_lowercase(),
+                host_only: !fields[1].eq_ignore_ascii_case("TRUE"),
+                path: fields[2].to_string(),
+                secure: fields[3].eq_ignore_ascii_case("TRUE"),
+                http_only,
+                expires: (expires != 0).then(|| UNIX_EPOCH + Duration::from_secs(expires)),
+                same_site: None,
+                created: now,
+            };
+            if !cookie.is_expired(now) {
+                loaded.push(cookie);
+            }
+        }
+        let mut cookies = self.cookies.lock().unwrap();
+        for cookie in loaded {
+            cookies.retain(|c| !(c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path));
+            cookies.push(cookie);
+        }
+        Ok(())
+    }
+
+    /// Writes the cookies in Netscape `cookies.txt` format. Session cookies
+    /// get an expiry of 0.
+    pub fn save_netscape<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "# Netscape HTTP Cookie File")?;
+        for cookie in self.cookies() {
+            let expires = cookie.expires.and_then(|e| e.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+            writeln!(
+                writer,
+                "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                if cookie.http_only { "#HttpOnly_" } else { "" },
+                if cookie.host_only { "" } else { "." },
+                cookie.domain,
+                if cookie.host_only { "FALSE" } else { "TRUE" },
+                cookie.path,
+                if cookie.secure { "TRUE" } else { "FALSE" },
+                expires,
+                cookie.name,
+                cookie.value,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses an `Expires` value with the cookie-date algorithm of RFC 6265,
+/// 5.1.1, which accepts the date formats found in the wild.
+fn parse_cookie_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+    let is_delimiter = |c: char| matches!(c, '\t' | ' '..='/' | ';'..='@' | '['..='`' | '{'..='~');
+    let (mut time, mut day, mut month, mut year) = (None, None, None, None);
+    for token in value.split(is_delimiter).filter(|token| !token.is_empty()) {
+        if time.is_none() {
+            if let Some(hms) = time_token(token) {
+                time = Some(hms);
+                continue;
+            }
+        }
+        if day.is_none() {
+            if let Some((n, _)) = leading_digits(token, 1, 2) {
+                day = Some(n);
+                continue;
+            }
+        }
+        if month.is_none() {
+            let prefix = token.get(..3).map(str::to_ascii_lowercase);
+            if let Some(i) = MONTHS.iter().position(|name| prefix.as_deref() == Some(*name)) {
+                month = Some(i as u32 + 1);
+                continue;
+            }
+        }
+        if year.is_none() {
+            if let Some((n, _)) = leading_digits(token, 2, 4) {
+                year = Some(n);
+            }
+        }
+    }
+    let ((hour, minute, second), day, month) = (time?, day?, month?);
+    let year = match year? {
+        year @ 70..=99 => year + 1900,
+        year @ 0..=69 => year + 2000,
+        year => year,
+    };
+    if !(1..=31).contains(&day) || year < 1601 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if day > month_days[month as usize - 1] {
+        return None;
+    }
+    // days since 1970-01-01 of the proleptic Gregorian calendar
+    let (y, m) = if month <= 2 { (year as i64 - 1, month as i64 + 9) } else { (year as i64, month as i64 - 3) };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let seconds = days * 86_400 + (hour * 3600 + minute * 60 + second) as i64;
+    match u64::try_from(seconds) {
+        Ok(seconds) => UNIX_EPOCH.checked_add(Duration::from_secs(seconds)),
+        Err(_) => UNIX_EPOCH.checked_sub(Duration::from_secs(seconds.unsigned_abs())),
+    }
+}
+
+/// Value of the `min` to `max` digits `token` starts with, and what follows
+/// them, which must not start with a digit.
+fn leading_digits(token: &str, min: usize, max: usize) -> Option<(u32, &str)> {
+    let len = token.bytes().take_while(u8::is_ascii_digit).count();
+    if len < min || len > max {
+        return None;
+    }
+    Some((token[..len].parse().ok()?, &token[len..]))
+}
+
+/// `hh:mm:ss` at the start of a cookie-date token, fields of one or two digits.
+fn time_token(token: &str) -> Option<(u32, u32, u32)> {
+    let (hour, rest) = leading_digits(token, 1, 2)?;
+    let (minute, rest) = leading_digits(rest.strip_prefix(':')?, 1, 2)?;
+    let (second, _) = leading_digits(rest.strip_prefix(':')?, 1, 2)?;
+    Some((hour, minute, second))
+}
+
+/// RFC 6265, 5.1.3.
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.parse::<IpAddr>().is_err()
+            && host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.')))
+}
+
+/// RFC 6265, 5.1.4.
+fn path_match(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || (request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/')))
+}
+
+/// Directory of the request path, the default cookie path (RFC 6265, 5.1.4).
+fn default_path(request_path: &str) -> String {
+    match request_path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => request_path[..i].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> url::Url {
+        url::Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn domain_and_path_matching() {
+        let jar = CookieJar::new();
+        let master = url("https://cdn.example.com/live/stream/master.m3u8");
+        assert!(jar.set_cookie(&master, "session=abc; Domain=.example.com; Path=/live; Secure; HttpOnly; SameSite=Lax"));
+        assert!(jar.set_cookie(&master, "variant=720p"));
+        assert!(!jar.set_cookie(&master, "evil=1; Domain=other.com"));
+        assert!(!jar.set_cookie(&master, "tld=1; Domain=com"));
+        assert!(!jar.set_cookie(&master, "novalue"));
+
+        assert_eq!(jar.cookie_header(&url("https://edge.example.com/live/seg1.ts")).as_deref(), Some("session=abc"));
+        assert_eq!(
+            jar.cookie_header(&url("https://cdn.example.com/live/stream/seg1.ts")).as_deref(),
+            Some("variant=720p; session=abc")
+        );
+        assert_eq!(jar.cookie_header(&url("https://cdn.example.com/livestream/x")), None);
+        // Secure cookies stay off plain http
+        assert_eq!(jar.cookie_header(&url("http://cdn.example.com/live/seg1.ts")), None);
+        assert_eq!(jar.cookie_header(&url("https://example.org/live/")), None);
+
+        let session = jar.cookies().into_iter().find(|c| c.name() == "session").unwrap();
+        assert!(session.is_secure() && session.is_http_only());
+        assert_eq!(session.same_site(), Some(SameSite::Lax));
+        assert_eq!((session.domain(), session.path()), ("example.com", "/live"));
+    }
+
+    #[test]
+    fn expiry_and_replacement() {
+        let jar = CookieJar::new();
+        let u = url("http://example.com/");
+        jar.set_cookie(&u, "a=1; Max-Age=60");
+        jar.set_cookie(&u, "b=2; Expires=Wed, 21 Oct 2015 07:28:00 GMT");
+        jar.set_cookie(&u, "c=3; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Max-Age=60");
+        assert_eq!(jar.cookie_header(&u).as_deref(), Some("a=1; c=3"));
+
+        jar.set_cookie(&u, "a=updated");
+        assert_eq!(jar.cookie_header(&u).as_deref(), Some("a=updated; c=3"));
+        jar.set_cookie(&u, "c=gone; Max-Age=0");
+        assert_eq!(jar.cookie_header(&u).as_deref(), Some("a=updated"));
+        assert!(!jar.set_cookie(&u, "s=1; Secure"));
+    }
+
+    #[test]
+    fn cookie_dates() {
+        let expected = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").ok();
+        assert_eq!(parse_cookie_date("Wed, 21 Oct 2015 07:28:00 GMT"), expected);
+        assert_eq!(parse_cookie_date("Wed, 21-Oct-2015 07:28:00 GMT"), expected);
+        assert_eq!(parse_cookie_date("Wednesday, 21-Oct-15 07:28:00 GMT"), expected);
+        assert_eq!(parse_cookie_date("Wed Oct 21 7:28:00 2015"), expected);
+        assert_eq!(parse_cookie_date("21 October 2015 07:28:00"), expected);
+        assert_eq!(parse_cookie_date("Thu, 01-Jan-70 00:00:01 GMT"), Some(UNIX_EPOCH + Duration::from_secs(1)));
+        assert_eq!(parse_cookie_date("Sat, 01-Jan-00 00:00:00 GMT"), Some(UNIX_EPOCH + Duration::from_secs(946_684_800)));
+        assert_eq!(parse_cookie_date("Mon, 01 Jan 1601 00:00:00 GMT"), UNIX_EPOCH.checked_sub(Duration::from_secs(11_644_473_600)));
+
+        assert_eq!(parse_cookie_date("Wed, 21 Oct 1600 07:28:00 GMT"), None);
+        assert_eq!(parse_cookie_date("Sat, 30 Feb 2016 07:28:00 GMT"), None);
+        assert_eq!(parse_cookie_date("Wed, 21 Oct 2015 24:28:00 GMT"), None);
+        assert_eq!(parse_cookie_date("Wed, 21 Oct 2015"), None);
+        assert_eq!(parse_cookie_date("tomorrow"), None);
+
+        let jar = CookieJar::new();
+        let u = url("http://example.com/");
+        jar.set_cookie(&u, "old=1; Expires=Wed, 21-Oct-15 07:28:00 GMT");
+        jar.set_cookie(&u, "new=1; Expires=Fri, 31-Dec-99 23:59:59 GMT");
+        jar.set_cookie(&u, "later=1; Expires=Fri, 31-Dec-2100 23:59:59 GMT");
+        assert_eq!(jar.cookie_header(&u).as_deref(), Some("later=1"));
+    }
+
+    #[test]
+    fn netscape_round_trip() {
+        let text = "# Netscape HTTP Cookie File\n\
+                    .example.com\tTRUE\t/\tFALSE\t4102444800\ttoken\txyz\n\
+                    #HttpOnly_cdn.example.com\tFALSE\t/live\tTRUE\t0\tsession\tabc\n\
+                    old.example.com\tFALSE\t/\tFALSE\t1000\texpired\t1\n";
+        let jar = CookieJar::new();
+        jar.load_netscape(text.as_bytes()).unwrap();
+        assert_eq!(jar.len(), 2);
+        assert_eq!(jar.cookie_header(&url("https://cdn.example.com/live/a.ts")).as_deref(), Some("session=abc; token=xyz"));
+        assert_eq!(jar.cookie_header(&url("https://www.example.com/")).as_deref(), Some("token=xyz"));
+
+        let mut saved = Vec::new();
+        jar.save_netscape(&mut saved).unwrap();
+        let reloaded = CookieJar::new();
+        reloaded.load_netscape(&saved[..]).unwrap();
+        let mut before = jar.cookies();
+        let mut after = reloaded.cookies();
+        for cookie in before.iter_mut().chain(after.iter_mut()) {
+            cookie.created = UNIX_EPOCH;
+        }
+        assert_eq!(before, after);
+
+        assert!(jar.load_netscape("example.com\tTRUE\t/\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn cookies_flow_between_requests() {
+        use std::io::Read;
+
+        use hyper::{Body, Response};
+
+        let addr = crate::test_server::serve(|req| async move {
+            match req.uri().path() {
+                "/master.m3u8" => Response::builder()
+                    .header(header::SET_COOKIE, "session=s1; Path=/")
+                    .header(header::SET_COOKIE, "hint=x; Path=/other")
+                    .body(Body::from("#EXTM3U\n"))
+                    .unwrap(),
+                _ => {
+                    let cookie = req.headers().get(header::COOKIE).map(|v| v.to_str().unwrap().to_string());
+                    Response::new(Body::from(cookie.unwrap_or_default()))
+                }
+            }
+        });
+        let jar = CookieJar::new();
+        let client = crate::UrlStreamClient::builder().cookie_jar(jar.clone()).build();
+        let read = |path: &str| {
+            let mut body = String::new();
+            crate::HttpUrlStream::open(&url(&format!("http://{}{}", addr, path)), &client).unwrap().read_to_string(&mut body).unwrap();
+            body
+        };
+        assert_eq!(read("/seg0.ts"), "");
+        read("/master.m3u8");
+        assert_eq!(read("/seg1.ts"), "session=s1");
+        assert_eq!(jar.len(), 2);
+    }
+}
@@ -0,0 +1,226 @@
+use std::io::{self, Write};
+
+use bytes::Bytes;
+use flate2::write::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use hyper::{header, HeaderMap};
+
+use crate::Error;
+
+/// Value advertised in `Accept-Encoding`.
+pub(crate) const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+/// Content coding applied by the server to a response body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Brotli => "br",
+        }
+    }
+
+    /// Parses `Content-Encoding` into the codings in the order they were
+    /// applied. `identity` is dropped.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Vec<Self>, Error> {
+        let mut encodings = Vec::new();
+        for value in headers.get_all(header::CONTENT_ENCODING) {
+            for coding in value.to_str()?.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+                encodings.push(match coding.to_ascii_lowercase().as_str() {
+                    "gzip" | "x-gzip" => Self::Gzip,
+                    "deflate" => Self::Deflate,
+                    "br" => Self::Brotli,
+                    "identity" => continue,
+                    _ => return Err(Error::from(format!("unsupported content encoding {}", coding))),
+                });
+            }
+        }
+        Ok(encodings)
+    }
+}
+
+/// Incremental decoder fed with body chunks as they arrive.
+pub(crate) struct Decoder {
+    stages: Vec<Stage>,
+}
+
+enum Stage {
+    Gzip(GzDecoder<Vec<u8>>),
+    /// `deflate` is meant to be zlib-wrapped, but some servers send raw
+    /// deflate data; which one is decided once the first two bytes are in.
+    Deflate(Vec<u8>),
+    Zlib(ZlibDecoder<Vec<u8>>),
+    RawDeflate(DeflateDecoder<Vec<u8>>),
+    Brotli(Box<brotli_decompressor::DecompressorWriter<Vec<u8>>>),
+}
+
+impl Decoder {
+    /// Decoder undoing `encodings`, given in the order they were applied.
+    pub(crate) fn new(encodings: &[ContentEncoding]) -> Self {
+        let stages = encodings.iter().rev().map(|encoding| match encoding {
+            ContentEncoding::Gzip => Stage::Gzip(GzDecoder::new(Vec::new())),
+            ContentEncoding::Deflate => Stage::Deflate(Vec::new()),
+            ContentEncoding::Brotli => Stage::Brotli(Box::new(brotli_decompressor::DecompressorWriter::new(Vec::new(), 16 * 1024))),
+        }).collect();
+        Decoder { stages }
+    }
+
+    /// Decodes `input`, returning whatever output is available so far.
+    pub(crate) fn decode(&mut self, input: &[u8]) -> io::Result<Bytes> {
+        let mut data = input.to_vec();
+        for stage in &mut self.stages {
+            data = stage.write(&data)?;
+        }
+        Ok(Bytes::from(data))
+    }
+
+    /// Flushes the remaining output once the body has ended. Later calls
+    /// return nothing.
+    pub(crate) fn finish(&mut self) -> io::Result<Bytes> {
+        let mut data = Vec::new();
+        for stage in &mut std::mem::take(&mut self.stages) {
+            data = stage.write(&data)?;
+            data.extend(stage.finish()?);
+        }
+        Ok(Bytes::from(data))
+    }
+}
+
+impl Stage {
+    fn write(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Stage::Deflate(pending) => {
+                pending.extend_from_slice(input);
+                if pending.len() < 2 {
+                    return Ok(Vec::new());
+                }
+                let pending = std::mem::take(pending);
+                *self = if is_zlib_header(pending[0], pending[1]) {
+                    Stage::Zlib(ZlibDecoder::new(Vec::new()))
+                } else {
+                    Stage::RawDeflate(DeflateDecoder::new(Vec::new()))
+                };
+                self.write(&pending)
+            }
+            Stage::Gzip(decoder) => {
+                decoder.write_all(input)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Stage::Zlib(decoder) => {
+                decoder.write_all(input)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Stage::RawDeflate(decoder) => {
+                decoder.write_all(input)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Stage::Brotli(decoder) => {
+                decoder.write_all(input)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        match self {
+            Stage::Deflate(pending) if pending.is_empty() => Ok(Vec::new()),
+            Stage::Deflate(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated deflate stream")),
+            Stage::Gzip(decoder) => {
+                decoder.try_finish()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Stage::Zlib(decoder) => {
+                decoder.try_finish()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Stage::RawDeflate(decoder) => {
+                decoder.try_finish()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Stage::Brotli(decoder) => {
+                decoder.close()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+        }
+    }
+}
+
+/// RFC 1950: deflate compression method with a valid header checksum.
+fn is_zlib_header(cmf: u8, flg: u8) -> bool {
+    cmf & 0x0f == 8 && (u16::from(cmf) << 8 | u16::from(flg)) % 31 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::{DeflateEncoder, GzEncoder, ZlibEncoder}, Compression};
+
+    use super::*;
+
+    fn decode_in_chunks(encodings: &[ContentEncoding], encoded: &[u8]) -> Vec<u8> {
+        let mut decoder = Decoder::new(encodings);
+        let mut out = Vec::new();
+        for chunk in encoded.chunks(7) {
+            out.extend(decoder.decode(chunk).unwrap());
+        }
+        out.extend(decoder.finish().unwrap());
+        out
+    }
+
+    fn text() -> Vec<u8> {
+        "#EXTINF:6.0,\nsegment.ts\n".repeat(200).into_bytes()
+    }
+
+    #[test]
+    fn decode_each_coding() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&text()).unwrap();
+        assert_eq!(decode_in_chunks(&[ContentEncoding::Gzip], &gz.finish().unwrap()), text());
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&text()).unwrap();
+        assert_eq!(decode_in_chunks(&[ContentEncoding::Deflate], &zlib.finish().unwrap()), text());
+
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(&text()).unwrap();
+        assert_eq!(decode_in_chunks(&[ContentEncoding::Deflate], &raw.finish().unwrap()), text());
+
+        let mut br = Vec::new();
+        brotli::BrotliCompress(&mut &text()[..], &mut br, &Default::default()).unwrap();
+        assert_eq!(decode_in_chunks(&[ContentEncoding::Brotli], &br), text());
+    }
+
+    #[test]
+    fn decode_stacked_codings() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&text()).unwrap();
+        let mut br = Vec::new();
+        brotli::BrotliCompress(&mut &gz.finish().unwrap()[..], &mut br, &Default::default()).unwrap();
+        assert_eq!(decode_in_chunks(&[ContentEncoding::Gzip, ContentEncoding::Brotli], &br), text());
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&text()).unwrap();
+        let encoded = gz.finish().unwrap();
+        let mut decoder = Decoder::new(&[ContentEncoding::Gzip]);
+        decoder.decode(&encoded[..encoded.len() / 2]).unwrap();
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn parse_content_encoding() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::CONTENT_ENCODING, "gzip, identity".parse().unwrap());
+        headers.append(header::CONTENT_ENCODING, "br".parse().unwrap());
+        assert_eq!(ContentEncoding::from_headers(&headers).unwrap(), vec![ContentEncoding::Gzip, ContentEncoding::Brotli]);
+        headers.insert(header::CONTENT_ENCODING, "compress".parse().unwrap());
+        assert!(ContentEncoding::from_headers(&headers).is_err());
+    }
+}
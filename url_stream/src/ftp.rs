@@ -0,0 +1,729 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use percent_encoding::percent_decode_str;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::{
+    auth,
+    client::UrlStreamClient,
+    progress::ProgressTracker,
+    runtime,
+    throttle::Throttle,
+    timeout::{self, BodyTimers, Limits, TimeoutError},
+    AsyncUrlStream, Error, UrlStream,
+};
+
+/// Size of the chunks yielded by the `Stream` view of [`FtpStream`].
+const CHUNK: usize = 16 * 1024;
+
+/// File or directory listing transferred over FTP (RFC 959), read or
+/// written asynchronously.
+///
+/// The URL userinfo is used to log in, `anonymous` otherwise. Transfers
+/// are binary and go over a passive data connection, negotiated with
+/// `EPSV` (RFC 2428) and falling back to `PASV`. A URL whose path ends in
+/// `/` opens the `LIST` output of that directory.
+///
+/// Reads honor the timeouts, cancellation token, rate limits and progress
+/// callback of the client, like [`HttpStream`](crate::HttpStream).
+pub struct FtpStream {
+    url: url::Url,
+    control: Control,
+    data: Option<TcpStream>,
+    transfer: Transfer,
+    path: String,
+    /// Whether the server still owes the reply closing the transfer.
+    reply_pending: bool,
+    position: u64,
+    length: Option<u64>,
+    timers: BodyTimers,
+    throttle: Throttle,
+    progress: Option<ProgressTracker>,
+    limits: Limits,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transfer {
+    Retrieve,
+    List,
+    Store,
+}
+
+/// Control connection of an FTP session.
+struct Control {
+    stream: BufReader<TcpStream>,
+}
+
+struct Reply {
+    code: u16,
+    text: String,
+}
+
+/// Blocking view of [`FtpStream`] driven by the shared runtime.
+pub struct FtpUrlStream {
+    inner: FtpStream,
+}
+
+impl Control {
+    /// Connects to the server of `url` and logs in.
+    async fn login(url: &url::Url, limits: &Limits) -> Result<Self, Error> {
+        let host = url.host_str().ok_or_else(|| Error::from(format!("{} has no host", url)))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = url.port().unwrap_or(21);
+        let tcp = timeout::within(limits.timeouts().connect, TimeoutError::Connect, async {
+            Ok(TcpStream::connect((host, port)).await?)
+        })
+        .await?;
+        let mut control = Control { stream: BufReader::new(tcp) };
+        control.reply().await?.expect(|code| code == 220, "connect")?;
+
+        let (user, password) = match url.username() {
+            "" => ("anonymous".to_string(), "anonymous@".to_string()),
+            user => (decode(user), decode(url.password().unwrap_or(""))),
+        };
+        check_argument(&user)?;
+        check_argument(&password)?;
+        let reply = control.command(&format!("USER {}", user)).await?;
+        if reply.code == 331 {
+            // the password is left out of errors
+            control.command(&format!("PASS {}", password)).await?.expect(|code| code == 230 || code == 202, "PASS")?;
+        } else {
+            reply.expect(|code| code == 230, "USER")?;
+        }
+        control.command("TYPE I").await?.expect(|code| code == 200, "TYPE I")?;
+        Ok(control)
+    }
+
+    async fn command(&mut self, command: &str) -> Result<Reply, Error> {
+        self.stream.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await?;
+        self.reply().await
+    }
+
+    /// Reads a reply, joining the lines of a multi-line one.
+    async fn reply(&mut self) -> Result<Reply, Error> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(Error::from("ftp control connection closed"));
+        }
+        let code = line.get(..3).and_then(|code| code.parse().ok())
+            .ok_or_else(|| Error::from(format!("malformed ftp reply {:?}", line.trim_end())))?;
+        let mut text = line[3..].trim_end().to_string();
+        if text.starts_with('-') {
+            let last = format!("{} ", code);
+            loop {
+                line.clear();
+                if self.stream.read_line(&mut line).await? == 0 {
+                    return Err(Error::from("ftp control connection closed"));
+                }
+                text.push('\n');
+                text.push_str(line.trim_end());
+                if line.starts_with(&last) {
+                    break;
+                }
+            }
+        }
+        Ok(Reply { code, text: text.trim_start_matches(['-', ' ']).to_string() })
+    }
+
+    /// Opens a passive data connection.
+    async fn passive(&mut self, limits: &Limits) -> Result<TcpStream, Error> {
+        // the data connection goes to the control peer, whatever address
+        // PASV names: it may be private, or someone else's
+        let peer = self.stream.get_ref().peer_addr()?;
+        let reply = self.command("EPSV").await?;
+        let port = if reply.code == 229 {
+            epsv_port(&reply.text)
+        } else {
+            let reply = self.command("PASV").await?.expect(|code| code == 227, "PASV")?;
+            pasv_port(&reply.text)
+        };
+        let port = port.ok_or_else(|| Error::from("malformed passive mode reply"))?;
+        timeout::within(limits.timeouts().connect, TimeoutError::Connect, async {
+            Ok(TcpStream::connect(SocketAddr::new(peer.ip(), port)).await?)
+        })
+        .await
+    }
+}
+
+impl Reply {
+    fn expect(self, ok: impl Fn(u16) -> bool, command: &str) -> Result<Self, Error> {
+        if ok(self.code) {
+            Ok(self)
+        } else {
+            Err(Error::from(format!("ftp {} failed: {} {}", command, self.code, self.text)))
+        }
+    }
+}
+
+impl FtpStream {
+    /// Opens `url` through `client` to read the file, or the listing of the
+    /// directory, it names.
+    pub async fn open(url: &url::Url, client: &UrlStreamClient) -> Result<Self, Error> {
+        let (mut stream, limits) = Self::connect(url, client).await?;
+        let transfer = if stream.path.is_empty() || url.path().ends_with('/') { Transfer::List } else { Transfer::Retrieve };
+        stream.transfer = transfer;
+        limits
+            .guard(async {
+                if transfer == Transfer::Retrieve {
+                    let reply = stream.control.command(&format!("SIZE {}", stream.path)).await?;
+                    if reply.code == 213 {
+                        stream.length = reply.text.trim().parse().ok();
+                    }
+                }
+                stream.start(0).await
+            })
+            .await?;
+        stream.progress = client.progress().map(|callback| ProgressTracker::new(callback.clone(), stream.length));
+        Ok(stream)
+    }
+
+    /// Opens `url` through `client` to upload a file, replacing any file
+    /// of the same name. Call [`finish`](Self::finish) once written.
+    pub async fn create(url: &url::Url, client: &UrlStreamClient) -> Result<Self, Error> {
+        let (mut stream, limits) = Self::connect(url, client).await?;
+        stream.transfer = Transfer::Store;
+        limits.guard(stream.start(0)).await?;
+        Ok(stream)
+    }
+
+    async fn connect(url: &url::Url, client: &UrlStreamClient) -> Result<(Self, Limits), Error> {
+        let path = decode(url.path().trim_start_matches('/'));
+        check_argument(&path)?;
+        let limits = client.limits();
+        let control = limits.guard(Control::login(url, &limits)).await?;
+        let stream = FtpStream {
+            url: auth::without_credentials(url),
+            control,
+            data: None,
+            transfer: Transfer::Retrieve,
+            path,
+            reply_pending: false,
+            position: 0,
+            length: None,
+            timers: limits.timers(),
+            throttle: Throttle::new(client.rate_limiters()),
+            progress: None,
+            limits: limits.clone(),
+        };
+        Ok((stream, limits))
+    }
+
+    /// Opens the data connection and starts the transfer at `offset`.
+    async fn start(&mut self, offset: u64) -> Result<(), Error> {
+        let data = self.control.passive(&self.limits).await?;
+        if offset > 0 {
+            self.control.command(&format!("REST {}", offset)).await?.expect(|code| code == 350, "REST")?;
+        }
+        let command = match self.transfer {
+            Transfer::Retrieve => format!("RETR {}", self.path),
+            Transfer::Store => format!("STOR {}", self.path),
+            Transfer::List if self.path.is_empty() => "LIST".to_string(),
+            Transfer::List => format!("LIST {}", self.path),
+        };
+        let reply = self.control.command(&command).await?;
+        reply.expect(|code| code == 125 || code == 150, &command)?;
+        self.data = Some(data);
+        self.reply_pending = true;
+        self.position = offset;
+        Ok(())
+    }
+
+    /// Closes the data connection and reads the reply ending the transfer.
+    async fn end_transfer(&mut self) -> Result<Reply, Error> {
+        if let Some(mut data) = self.data.take() {
+            data.shutdown().await.ok();
+        }
+        if !self.reply_pending {
+            return Ok(Reply { code: 226, text: String::new() });
+        }
+        self.reply_pending = false;
+        self.control.reply().await
+    }
+
+    /// Moves the read position of a file to `offset`, restarting the
+    /// transfer there with `REST`.
+    pub async fn seek_to(&mut self, offset: u64) -> io::Result<u64> {
+        if self.transfer != Transfer::Retrieve {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("can't seek in {}", self.url)));
+        }
+        if offset == self.position && self.data.is_some() {
+            return Ok(offset);
+        }
+        let limits = self.limits.clone();
+        limits
+            .guard(async {
+                // an aborted transfer ends with 426 or 451 as well as 226
+                self.end_transfer().await?;
+                self.start(offset).await
+            })
+            .await
+            .map_err(timeout::into_io)?;
+        Ok(offset)
+    }
+
+    /// Completes an upload, or ends a download early, and reports whether
+    /// the server confirmed the transfer.
+    pub async fn finish(&mut self) -> Result<(), Error> {
+        let limits = self.limits.clone();
+        let transfer = self.transfer;
+        let read_all = self.data.is_none();
+        limits
+            .guard(async {
+                let reply = self.end_transfer().await?;
+                if transfer == Transfer::Store || read_all {
+                    reply.expect(|code| code == 226 || code == 250, "transfer")?;
+                }
+                let _ = self.control.command("QUIT").await;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Total size of the file, if the server supports `SIZE`.
+    pub fn len(&self) -> Option<u64> {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == Some(0)
+    }
+
+    /// Offset of the next byte to be read or written.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl AsyncUrlStream for FtpStream {}
+
+impl AsyncRead for FtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.transfer == Transfer::Store {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::Unsupported, "ftp upload stream is write-only")));
+        }
+        // an empty read of the data connection would look like its end
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(e) = this.timers.poll_expired(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if this.throttle.poll_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let data = match this.data.as_mut() {
+            Some(data) => data,
+            None => return Poll::Ready(Ok(())),
+        };
+        let filled = buf.filled().len();
+        match Pin::new(data).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                this.timers.data_received();
+                let len = buf.filled().len() - filled;
+                if len == 0 {
+                    this.data = None;
+                    if this.transfer == Transfer::Retrieve && this.length.is_some_and(|length| this.position < length) {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("transfer of {} ended early", this.url),
+                        )));
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                this.position += len as u64;
+                this.throttle.received(len);
+                if let Some(progress) = this.progress.as_mut() {
+                    progress.received(len);
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => match this.timers.poll_idle(cx) {
+                Some(e) => Poll::Ready(Err(e)),
+                None => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl AsyncWrite for FtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(e) = this.timers.poll_expired(cx) {
+            return Poll::Ready(Err(e));
+        }
+        let data = match (this.transfer, this.data.as_mut()) {
+            (Transfer::Store, Some(data)) => data,
+            (Transfer::Store, None) => return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "ftp upload already finished"))),
+            _ => return Poll::Ready(Err(io::Error::new(io::ErrorKind::Unsupported, "ftp download stream is read-only"))),
+        };
+        let written = std::task::ready!(Pin::new(data).poll_write(cx, buf))?;
+        this.position += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut().data.as_mut() {
+            Some(data) => Pin::new(data).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut().data.as_mut() {
+            Some(data) => Pin::new(data).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Stream for FtpStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut chunk = vec![0; CHUNK];
+        let mut buf = ReadBuf::new(&mut chunk);
+        match std::task::ready!(self.poll_read(cx, &mut buf)) {
+            Ok(()) if buf.filled().is_empty() => Poll::Ready(None),
+            Ok(()) => {
+                let len = buf.filled().len();
+                chunk.truncate(len);
+                Poll::Ready(Some(Ok(Bytes::from(chunk))))
+            }
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+impl FtpUrlStream {
+    /// Blocking counterpart of [`FtpStream::open`].
+    pub fn open(url: &url::Url, client: &UrlStreamClient) -> Result<Self, Error> {
+        Ok(FtpUrlStream { inner: runtime::block_on(FtpStream::open(url, client))? })
+    }
+
+    /// Blocking counterpart of [`FtpStream::create`].
+    pub fn create(url: &url::Url, client: &UrlStreamClient) -> Result<Self, Error> {
+        Ok(FtpUrlStream { inner: runtime::block_on(FtpStream::create(url, client))? })
+    }
+
+    /// Blocking counterpart of [`FtpStream::finish`].
+    pub fn finish(mut self) -> Result<(), Error> {
+        runtime::block_on(self.inner.finish())
+    }
+
+    pub fn len(&self) -> Option<u64> {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl UrlStream for FtpUrlStream {}
+
+impl Read for FtpUrlStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        runtime::block_on(self.inner.read(buf))
+    }
+}
+
+impl Write for FtpUrlStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        runtime::block_on(self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        runtime::block_on(self.inner.flush())
+    }
+}
+
+impl Seek for FtpUrlStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.inner.position().checked_add_signed(delta),
+            SeekFrom::End(delta) => match self.inner.len() {
+                Some(len) => len.checked_add_signed(delta),
+                None => return Err(io::Error::new(io::ErrorKind::Unsupported, "length of the file is unknown")),
+            },
+        };
+        let target = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        runtime::block_on(self.inner.seek_to(target))
+    }
+}
+
+fn decode(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().into_owned()
+}
+
+/// Rejects line breaks, which would smuggle extra commands.
+fn check_argument(argument: &str) -> Result<(), Error> {
+    if argument.contains(['\r', '\n']) {
+        return Err(Error::from("ftp arguments must not contain line breaks"));
+    }
+    Ok(())
+}
+
+/// Port of an `EPSV` reply, `229 Entering Extended Passive Mode (|||6446|)`.
+fn epsv_port(text: &str) -> Option<u16> {
+    let inner = &text[text.find('(')? + 1..];
+    let delimiter = inner.chars().next()?;
+    inner.split(delimiter).nth(3)?.parse().ok()
+}
+
+/// Port of a `PASV` reply, `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)`.
+fn pasv_port(text: &str) -> Option<u16> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let numbers: Vec<u16> = text[start..]
+        .split(|c: char| !c.is_ascii_digit())
+        .take(6)
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    match numbers[..] {
+        [_, _, _, _, high, low] if high < 256 && low < 256 => Some(high << 8 | low),
+        _ => None,
+    }
+}
+
+pub async fn open_async(url: &url::Url, client: &UrlStreamClient) -> Result<FtpStream, Error> {
+    FtpStream::open(url, client).await
+}
+
+pub fn open(url: &url::Url, client: &UrlStreamClient) -> Result<FtpUrlStream, Error> {
+    FtpUrlStream::open(url, client)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// In-process FTP server over `files`, logging the commands it gets.
+    /// Accepts `login`, or anonymous users when `None`.
+    fn serve_ftp(files: Files, login: Option<(&'static str, &'static str)>, epsv: bool) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let runtime = runtime::get();
+        let listener = runtime.block_on(TcpListener::bind(("127.0.0.1", 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let commands = log.clone();
+        runtime.spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let (files, commands) = (files.clone(), commands.clone());
+                tokio::spawn(async move {
+                    let _ = session(tcp, files, login, epsv, commands).await;
+                });
+            }
+        });
+        (addr, log)
+    }
+
+    async fn session(tcp: TcpStream, files: Files, login: Option<(&str, &str)>, epsv: bool, log: Arc<Mutex<Vec<String>>>) -> io::Result<()> {
+        let mut control = BufReader::new(tcp);
+        control.get_mut().write_all(b"220 stand-in ready\r\n").await?;
+        let (mut user, mut logged_in, mut rest) = (String::new(), false, 0usize);
+        let mut passive: Option<TcpListener> = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if control.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end().to_string();
+            log.lock().unwrap().push(line.clone());
+            let (command, argument) = line.split_once(' ').unwrap_or((&line, ""));
+            let reply = match command {
+                "USER" => {
+                    user = argument.to_string();
+                    "331 password please".to_string()
+                }
+                "PASS" => {
+                    logged_in = match login {
+                        Some((name, password)) => user == name && argument == password,
+                        None => user == "anonymous",
+                    };
+                    if logged_in { "230 logged in" } else { "530 login incorrect" }.to_string()
+                }
+                "QUIT" => {
+                    control.get_mut().write_all(b"221 bye\r\n").await?;
+                    return Ok(());
+                }
+                _ if !logged_in => "530 not logged in".to_string(),
+                "TYPE" => "200 type set".to_string(),
+                "SIZE" => match files.lock().unwrap().get(argument) {
+                    Some(file) => format!("213 {}", file.len()),
+                    None => "550 no such file".to_string(),
+                },
+                "EPSV" if epsv => {
+                    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+                    let port = listener.local_addr()?.port();
+                    passive = Some(listener);
+                    format!("229 Entering Extended Passive Mode (|||{}|)", port)
+                }
+                "PASV" => {
+                    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+                    let port = listener.local_addr()?.port();
+                    passive = Some(listener);
+                    // a bogus address, which clients must not trust
+                    format!("227 Entering Passive Mode (10,9,8,7,{},{})", port >> 8, port & 0xff)
+                }
+                "REST" => {
+                    rest = argument.parse().unwrap_or(0);
+                    format!("350 restarting at {}", rest)
+                }
+                "RETR" | "STOR" | "LIST" => {
+                    let listener = match passive.take() {
+                        Some(listener) => listener,
+                        None => {
+                            control.get_mut().write_all(b"425 use PASV first\r\n").await?;
+                            continue;
+                        }
+                    };
+                    let file = files.lock().unwrap().get(argument).cloned();
+                    if command == "RETR" && file.is_none() {
+                        control.get_mut().write_all(b"550 no such file\r\n").await?;
+                        continue;
+                    }
+                    control.get_mut().write_all(b"150 opening data connection\r\n").await?;
+                    let (mut data, _) = listener.accept().await?;
+                    let done = match command {
+                        "RETR" => {
+                            let file = file.unwrap();
+                            data.write_all(&file[rest.min(file.len())..]).await.is_ok()
+                        }
+                        "STOR" => {
+                            let mut body = Vec::new();
+                            data.read_to_end(&mut body).await?;
+                            files.lock().unwrap().insert(argument.to_string(), body);
+                            true
+                        }
+                        _ => {
+                            let prefix = argument.trim_end_matches('/');
+                            let listing: String = files.lock().unwrap().iter()
+                                .filter_map(|(path, file)| {
+                                    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+                                    (dir == prefix).then(|| format!("-rw-r--r-- 1 ftp ftp {} Jan 01 00:00 {}\r\n", file.len(), name))
+                                })
+                                .collect();
+                            data.write_all(listing.as_bytes()).await.is_ok()
+                        }
+                    };
+                    drop(data);
+                    rest = 0;
+                    if done { "226 transfer complete" } else { "426 transfer aborted" }.to_string()
+                }
+                _ => "502 not implemented".to_string(),
+            };
+            control.get_mut().write_all(format!("{}\r\n", reply).as_bytes()).await?;
+        }
+    }
+
+    fn files(entries: &[(&str, Vec<u8>)]) -> Files {
+        Arc::new(Mutex::new(entries.iter().map(|(path, file)| (path.to_string(), file.clone())).collect()))
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn passive_replies() {
+        assert_eq!(epsv_port("Entering Extended Passive Mode (|||6446|)"), Some(6446));
+        assert_eq!(epsv_port("Entering Extended Passive Mode (!!!6446!)"), Some(6446));
+        assert_eq!(pasv_port("Entering Passive Mode (192,168,1,2,25,46)."), Some(6446));
+        assert_eq!(pasv_port("Entering Passive Mode 192,168,1,2,25,46"), Some(6446));
+        assert_eq!(pasv_port("Entering Passive Mode (192,168,1,2,256,46)"), None);
+    }
+
+    #[test]
+    fn anonymous_download() {
+        let (addr, log) = serve_ftp(files(&[("media/seg1.ts", pattern(100_000))]), None, true);
+        let url = url::Url::parse(&format!("ftp://{}/media/seg1.ts", addr)).unwrap();
+        let mut stream = open(&url, UrlStreamClient::global()).unwrap();
+        assert_eq!(stream.len(), Some(100_000));
+        assert_eq!(stream.read(&mut []).unwrap(), 0);
+        let mut body = Vec::new();
+        stream.read_to_end(&mut body).unwrap();
+        assert_eq!(body, pattern(100_000));
+        stream.finish().unwrap();
+
+        let log = log.lock().unwrap();
+        assert_eq!(log[..3], ["USER anonymous", "PASS anonymous@", "TYPE I"]);
+        assert!(log.contains(&"EPSV".to_string()));
+        assert!(!log.contains(&"PASV".to_string()));
+    }
+
+    #[test]
+    fn userinfo_login_with_pasv_fallback() {
+        let (addr, log) = serve_ftp(files(&[("seg.ts", b"segment".to_vec())]), Some(("user", "p@ss")), false);
+        let url = url::Url::parse(&format!("ftp://user:p%40ss@{}/seg.ts", addr)).unwrap();
+        let mut body = String::new();
+        crate::UrlOpen::open_with(&url, UrlStreamClient::global()).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "segment");
+        assert!(log.lock().unwrap().contains(&"PASV".to_string()));
+
+        let wrong = url::Url::parse(&format!("ftp://user:wrong@{}/seg.ts", addr)).unwrap();
+        let err = open(&wrong, UrlStreamClient::global()).err().unwrap();
+        assert!(!err.to_string().contains("wrong"));
+        let missing = url::Url::parse(&format!("ftp://user:p%40ss@{}/missing.ts", addr)).unwrap();
+        assert!(open(&missing, UrlStreamClient::global()).is_err());
+    }
+
+    #[test]
+    fn seek_restarts_transfer() {
+        let (addr, log) = serve_ftp(files(&[("seg.ts", pattern(100_000))]), None, true);
+        let url = url::Url::parse(&format!("ftp://{}/seg.ts", addr)).unwrap();
+        let mut stream = open(&url, UrlStreamClient::global()).unwrap();
+        let mut head = [0u8; 10];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(stream.seek(SeekFrom::Start(50_000)).unwrap(), 50_000);
+        let mut tail = Vec::new();
+        stream.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, pattern(100_000)[50_000..]);
+        assert_eq!(stream.seek(SeekFrom::End(-10)).unwrap(), 99_990);
+        tail.clear();
+        stream.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, pattern(100_000)[99_990..]);
+        let log = log.lock().unwrap();
+        assert!(log.contains(&"REST 50000".to_string()));
+        assert!(log.contains(&"REST 99990".to_string()));
+    }
+
+    #[test]
+    fn upload_and_list() {
+        let files = files(&[("up/old.ts", b"old".to_vec())]);
+        let (addr, _) = serve_ftp(files.clone(), None, true);
+        let url = url::Url::parse(&format!("ftp://{}/up/new.ts", addr)).unwrap();
+        let mut upload = FtpUrlStream::create(&url, UrlStreamClient::global()).unwrap();
+        upload.write_all(&pattern(70_000)).unwrap();
+        assert!(upload.read(&mut [0u8; 4]).is_err());
+        upload.finish().unwrap();
+        assert_eq!(files.lock().unwrap()["up/new.ts"], pattern(70_000));
+
+        let dir = url::Url::parse(&format!("ftp://{}/up/", addr)).unwrap();
+        let mut listing = String::new();
+        open(&dir, UrlStreamClient::global()).unwrap().read_to_string(&mut listing).unwrap();
+        let mut names: Vec<&str> = listing.lines().filter_map(|line| line.split_whitespace().last()).collect();
+        names.sort();
+        assert_eq!(names, ["new.ts", "old.ts"]);
+    }
+}
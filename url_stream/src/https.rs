@@ -1,42 +1,1315 @@
-use std::{fmt::Write, io::{Read, BufWriter}};
+use std::{
+    fmt,
+    future::Future,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use hyper::{Client, client::conn};
-use url;
-use crate::UrlStream;
-extern crate hyper_rustls;
+use bytes::{Buf, Bytes};
+use futures::Stream;
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderValue},
+    Body, HeaderMap, Request, Response, StatusCode, Version,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    sync::OwnedSemaphorePermit,
+};
+
+use crate::{
+    auth::{self, Auth},
+    cache::{HttpCache, Lookup},
+    client::UrlStreamClient,
+    decode::{self, ContentEncoding, Decoder},
+    progress::ProgressTracker,
+    retry, runtime,
+    throttle::Throttle,
+    timeout::{self, BodyTimers, Limits},
+    AsyncUrlStream, Error, UrlStream,
+};
 extern crate hyper;
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send>>;
+
+/// Forward seeks shorter than this are served by discarding body bytes
+/// instead of issuing a new range request.
+const SKIP_THRESHOLD: u64 = 64 * 1024;
+/// Size of the read-ahead buffer of [`HttpUrlStream`].
+const READ_AHEAD: usize = 64 * 1024;
+/// Redirects followed before giving up on opening a URL.
+const MAX_REDIRECTS: usize = 10;
+
+/// Response body of an HTTP(S) request, read asynchronously.
+///
+/// When the transfer breaks mid-body, the stream reconnects according to
+/// its [`RetryPolicy`](crate::RetryPolicy) and continues from the last
+/// received byte.
+///
+/// Bodies sent with a `Content-Encoding` are decoded on the fly unless
+/// disabled on the client. Since ranges address the encoded bytes, a
+/// decoded stream has no known length and can only seek forward or back to
+/// the start.
+///
+/// Reads honor the [`Timeouts`](crate::Timeouts) and cancellation token of
+/// the client the stream was opened with: an elapsed timeout fails with
+/// `io::ErrorKind::TimedOut` carrying a [`TimeoutError`](crate::TimeoutError),
+/// cancellation with a [`Cancelled`](crate::Cancelled) error. Its
+/// [`RateLimiter`](crate::RateLimiter)s pause reads that get ahead of the
+/// allowed bandwidth, and a progress callback hears of each received chunk.
+///
+/// Credentials in the URL are sent as Basic or Digest authorization,
+/// whichever the server asks for with a 401, and afterwards up front to
+/// the same origin. Redirects are followed, without credentials once they
+/// lead to another origin.
+pub struct HttpStream {
+    url: url::Url,
+    client: UrlStreamClient,
+    request_headers: HeaderMap,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Body,
+    chunk: Bytes,
+    position: u64,
+    length: Option<u64>,
+    accept_ranges: bool,
+    validator: Option<Validator>,
+    resume: Option<ResponseFuture>,
+    from_cache: bool,
+    encodings: Vec<ContentEncoding>,
+    decoder: Option<Decoder>,
+    limits: Limits,
+    timers: BodyTimers,
+    auth: Option<Auth>,
+    throttle: Throttle,
+    progress: Option<ProgressTracker>,
+    version: Version,
+    /// Offset the body stops at, for streams opened on a range.
+    end: Option<u64>,
+    /// Slots held while the stream is open, when the client limits streams
+    /// per host.
+    _permits: Vec<OwnedSemaphorePermit>,
+}
 
-struct HttpUrlStream {
+/// Identifies the version of the resource a stream was opened on, so that
+/// later range requests can't splice bytes from a different version.
+#[derive(Clone)]
+enum Validator {
+    ETag(HeaderValue),
+    LastModified(HeaderValue),
+}
+
+/// Bytes asked for with a `Range` header: from `start` up to `end`, or to
+/// the end of the resource.
+#[derive(Clone, Copy)]
+struct ByteRange {
+    start: u64,
+    end: Option<u64>,
+}
 
+/// Opening a URL ended with a response other than a success.
+#[derive(Debug)]
+pub struct StatusError {
+    pub url: url::Url,
+    pub status: StatusCode,
 }
 
-impl UrlStream for HttpUrlStream {
+/// Blocking view of [`HttpStream`] driven by the shared runtime.
+///
+/// Reads go through a read-ahead buffer holding whatever arrived with the
+/// last chunk; seeks landing inside it are served without touching the
+/// network.
+pub struct HttpUrlStream {
+    inner: HttpStream,
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    buffer_pos: usize,
 }
 
+impl HttpStream {
+    /// Opens `url` through `client`.
+    pub async fn open(url: &url::Url, client: &UrlStreamClient) -> Result<Self, Error> {
+        Self::open_from(url, client, None).await
+    }
+
+    /// Opens the `range` of `url` through `client`, asking for only those
+    /// bytes.
+    ///
+    /// A `206 Partial Content` answer must start at `range.start` and end
+    /// within the range; when the server ignores `Range` and sends it all,
+    /// the bytes before the range are skipped. Reads end at `range.end`, or
+    /// sooner with the resource. [`len`](Self::len) is still the size of
+    /// the whole resource.
+    pub async fn open_range(url: &url::Url, client: &UrlStreamClient, range: Range<u64>) -> Result<Self, Error> {
+        if range.is_empty() {
+            return Err(Error::from(format!("empty range {:?}", range)));
+        }
+        let range = ByteRange { start: range.start, end: Some(range.end) };
+        let mut stream = Self::open_from(url, client, Some(range)).await?;
+        if stream.status == StatusCode::OK {
+            let mut skip = (&mut stream).take(range.start);
+            tokio::io::copy(&mut skip, &mut tokio::io::sink()).await?;
+        }
+        Ok(stream)
+    }
+
+    async fn open_from(url: &url::Url, client: &UrlStreamClient, range: Option<ByteRange>) -> Result<Self, Error> {
+        let mut auth = Auth::for_url(url, client.bearer_token());
+        let mut url = auth::without_credentials(url);
+        let mut request_headers = client.default_headers().clone();
+        if !request_headers.contains_key(header::ACCEPT_ENCODING) {
+            request_headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(decode::ACCEPT_ENCODING));
+        }
+        if range.is_some() {
+            // ranges address the encoded bytes
+            request_headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        }
+        let limits = client.limits();
+        let mut redirects = 0;
+        let (permit, res, from_cache) = loop {
+            match client.proxy().forward_authorization(&url) {
+                Some(authorization) => request_headers.insert(header::PROXY_AUTHORIZATION, authorization),
+                None => request_headers.remove(header::PROXY_AUTHORIZATION),
+            };
+            let (permit, (res, from_cache)) = limits
+                .guard(async {
+                    let permit = client.acquire(&url).await;
+                    Ok((permit, send_cached(client, &limits, &url, &request_headers, range, auth.as_ref()).await?))
+                })
+                .await?;
+            let location = match res.status() {
+                StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT => res.headers().get(header::LOCATION).and_then(|v| v.to_str().ok()),
+                _ => None,
+            };
+            let next = match location {
+                Some(location) if redirects < MAX_REDIRECTS => auth::without_credentials(&url.join(location)?),
+                Some(_) => return Err(Error::from(format!("can't open {} (too many redirects)", url))),
+                None => break (permit, res, from_cache),
+            };
+            if next.origin() != url.origin() {
+                auth = None;
+                request_headers.remove(header::AUTHORIZATION);
+            }
+            url = next;
+            redirects += 1;
+        };
+        if !from_cache {
+            client.record_version(&url, res.version());
+        }
+        let status = res.status();
+        if !status.is_success() {
+            return Err(Error::from(StatusError { url, status }));
+        }
+        let (parts, body) = res.into_parts();
+        let partial = range.filter(|_| status == StatusCode::PARTIAL_CONTENT);
+        if let Some(range) = partial {
+            if !range.is_answered_by(&parts.headers) {
+                return Err(Error::from(io::Error::new(io::ErrorKind::InvalidData, format!("{} answered {} with an unexpected Content-Range", url, range))));
+            }
+        }
+        let mut accept_ranges = partial.is_some() || parts.headers.get_all(header::ACCEPT_RANGES).iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.split(',').any(|unit| unit.trim().eq_ignore_ascii_case("bytes")));
+        let mut length = match partial {
+            Some(_) => content_range(&parts.headers).and_then(|(_, total)| total),
+            None => parts.headers.get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok()),
+        };
+        let encodings = ContentEncoding::from_headers(&parts.headers)?;
+        let progress = client.progress().map(|callback| ProgressTracker::new(callback.clone(), length));
+        let decoder = if client.decodes_content() && !encodings.is_empty() {
+            accept_ranges = false;
+            length = None;
+            Some(Decoder::new(&encodings))
+        } else {
+            None
+        };
+        Ok(HttpStream {
+            url,
+            client: client.clone(),
+            request_headers,
+            status,
+            validator: validator(&parts.headers),
+            headers: parts.headers,
+            body,
+            chunk: Bytes::new(),
+            position: partial.map_or(0, |range| range.start),
+            length,
+            accept_ranges,
+            resume: None,
+            from_cache,
+            encodings,
+            decoder,
+            timers: limits.timers(),
+            limits,
+            auth,
+            // reading from the cache doesn't touch the network
+            throttle: Throttle::new(if from_cache { Vec::new() } else { client.rate_limiters() }),
+            progress,
+            version: parts.version,
+            end: range.and_then(|range| range.end),
+            _permits: if from_cache { Vec::new() } else { permit },
+        })
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// URL the stream was opened on, where redirects led.
+    pub fn url(&self) -> &url::Url {
+        &self.url
+    }
+
+    /// HTTP version of the response the stream was opened on.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Offset of the next byte to be read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Total size of the resource, if the server announced it.
+    pub fn len(&self) -> Option<u64> {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == Some(0)
+    }
+
+    /// Codings the server applied to the body, in the order applied.
+    pub fn content_encoding(&self) -> &[ContentEncoding] {
+        &self.encodings
+    }
+
+    /// Whether the body is served from the [`HttpCache`].
+    pub fn is_from_cache(&self) -> bool {
+        self.from_cache
+    }
+
+    /// Whether the server advertised `Accept-Ranges: bytes`.
+    pub fn is_seekable(&self) -> bool {
+        self.accept_ranges
+    }
+
+    /// Moves the read position to `offset` bytes from the start.
+    ///
+    /// Short forward seeks skip over body bytes; anything else reissues the
+    /// request with a `Range` header, guarded by `If-Range` so a resource
+    /// changed since it was opened is reported instead of silently mixed.
+    pub async fn seek_to(&mut self, offset: u64) -> io::Result<u64> {
+        if self.end.is_some_and(|end| offset >= end) {
+            self.reset(Response::new(Body::empty()), offset);
+            return Ok(offset);
+        }
+        if offset >= self.position && offset - self.position <= SKIP_THRESHOLD {
+            let distance = offset - self.position;
+            let mut skip = (&mut *self).take(distance);
+            tokio::io::copy(&mut skip, &mut tokio::io::sink()).await?;
+            if self.position == offset {
+                return Ok(offset);
+            }
+        }
+        if offset == 0 && !self.accept_ranges {
+            let send = retry::send(self.client.retry_policy(), &self.url, 0, || fetch(&self.client, &self.limits, &self.url, &self.request_headers, None, self.auth.as_ref()));
+            let res = self.limits.guard(send).await.map_err(timeout::into_io)?;
+            self.check_same_resource(&res)?;
+            self.reset(res, 0);
+            return Ok(0);
+        }
+        if !self.accept_ranges {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} does not support range requests", self.url),
+            ));
+        }
+
+        let range = Some((ByteRange { start: offset, end: self.end }, self.validator.as_ref()));
+        let send = retry::send(self.client.retry_policy(), &self.url, offset, || fetch(&self.client, &self.limits, &self.url, &self.request_headers, range, self.auth.as_ref()));
+        let res = self.limits.guard(send).await.map_err(timeout::into_io)?;
+        match res.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                self.check_same_resource(&res)?;
+                let start = content_range(res.headers()).map(|(range, _)| range.start);
+                if start != Some(offset) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected Content-Range in response"));
+                }
+                self.reset(res, offset);
+                Ok(offset)
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                self.reset(Response::new(Body::empty()), offset);
+                Ok(offset)
+            }
+            StatusCode::OK => Err(changed_resource(&self.url)),
+            status => Err(io::Error::other(format!("range request to {} failed (status: {})", self.url, status))),
+        }
+    }
+
+    fn check_same_resource<B>(&self, res: &Response<B>) -> io::Result<()> {
+        match (&self.validator, validator(res.headers())) {
+            (Some(Validator::ETag(old)), Some(Validator::ETag(new))) if *old != new => Err(changed_resource(&self.url)),
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&mut self, res: Response<Body>, position: u64) {
+        let (parts, body) = res.into_parts();
+        if let Some((_, Some(total))) = content_range(&parts.headers) {
+            self.length = Some(total);
+        }
+        self.body = body;
+        self.chunk = Bytes::new();
+        self.position = position;
+        self.resume = None;
+        if self.decoder.is_some() {
+            self.decoder = Some(Decoder::new(&self.encodings));
+        }
+    }
+
+    /// Whether a transfer that failed with `cause` can be picked up where it
+    /// stopped.
+    fn can_resume(&self, cause: &Error) -> bool {
+        self.client.retry_policy().enabled() && (self.accept_ranges || self.position == 0) && retry::is_transient(cause)
+    }
+
+    /// Starts reconnecting after the body failed with `cause`, requesting
+    /// the rest of the resource from the current position.
+    fn start_resume(&mut self, cause: Error) {
+        let client = self.client.clone();
+        let headers = self.request_headers.clone();
+        let url = self.url.clone();
+        let validator = self.validator.clone();
+        let limits = self.limits.clone();
+        let auth = self.auth.clone();
+        let offset = self.position;
+        let end = self.end;
+        self.resume = Some(Box::pin(async move {
+            let range = (offset > 0 || end.is_some()).then_some((ByteRange { start: offset, end }, validator.as_ref()));
+            let res = retry::resume(client.retry_policy(), &url, offset, cause, || fetch(&client, &limits, &url, &headers, range, auth.as_ref())).await?;
+            match res.status() {
+                StatusCode::PARTIAL_CONTENT if content_range(res.headers()).map(|(range, _)| range.start) == Some(offset) => Ok(res),
+                StatusCode::OK if offset == 0 => Ok(res),
+                StatusCode::OK | StatusCode::PARTIAL_CONTENT => Err(Error::from(changed_resource(&url))),
+                status => Err(Error::from(format!("can't resume {} (status: {})", url, status))),
+            }
+        }));
+    }
+
+    /// Next chunk of the body, cut off at the end of the range the stream
+    /// was opened on.
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Bytes>>> {
+        let remaining = match self.end {
+            Some(end) if self.position >= end => return Poll::Ready(None),
+            Some(end) => end - self.position,
+            None => return self.poll_body(cx),
+        };
+        match self.poll_body(cx) {
+            Poll::Ready(Some(Ok(mut data))) => {
+                data.truncate(remaining.try_into().unwrap_or(usize::MAX));
+                Poll::Ready(Some(Ok(data)))
+            }
+            polled => polled,
+        }
+    }
+
+    fn poll_body(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Bytes>>> {
+        loop {
+            if let Some(e) = self.timers.poll_expired(cx) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            if let Some(resume) = self.resume.as_mut() {
+                let res = match resume.as_mut().poll(cx) {
+                    Poll::Ready(res) => res.map_err(timeout::into_io),
+                    Poll::Pending => return Poll::Pending,
+                };
+                self.resume = None;
+                let res = res?;
+                self.check_same_resource(&res)?;
+                self.reset(res, self.position);
+            }
+            if self.chunk.has_remaining() {
+                return Poll::Ready(Some(Ok(std::mem::take(&mut self.chunk))));
+            }
+            if self.throttle.poll_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let polled = Pin::new(&mut self.body).poll_data(cx);
+            if polled.is_ready() {
+                self.timers.data_received();
+            }
+            if let Poll::Ready(Some(Ok(data))) = &polled {
+                self.throttle.received(data.len());
+                if let Some(progress) = self.progress.as_mut() {
+                    progress.received(data.len());
+                }
+            }
+            return match polled {
+                Poll::Ready(Some(Ok(data))) => match self.decoder.as_mut() {
+                    Some(decoder) => match decoder.decode(&data)? {
+                        decoded if decoded.is_empty() => continue,
+                        decoded => Poll::Ready(Some(Ok(decoded))),
+                    },
+                    None => Poll::Ready(Some(Ok(data))),
+                },
+                Poll::Ready(Some(Err(e))) => {
+                    let cause = Error::from(e);
+                    if self.can_resume(&cause) {
+                        self.start_resume(cause);
+                        continue;
+                    }
+                    Poll::Ready(Some(Err(io::Error::other(cause))))
+                }
+                Poll::Ready(None) => match self.decoder.as_mut().map(Decoder::finish).transpose()? {
+                    Some(tail) if !tail.is_empty() => Poll::Ready(Some(Ok(tail))),
+                    _ => Poll::Ready(None),
+                },
+                Poll::Pending => match self.timers.poll_idle(cx) {
+                    Some(e) => {
+                        let cause = Error::from(e);
+                        if self.can_resume(&cause) {
+                            self.start_resume(cause);
+                            continue;
+                        }
+                        Poll::Ready(Some(Err(timeout::into_io(cause))))
+                    }
+                    None => Poll::Pending,
+                },
+            };
+        }
+    }
+}
+
+impl AsyncUrlStream for HttpStream {}
+
+impl AsyncRead for HttpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while !this.chunk.has_remaining() {
+            match this.poll_chunk(cx) {
+                Poll::Ready(Some(Ok(data))) => this.chunk = data,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = this.chunk.len().min(buf.remaining());
+        buf.put_slice(&this.chunk.split_to(len));
+        this.position += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for HttpStream {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(read_only()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for HttpStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let next = this.poll_chunk(cx);
+        if let Poll::Ready(Some(Ok(data))) = &next {
+            this.position += data.len() as u64;
+        }
+        next
+    }
+}
+
+impl HttpUrlStream {
+    /// Blocking counterpart of [`HttpStream::open`].
+    pub fn open(url: &url::Url, client: &UrlStreamClient) -> Result<Self, Error> {
+        let inner = runtime::block_on(HttpStream::open(url, client))?;
+        Ok(HttpUrlStream::new(inner))
+    }
+
+    fn new(inner: HttpStream) -> Self {
+        let buffer_start = inner.position();
+        HttpUrlStream { inner, buffer: Vec::with_capacity(READ_AHEAD), buffer_start, buffer_pos: 0 }
+    }
+
+    pub fn len(&self) -> Option<u64> {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        self.inner.is_seekable()
+    }
+
+    pub fn is_from_cache(&self) -> bool {
+        self.inner.is_from_cache()
+    }
+
+    pub fn content_encoding(&self) -> &[ContentEncoding] {
+        self.inner.content_encoding()
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.inner.status()
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        self.inner.headers()
+    }
+
+    pub fn version(&self) -> Version {
+        self.inner.version()
+    }
+}
+
+impl UrlStream for HttpUrlStream {}
 
 impl Write for HttpUrlStream {
-    fn write_char(&mut self, c: char) -> std::fmt::Result {
-        unimplemented!()
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(read_only())
     }
 
-    fn write_str(&mut self, s: &str) -> std::fmt::Result {
-        unimplemented!()
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
 impl Read for HttpUrlStream {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        unimplemented!()
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer_pos == self.buffer.len() {
+            self.buffer.clear();
+            self.buffer_pos = 0;
+            self.buffer_start = self.inner.position();
+            let inner = &mut self.inner;
+            let buffer = &mut self.buffer;
+            // One read: bytes already received are handed out rather than
+            // held back until the buffer fills or the read times out.
+            runtime::block_on(async move {
+                let mut limited = (&mut *inner).take(READ_AHEAD as u64);
+                limited.read_buf(buffer).await
+            })?;
+        }
+        let available = &self.buffer[self.buffer_pos..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.buffer_pos += len;
+        Ok(len)
     }
 }
 
-pub fn open(url: &url::Url) -> Box<dyn UrlStream> {
-    let connector = hyper_rustls::HttpsConnectorBuilder::new()
-        .with_native_roots()
-        .https_or_http()
-        .enable_http1()
-        .build();
+impl Seek for HttpUrlStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let current = self.buffer_start + self.buffer_pos as u64;
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => current.checked_add_signed(delta),
+            SeekFrom::End(delta) => match self.inner.len() {
+                Some(len) => len.checked_add_signed(delta),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::Unsupported, "length of the resource is unknown"));
+                }
+            },
+        };
+        let target = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+
+        if target >= self.buffer_start && target <= self.buffer_start + self.buffer.len() as u64 {
+            self.buffer_pos = (target - self.buffer_start) as usize;
+            return Ok(target);
+        }
+        runtime::block_on(self.inner.seek_to(target))?;
+        self.buffer.clear();
+        self.buffer_pos = 0;
+        self.buffer_start = target;
+        Ok(target)
+    }
+}
 
-    let client: Client<_, hyper::Body> = Client::builder().build(connector);
-    Box::new(HttpUrlStream{})
-}
\ No newline at end of file
+impl ByteRange {
+    /// Whether the `Content-Range` of a 206 in `headers` starts where asked
+    /// and stays within the range.
+    fn is_answered_by(&self, headers: &HeaderMap) -> bool {
+        match content_range(headers) {
+            Some((range, _)) => range.start == self.start && self.end.is_none_or(|end| range.end <= end),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "bytes={}-{}", self.start, end - 1),
+            None => write!(f, "bytes={}-", self.start),
+        }
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can't open {} (status: {})", self.url, self.status)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "http response stream is read-only")
+}
+
+fn changed_resource(url: &url::Url) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} changed since it was opened", url))
+}
+
+fn validator(headers: &HeaderMap) -> Option<Validator> {
+    match headers.get(header::ETAG) {
+        Some(etag) if !etag.as_bytes().starts_with(b"W/") => Some(Validator::ETag(etag.clone())),
+        _ => headers.get(header::LAST_MODIFIED).cloned().map(Validator::LastModified),
+    }
+}
+
+/// Parses `Content-Range: bytes <first>-<last>/<total>` into the range of
+/// bytes sent and the total.
+fn content_range(headers: &HeaderMap) -> Option<(Range<u64>, Option<u64>)> {
+    let value = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let (first, last): (u64, u64) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+    Some((first..last.checked_add(1)?, total.trim().parse().ok()))
+}
+
+/// Sends a GET for `url`. A 401 answered with a challenge `auth` can meet
+/// is retried once with credentials.
+async fn fetch(client: &UrlStreamClient, limits: &Limits, url: &url::Url, headers: &HeaderMap, range: Option<(ByteRange, Option<&Validator>)>, auth: Option<&Auth>) -> Result<Response<Body>, Error> {
+    // an explicit Authorization header is sent as is
+    let auth = auth.filter(|_| !headers.contains_key(header::AUTHORIZATION));
+    let mut challenged = false;
+    loop {
+        let mut req = Request::get(url.as_str());
+        let authorization = auth.and_then(|auth| auth.authorization(client.auth_cache(), url));
+        if let Some(request_headers) = req.headers_mut() {
+            request_headers.extend(headers.clone());
+            if let Some(jar) = client.cookie_jar() {
+                jar.add_request_header(url, request_headers);
+            }
+            if let Some(authorization) = &authorization {
+                request_headers.insert(header::AUTHORIZATION, authorization.clone());
+            }
+        }
+        if let Some((range, validator)) = range {
+            req = req.header(header::RANGE, range.to_string());
+            match validator {
+                Some(Validator::ETag(v)) | Some(Validator::LastModified(v)) => req = req.header(header::IF_RANGE, v),
+                None => {}
+            }
+        }
+        let req = req.body(Body::empty())?;
+        let res = limits.request(client.transport().send(req)).await?;
+        if let Some(jar) = client.cookie_jar() {
+            jar.store_response(url, res.headers());
+        }
+        match auth {
+            Some(auth) if res.status() == StatusCode::UNAUTHORIZED
+                && !challenged
+                && auth.challenged(client.auth_cache(), url, res.headers(), authorization.as_ref()) => challenged = true,
+            _ => return Ok(res),
+        }
+    }
+}
+
+/// Sends the initial request, going through the cache when one is
+/// configured and the whole resource is asked for. Returns the response
+/// and whether it was served from cache.
+async fn send_cached(client: &UrlStreamClient, limits: &Limits, url: &url::Url, headers: &HeaderMap, range: Option<ByteRange>, auth: Option<&Auth>) -> Result<(Response<Body>, bool), Error> {
+    let policy = client.retry_policy();
+    let cache = match client.cache() {
+        Some(cache) if range.is_none() => cache,
+        _ => {
+            let offset = range.map_or(0, |range| range.start);
+            let range = range.map(|range| (range, None));
+            return Ok((retry::send(policy, url, offset, || fetch(client, limits, url, headers, range, auth)).await?, false));
+        }
+    };
+    match cache.lookup(url, headers) {
+        Lookup::Fresh(entry) => Ok((cache.respond(entry)?, true)),
+        Lookup::Stale(entry) => {
+            let mut conditional = headers.clone();
+            HttpCache::conditional_headers(&entry, &mut conditional);
+            let res = retry::send(policy, url, 0, || fetch(client, limits, url, &conditional, None, auth)).await?;
+            if res.status() == StatusCode::NOT_MODIFIED {
+                Ok((cache.revalidated(entry, res.headers())?, true))
+            } else {
+                Ok((cache.store(url, headers, res), false))
+            }
+        }
+        Lookup::Miss => {
+            let res = retry::send(policy, url, 0, || fetch(client, limits, url, headers, None, auth)).await?;
+            Ok((cache.store(url, headers, res), false))
+        }
+    }
+}
+
+pub async fn open_async(url: &url::Url, client: &UrlStreamClient) -> Result<HttpStream, Error> {
+    HttpStream::open(url, client).await
+}
+
+pub fn open(url: &url::Url, client: &UrlStreamClient) -> Result<HttpUrlStream, Error> {
+    HttpUrlStream::open(url, client)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex};
+
+    use super::*;
+
+    fn serve_text(text: &'static str) -> std::net::SocketAddr {
+        crate::test_server::serve(move |_| async move { hyper::Response::new(Body::from(text)) })
+    }
+
+    #[test]
+    fn blocking_read_to_end() {
+        let addr = serve_text("#EXTM3U\n#EXT-X-VERSION:3\n");
+        let url = url::Url::parse(&format!("http://{}/master.m3u8", addr)).unwrap();
+        let mut body = String::new();
+        for _ in 0..2 {
+            body.clear();
+            open(&url, UrlStreamClient::global()).unwrap().read_to_string(&mut body).unwrap();
+            assert_eq!(body, "#EXTM3U\n#EXT-X-VERSION:3\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn async_read_and_stream_views() {
+        use futures::TryStreamExt;
+
+        let addr = serve_text("segment-bytes");
+        let url = url::Url::parse(&format!("http://{}/seg.ts", addr)).unwrap();
+
+        let mut body = Vec::new();
+        open_async(&url, UrlStreamClient::global()).await.unwrap().read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"segment-bytes");
+
+        let chunks: Vec<Bytes> = open_async(&url, UrlStreamClient::global()).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"segment-bytes");
+    }
+
+    #[test]
+    fn error_status_fails_open() {
+        let addr = crate::test_server::serve(|_| async {
+            hyper::Response::builder().status(404).body(Body::empty()).unwrap()
+        });
+        let url = url::Url::parse(&format!("http://{}/missing", addr)).unwrap();
+        let err = open(&url, UrlStreamClient::global()).err().unwrap();
+        assert_eq!(err.downcast_ref::<StatusError>().unwrap().status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn open_range_reads_only_the_range() {
+        let addr = crate::test_server::serve(|req| async move {
+            let body = pattern(1000);
+            let range = req.headers().get(header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("bytes="))
+                .and_then(|v| v.split_once('-'))
+                .map(|(first, last)| (first.parse::<usize>().unwrap(), last.parse::<usize>().unwrap()));
+            let partial = |first: usize, last: usize| hyper::Response::builder()
+                .status(206)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", first, last, body.len()))
+                .body(Body::from(body[first..=last].to_vec()))
+                .unwrap();
+            match (req.uri().path(), range) {
+                ("/ranged", Some((first, last))) => partial(first, last),
+                ("/shifted", Some((first, last))) => partial(first + 1, last + 1),
+                ("/longer", Some((first, _))) => partial(first, body.len() - 1),
+                _ => hyper::Response::new(Body::from(body)),
+            }
+        });
+        let url = |path: &str| url::Url::parse(&format!("http://{}{}", addr, path)).unwrap();
+        let read_range = |path: &'static str, range: Range<u64>| {
+            let url = url(path);
+            async move {
+                let mut stream = HttpStream::open_range(&url, UrlStreamClient::global(), range).await?;
+                let mut data = Vec::new();
+                stream.read_to_end(&mut data).await?;
+                Ok::<_, Error>((data, stream.len()))
+            }
+        };
+
+        assert_eq!(read_range("/ranged", 100..110).await.unwrap(), (pattern(1000)[100..110].to_vec(), Some(1000)));
+        // a server ignoring Range has the bytes around the range dropped
+        assert_eq!(read_range("/ignored", 100..110).await.unwrap().0, pattern(1000)[100..110]);
+        assert_eq!(read_range("/ignored", 990..2000).await.unwrap().0, pattern(1000)[990..]);
+        for path in ["/shifted", "/longer"] {
+            let err = read_range(path, 100..110).await.unwrap_err();
+            assert_eq!(err.downcast_ref::<io::Error>().map(io::Error::kind), Some(io::ErrorKind::InvalidData), "{}", err);
+        }
+    }
+
+    /// Serves `body` honoring `Range` when `ranges` is set. The ETag is read
+    /// from `etag` on every request so tests can change the resource.
+    fn serve_ranged(body: Vec<u8>, ranges: bool, etag: Arc<Mutex<&'static str>>, hits: Arc<AtomicUsize>) -> std::net::SocketAddr {
+        let body = Arc::new(body);
+        crate::test_server::serve(move |req| {
+            let body = body.clone();
+            let etag = *etag.lock().unwrap();
+            hits.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let mut res = hyper::Response::builder().header(header::ETAG, etag);
+                if ranges {
+                    res = res.header(header::ACCEPT_RANGES, "bytes");
+                }
+                let range = req.headers().get(header::RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.strip_prefix("bytes="))
+                    .and_then(|v| v.trim_end_matches('-').parse::<usize>().ok());
+                let fresh = req.headers().get(header::IF_RANGE).is_none_or(|v| v == etag);
+                match range {
+                    Some(start) if ranges && fresh && start >= body.len() => {
+                        res.status(416).body(Body::empty()).unwrap()
+                    }
+                    Some(start) if ranges && fresh => res
+                        .status(206)
+                        .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, body.len() - 1, body.len()))
+                        .body(Body::from(body[start..].to_vec()))
+                        .unwrap(),
+                    _ => res.body(Body::from(body.to_vec())).unwrap(),
+                }
+            }
+        })
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn seek_with_range_requests() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = serve_ranged(pattern(300_000), true, Arc::new(Mutex::new("\"v1\"")), hits.clone());
+        let url = url::Url::parse(&format!("http://{}/video.mp4", addr)).unwrap();
+        let mut stream = open(&url, UrlStreamClient::global()).unwrap();
+        assert!(stream.is_seekable());
+        assert_eq!(stream.len(), Some(300_000));
+
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3]);
+
+        // inside the read-ahead buffer
+        assert_eq!(stream.seek(SeekFrom::Start(1000)).unwrap(), 1000);
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], (1000 % 251) as u8);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        assert_eq!(stream.seek(SeekFrom::Start(250_000)).unwrap(), 250_000);
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], (250_000 % 251) as u8);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        assert_eq!(stream.seek(SeekFrom::End(-2)).unwrap(), 299_998);
+        let mut tail = Vec::new();
+        stream.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, [(299_998 % 251) as u8, (299_999 % 251) as u8]);
+
+        stream.seek(SeekFrom::Start(400_000)).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn seek_without_range_support() {
+        let addr = serve_ranged(pattern(300_000), false, Arc::new(Mutex::new("\"v1\"")), Arc::new(AtomicUsize::new(0)));
+        let url = url::Url::parse(&format!("http://{}/video.mp4", addr)).unwrap();
+        let mut stream = open(&url, UrlStreamClient::global()).unwrap();
+        assert!(!stream.is_seekable());
+        let err = stream.seek(SeekFrom::Start(200_000)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(stream.seek(SeekFrom::Start(100)).unwrap(), 100);
+    }
+
+    #[test]
+    fn seek_detects_changed_resource() {
+        let etag = Arc::new(Mutex::new("\"v1\""));
+        let addr = serve_ranged(pattern(300_000), true, etag.clone(), Arc::new(AtomicUsize::new(0)));
+        let url = url::Url::parse(&format!("http://{}/video.mp4", addr)).unwrap();
+        let mut stream = open(&url, UrlStreamClient::global()).unwrap();
+        *etag.lock().unwrap() = "\"v2\"";
+        let err = stream.seek(SeekFrom::Start(200_000)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn broken_transfer_resumes_from_last_byte() {
+        let body = Arc::new(pattern(200_000));
+        let hits = Arc::new(AtomicUsize::new(0));
+        let handler_hits = hits.clone();
+        let addr = crate::test_server::serve(move |req| {
+            let body = body.clone();
+            let hit = handler_hits.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let res = hyper::Response::builder()
+                    .header(header::ACCEPT_RANGES, "bytes")
+                    .header(header::ETAG, "\"v1\"");
+                let start = req.headers().get(header::RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.strip_prefix("bytes="))
+                    .and_then(|v| v.trim_end_matches('-').parse::<usize>().ok());
+                match start {
+                    None if hit == 0 => {
+                        use futures::StreamExt;
+
+                        let head = Bytes::copy_from_slice(&body[..70_000]);
+                        let broken = futures::stream::iter([Ok::<_, io::Error>(head)]).chain(futures::stream::once(async {
+                            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                            Err(io::ErrorKind::ConnectionReset.into())
+                        }));
+                        res.header(header::CONTENT_LENGTH, body.len())
+                            .body(Body::wrap_stream(broken))
+                            .unwrap()
+                    }
+                    None => res.body(Body::from(body.to_vec())).unwrap(),
+                    Some(start) => res
+                        .status(206)
+                        .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, body.len() - 1, body.len()))
+                        .body(Body::from(body[start..].to_vec()))
+                        .unwrap(),
+                }
+            }
+        });
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let policy = crate::RetryPolicy::new()
+            .backoff(std::time::Duration::from_millis(1), std::time::Duration::from_millis(10))
+            .on_retry(move |event| seen.lock().unwrap().push((event.attempt, event.offset)));
+        let url = url::Url::parse(&format!("http://{}/segment.ts", addr)).unwrap();
+        let mut received = Vec::new();
+        HttpUrlStream::open(&url, &UrlStreamClient::builder().retry(policy).build()).unwrap().read_to_end(&mut received).unwrap();
+
+        assert_eq!(received, pattern(200_000));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(*events.lock().unwrap(), vec![(2, 70_000)]);
+    }
+
+    #[test]
+    fn retryable_status_honors_retry_after() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let handler_hits = hits.clone();
+        let addr = crate::test_server::serve(move |_| {
+            let hit = handler_hits.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match hit {
+                    0 => hyper::Response::builder().status(429).header(header::RETRY_AFTER, "0").body(Body::empty()).unwrap(),
+                    1 => hyper::Response::builder().status(503).body(Body::empty()).unwrap(),
+                    _ => hyper::Response::new(Body::from("ok")),
+                }
+            }
+        });
+
+        let causes = Arc::new(Mutex::new(Vec::new()));
+        let seen = causes.clone();
+        let policy = crate::RetryPolicy::new()
+            .backoff(std::time::Duration::from_millis(1), std::time::Duration::from_millis(10))
+            .on_retry(move |event| {
+                if let retry::RetryCause::Status(status) = event.cause {
+                    seen.lock().unwrap().push(status.as_u16());
+                }
+            });
+        let url = url::Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut body = String::new();
+        HttpUrlStream::open(&url, &UrlStreamClient::builder().retry(policy).build()).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "ok");
+        assert_eq!(*causes.lock().unwrap(), vec![429, 503]);
+
+        hits.store(0, Ordering::SeqCst);
+        assert!(HttpUrlStream::open(&url, &UrlStreamClient::builder().retry(crate::RetryPolicy::none()).build()).is_err());
+    }
+
+    fn cache_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("url_stream_cache_{}", rand::random::<u32>()))
+    }
+
+    fn read_all(url: &url::Url, client: &UrlStreamClient) -> (String, bool) {
+        let mut stream = HttpUrlStream::open(url, client).unwrap();
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        (body, stream.is_from_cache())
+    }
+
+    #[test]
+    fn fresh_responses_come_from_cache() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let handler_hits = hits.clone();
+        let addr = crate::test_server::serve(move |req| {
+            handler_hits.fetch_add(1, Ordering::SeqCst);
+            async move {
+                hyper::Response::builder()
+                    .header(header::CACHE_CONTROL, "max-age=60")
+                    .body(Body::from(format!("body of {}", req.uri().path())))
+                    .unwrap()
+            }
+        });
+        let dir = cache_dir();
+        let client = UrlStreamClient::builder().cache(HttpCache::open(&dir, 1 << 20).unwrap()).build();
+        let url = url::Url::parse(&format!("http://{}/key.bin?b=2&a=1", addr)).unwrap();
+
+        assert_eq!(read_all(&url, &client), ("body of /key.bin".to_string(), false));
+        assert_eq!(read_all(&url, &client), ("body of /key.bin".to_string(), true));
+        // equivalent after normalization
+        let reordered = url::Url::parse(&format!("http://{}/key.bin?a=1&b=2#x", addr)).unwrap();
+        assert_eq!(read_all(&reordered, &client), ("body of /key.bin".to_string(), true));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // entries survive reopening the cache directory
+        let reopened = UrlStreamClient::builder().cache(HttpCache::open(&dir, 1 << 20).unwrap()).build();
+        assert!(read_all(&url, &reopened).1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_responses_are_revalidated() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let handler_hits = hits.clone();
+        let addr = crate::test_server::serve(move |req| {
+            handler_hits.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let res = hyper::Response::builder()
+                    .header(header::CACHE_CONTROL, "no-cache")
+                    .header(header::ETAG, "\"rev1\"");
+                if req.headers().get(header::IF_NONE_MATCH).is_some_and(|v| v == "\"rev1\"") {
+                    res.status(304).body(Body::empty()).unwrap()
+                } else {
+                    res.body(Body::from("#EXTM3U\n")).unwrap()
+                }
+            }
+        });
+        let dir = cache_dir();
+        let client = UrlStreamClient::builder().cache(HttpCache::open(&dir, 1 << 20).unwrap()).build();
+        let url = url::Url::parse(&format!("http://{}/live.m3u8", addr)).unwrap();
+
+        assert_eq!(read_all(&url, &client), ("#EXTM3U\n".to_string(), false));
+        assert_eq!(read_all(&url, &client), ("#EXTM3U\n".to_string(), true));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let addr = crate::test_server::serve(|_| async {
+            hyper::Response::builder()
+                .header(header::CACHE_CONTROL, "max-age=600")
+                .header(header::VARY, "Accept-Language")
+                .body(Body::from(vec![b'x'; 100]))
+                .unwrap()
+        });
+        let dir = cache_dir();
+        let cache = HttpCache::open(&dir, 250).unwrap();
+        let client = UrlStreamClient::builder().cache(cache.clone()).build();
+        let urls: Vec<url::Url> = (0..3)
+            .map(|i| url::Url::parse(&format!("http://{}/seg{}.ts", addr, i)).unwrap())
+            .collect();
+
+        read_all(&urls[0], &client);
+        read_all(&urls[1], &client);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(read_all(&urls[0], &client).1);
+        read_all(&urls[2], &client);
+
+        assert_eq!(cache.size(), 200);
+        assert!(read_all(&urls[0], &client).1);
+        assert!(!read_all(&urls[1], &client).1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_bodies_are_decoded() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let text = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n".repeat(50);
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        std::io::Write::write_all(&mut gz, text.as_bytes()).unwrap();
+        let encoded = gz.finish().unwrap();
+        let served = encoded.clone();
+        let addr = crate::test_server::serve(move |req| {
+            let served = served.clone();
+            async move {
+                assert_eq!(req.headers()[header::ACCEPT_ENCODING], decode::ACCEPT_ENCODING);
+                hyper::Response::builder()
+                    .header(header::CONTENT_ENCODING, "gzip")
+                    .header(header::ACCEPT_RANGES, "bytes")
+                    .body(Body::from(served))
+                    .unwrap()
+            }
+        });
+        let url = url::Url::parse(&format!("http://{}/master.m3u8", addr)).unwrap();
+
+        let mut stream = open(&url, UrlStreamClient::global()).unwrap();
+        assert_eq!(stream.content_encoding(), [ContentEncoding::Gzip]);
+        assert!(!stream.is_seekable());
+        let mut decoded = String::new();
+        stream.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        let mut raw = HttpUrlStream::open(&url, &UrlStreamClient::builder().decode_content(false).build()).unwrap();
+        assert_eq!(raw.content_encoding(), [ContentEncoding::Gzip]);
+        let mut bytes = Vec::new();
+        raw.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, encoded);
+    }
+
+    /// Accepts connections and answers every request with `head`, if any,
+    /// then keeps the connection open without sending anything else.
+    fn serve_stalled(head: Option<&'static [u8]>) -> std::net::SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut open = Vec::new();
+            for mut conn in listener.incoming().flatten() {
+                if let Some(head) = head {
+                    let mut request = [0u8; 1024];
+                    let _ = conn.read(&mut request);
+                    let _ = conn.write_all(head);
+                }
+                open.push(conn);
+            }
+        });
+        addr
+    }
+
+    fn limited(timeouts: crate::Timeouts) -> UrlStreamClient {
+        UrlStreamClient::builder().retry(crate::RetryPolicy::none()).timeouts(timeouts).build()
+    }
+
+    fn timeout_of(err: &(dyn std::error::Error + 'static)) -> Option<crate::TimeoutError> {
+        crate::TimeoutError::find(err)
+    }
+
+    #[test]
+    fn stalled_server_times_out() {
+        use std::time::Duration;
+
+        let silent = serve_stalled(None);
+        let url = url::Url::parse(&format!("https://{}/master.m3u8", silent)).unwrap();
+        let err = open(&url, &limited(crate::Timeouts::new().tls_handshake(Duration::from_millis(100)))).err().unwrap();
+        assert_eq!(timeout_of(err.as_ref()), Some(crate::TimeoutError::TlsHandshake));
+
+        let no_response = serve_stalled(Some(b""));
+        let url = url::Url::parse(&format!("http://{}/master.m3u8", no_response)).unwrap();
+        let err = open(&url, &limited(crate::Timeouts::new().first_byte(Duration::from_millis(100)))).err().unwrap();
+        assert_eq!(timeout_of(err.as_ref()), Some(crate::TimeoutError::FirstByte));
+
+        let truncated = serve_stalled(Some(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc"));
+        let url = url::Url::parse(&format!("http://{}/seg.ts", truncated)).unwrap();
+        for (timeouts, expected) in [
+            (crate::Timeouts::new().read_idle(Duration::from_millis(100)), crate::TimeoutError::ReadIdle),
+            (crate::Timeouts::new().total(Duration::from_millis(300)), crate::TimeoutError::Total),
+        ] {
+            let mut stream = open(&url, &limited(timeouts)).unwrap();
+            let mut body = Vec::new();
+            let err = stream.read_to_end(&mut body).unwrap_err();
+            assert_eq!(body, b"abc");
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(timeout_of(&err), Some(expected));
+        }
+    }
+
+    #[test]
+    fn per_request_timeouts_override_client_defaults() {
+        use std::time::Duration;
+
+        let addr = serve_stalled(Some(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc"));
+        let url = url::Url::parse(&format!("http://{}/seg.ts", addr)).unwrap();
+        let client = limited(crate::Timeouts::new().read_idle(Duration::from_secs(60)));
+        let per_request = client.with_timeouts(crate::Timeouts::new().read_idle(Duration::from_millis(100)));
+        let err = open(&url, &per_request).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(timeout_of(&err), Some(crate::TimeoutError::ReadIdle));
+        assert_eq!(client.timeouts().read_idle, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn cancellation_interrupts_reads() {
+        let addr = serve_stalled(Some(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc"));
+        let url = url::Url::parse(&format!("http://{}/seg.ts", addr)).unwrap();
+        let token = crate::CancellationToken::new();
+        let client = UrlStreamClient::global().with_cancellation(token.clone());
+        let mut stream = open(&url, &client).unwrap();
+
+        let canceller = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            canceller.cancel();
+        });
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 3);
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+
+        let err = open(&url, &client).err().unwrap();
+        assert!(err.is::<crate::Cancelled>());
+    }
+
+    #[test]
+    fn progress_reports_transferred_bytes() {
+        let body = pattern(200_000);
+        let addr = crate::test_server::serve(move |_| {
+            let body = body.clone();
+            async move { hyper::Response::new(Body::from(body)) }
+        });
+        let url = url::Url::parse(&format!("http://{}/seg.ts", addr)).unwrap();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let seen = reports.clone();
+        let client = UrlStreamClient::new().with_progress(move |p| seen.lock().unwrap().push(*p));
+        open(&url, &client).unwrap().read_to_end(&mut Vec::new()).unwrap();
+
+        let reports = reports.lock().unwrap();
+        assert!(!reports.is_empty());
+        assert!(reports.windows(2).all(|w| w[0].transferred() < w[1].transferred()));
+        let last = reports.last().unwrap();
+        assert_eq!((last.transferred(), last.total(), last.fraction()), (200_000, Some(200_000), Some(1.0)));
+        assert!(last.average_rate() > 0.0);
+    }
+
+    #[test]
+    fn rate_limit_paces_reads() {
+        let body = pattern(64 * 1024);
+        let addr = crate::test_server::serve(move |_| {
+            let body = body.clone();
+            async move { hyper::Response::new(Body::from(body)) }
+        });
+        let url = url::Url::parse(&format!("http://{}/seg.ts", addr)).unwrap();
+        // the 48 KiB beyond the burst take at least 3/8 s at 128 KiB/s
+        let client = UrlStreamClient::builder().rate_limit(crate::RateLimiter::new(128 * 1024).burst(16 * 1024)).build();
+        let started = std::time::Instant::now();
+        let mut read = Vec::new();
+        open(&url, &client).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read.len(), 64 * 1024);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(350), "{:?}", started.elapsed());
+
+        // the limit belongs to the client, not the server
+        let unlimited = std::time::Instant::now();
+        open(&url, &UrlStreamClient::new()).unwrap().read_to_end(&mut Vec::new()).unwrap();
+        assert!(unlimited.elapsed() < std::time::Duration::from_millis(350));
+    }
+}
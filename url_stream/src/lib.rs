@@ -1,25 +1,91 @@
-use std::{io::Read, fmt::Write};
+use std::{future::Future, io::{Read, Write}};
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 extern crate url;
+mod auth;
+pub mod cache;
+mod client;
+mod connector;
+mod cookie;
+mod decode;
+mod ftp;
 mod https;
+mod progress;
+mod proxy;
+pub mod retry;
+mod runtime;
+#[cfg(test)]
+mod test_server;
+mod throttle;
+mod timeout;
+mod tls;
+mod transport;
+
+pub use cache::HttpCache;
+pub use client::{HttpVersion, UrlStreamClient, UrlStreamClientBuilder};
+pub use cookie::{Cookie, CookieJar, SameSite};
+pub use decode::ContentEncoding;
+pub use ftp::{FtpStream, FtpUrlStream};
+pub use https::{HttpStream, HttpUrlStream, StatusError};
+pub use progress::Progress;
+pub use proxy::{Proxy, ProxyConfig};
+pub use retry::RetryPolicy;
+pub use throttle::RateLimiter;
+pub use timeout::{Cancelled, TimeoutError, Timeouts};
+pub use tls::{spki_sha256, TlsConfig};
+pub use transport::{Fixtures, Transport, TransportFuture};
+pub use tokio_util::sync::CancellationToken;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
-type Error = Box<dyn std::error::Error>;
+/// Blocking stream opened from a URL.
+pub trait UrlStream: Read + Write { }
 
-trait UrlStream: Read + Write { }
+/// Asynchronous stream opened from a URL.
+///
+/// Besides `AsyncRead`/`AsyncWrite`, the body can be consumed as a `Stream`
+/// of the chunks as they arrive from the transport.
+pub trait AsyncUrlStream: AsyncRead + AsyncWrite + Stream<Item = std::io::Result<Bytes>> + Send + Unpin { }
 
+pub trait UrlOpen {
+    /// Opens through the process-wide [`UrlStreamClient::global`] client.
+    fn open(&self) -> Result<Box<dyn UrlStream>, Error> {
+        self.open_with(UrlStreamClient::global())
+    }
+
+    fn open_with(&self, client: &UrlStreamClient) -> Result<Box<dyn UrlStream>, Error>;
+}
 
+pub trait AsyncUrlOpen {
+    /// Opens through the process-wide [`UrlStreamClient::global`] client.
+    fn open_async(&self) -> impl Future<Output = Result<Box<dyn AsyncUrlStream>, Error>> + Send {
+        self.open_async_with(UrlStreamClient::global())
+    }
 
-trait UrlOpen {
-    fn open(&self) -> Result<Box<dyn UrlStream>, Error>;
+    fn open_async_with(&self, client: &UrlStreamClient) -> impl Future<Output = Result<Box<dyn AsyncUrlStream>, Error>> + Send;
 }
 
 impl UrlOpen for url::Url {
 
-    fn open(&self) -> Result<Box<dyn UrlStream>, Error>  {
+    fn open_with(&self, client: &UrlStreamClient) -> Result<Box<dyn UrlStream>, Error>  {
         match self.scheme().to_lowercase().as_str() {
-            "https" => Ok(https::open(self)),
-            _ => Err(Error::from("value"))
+            "http" | "https" => Ok(Box::new(https::open(self, client)?)),
+            "ftp" => Ok(Box::new(ftp::open(self, client)?)),
+            scheme => Err(Error::from(format!("unsupported scheme {}", scheme)))
         }
     }
 }
 
+impl AsyncUrlOpen for url::Url {
+
+    async fn open_async_with(&self, client: &UrlStreamClient) -> Result<Box<dyn AsyncUrlStream>, Error> {
+        match self.scheme().to_lowercase().as_str() {
+            "http" | "https" => Ok(Box::new(https::open_async(self, client).await?)),
+            "ftp" => Ok(Box::new(ftp::open_async(self, client).await?)),
+            scheme => Err(Error::from(format!("unsupported scheme {}", scheme)))
+        }
+    }
+}
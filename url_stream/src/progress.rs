@@ -0,0 +1,119 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Window over which the current rate of [`Progress`] is measured.
+const RATE_WINDOW: Duration = Duration::from_millis(500);
+
+/// Callback receiving the progress of a stream after each received chunk.
+#[derive(Clone)]
+pub(crate) struct ProgressCallback(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+/// Snapshot of a body transfer, handed to the callback set with
+/// [`UrlStreamClient::with_progress`](crate::UrlStreamClient::with_progress).
+///
+/// Counts bytes as received from the network, before any content decoding,
+/// so they compare with the `Content-Length` in [`total`](Self::total).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    transferred: u64,
+    total: Option<u64>,
+    elapsed: Duration,
+    rate: f64,
+    average_rate: f64,
+}
+
+impl Progress {
+    /// Body bytes received so far, including those of resumed and range
+    /// requests.
+    pub fn transferred(&self) -> u64 {
+        self.transferred
+    }
+
+    /// Size of the body, from the `Content-Length` of the response.
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+
+    /// Time since the stream was opened.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Bytes per second over the last half second or so.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Bytes per second since the stream was opened.
+    pub fn average_rate(&self) -> f64 {
+        self.average_rate
+    }
+
+    /// Fraction of `total` received, when the total is known.
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some((self.transferred as f64 / total as f64).min(1.0)),
+            None => None,
+        }
+    }
+}
+
+impl ProgressCallback {
+    pub(crate) fn new(callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        ProgressCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Progress of one stream, reported to its callback.
+pub(crate) struct ProgressTracker {
+    callback: ProgressCallback,
+    total: Option<u64>,
+    transferred: u64,
+    started: Instant,
+    window_start: Instant,
+    window_bytes: u64,
+    rate: Option<f64>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(callback: ProgressCallback, total: Option<u64>) -> Self {
+        let now = Instant::now();
+        ProgressTracker { callback, total, transferred: 0, started: now, window_start: now, window_bytes: 0, rate: None }
+    }
+
+    /// Accounts for `len` more bytes and reports the new progress.
+    pub(crate) fn received(&mut self, len: usize) {
+        let now = Instant::now();
+        self.transferred += len as u64;
+        self.window_bytes += len as u64;
+        let window = now - self.window_start;
+        if window >= RATE_WINDOW {
+            self.rate = Some(self.window_bytes as f64 / window.as_secs_f64());
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+        let elapsed = now - self.started;
+        let average_rate = match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.transferred as f64 / secs,
+            _ => 0.0,
+        };
+        (self.callback.0)(&Progress {
+            transferred: self.transferred,
+            total: self.total,
+            elapsed,
+            // until a full window passed, the average is the best estimate
+            rate: self.rate.unwrap_or(average_rate),
+            average_rate,
+        });
+    }
+}
@@ -0,0 +1,301 @@
+use std::{fmt, future::Future, io, sync::Arc, time::{Duration, SystemTime}};
+
+use hyper::{header, HeaderMap, StatusCode};
+use rand::Rng;
+
+use crate::{timeout::{Cancelled, TimeoutError}, Error};
+
+type RetryHook = Arc<dyn Fn(&RetryEvent) + Send + Sync>;
+
+/// Controls how failed requests and broken transfers are retried.
+///
+/// Each failure gets up to `max_attempts` tries (the first one included);
+/// the delay between them grows exponentially from the initial backoff,
+/// is capped at the maximum backoff and randomized by the jitter factor.
+/// A `Retry-After` header sent with a retryable status replaces the
+/// computed delay; when it asks for more than the maximum backoff, the
+/// response is returned without retrying.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    jitter: f64,
+    hook: Option<RetryHook>,
+}
+
+/// Why a request is being retried.
+#[derive(Debug)]
+pub enum RetryCause<'a> {
+    Status(StatusCode),
+    Error(&'a Error),
+}
+
+/// Passed to the [`RetryPolicy::on_retry`] hook before each retry.
+#[derive(Debug)]
+pub struct RetryEvent<'a> {
+    pub url: &'a url::Url,
+    /// Number of the attempt about to be made, starting at 2.
+    pub attempt: u32,
+    pub delay: Duration,
+    /// Byte offset the transfer resumes from.
+    pub offset: u64,
+    pub cause: RetryCause<'a>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+            hook: None,
+        }
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("multiplier", &self.multiplier)
+            .field("jitter", &self.jitter)
+            .field("hook", &self.hook.is_some())
+            .finish()
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Policy that never retries.
+    pub fn none() -> Self {
+        Self::default().max_attempts(1)
+    }
+
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Fraction (0.0 to 1.0) of each delay that is randomized.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Calls `hook` before every retry, e.g. to log it.
+    pub fn on_retry<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&RetryEvent) + Send + Sync + 'static,
+    {
+        self.hook = Some(Arc::new(hook));
+        self
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.max_attempts > 1
+    }
+
+    /// Delay before the attempt following the `attempt`-th failure.
+    fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1).min(64) as i32);
+        let base = self.initial_backoff.as_secs_f64() * exp;
+        let base = base.min(self.max_backoff.as_secs_f64());
+        let jitter = if self.jitter > 0.0 { rand::thread_rng().gen_range(0.0..self.jitter) } else { 0.0 };
+        Duration::from_secs_f64(base * (1.0 - jitter))
+    }
+
+    fn notify(&self, event: RetryEvent) {
+        if let Some(hook) = &self.hook {
+            hook(&event);
+        }
+    }
+}
+
+/// Whether a response with `status` is worth retrying.
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    match status {
+        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => true,
+        StatusCode::NOT_IMPLEMENTED | StatusCode::HTTP_VERSION_NOT_SUPPORTED => false,
+        status => status.is_server_error(),
+    }
+}
+
+/// Whether `error` is a transport failure that may go away on retry.
+pub fn is_transient(error: &Error) -> bool {
+    if let Some(timeout) = TimeoutError::find(error.as_ref()) {
+        return timeout != TimeoutError::Total;
+    }
+    if error.is::<Cancelled>() {
+        return false;
+    }
+    if let Some(e) = error.downcast_ref::<hyper::Error>() {
+        return !(e.is_user() || e.is_parse());
+    }
+    if let Some(e) = error.downcast_ref::<io::Error>() {
+        return matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::TimedOut
+        );
+    }
+    false
+}
+
+/// Parses `Retry-After` given either in seconds or as an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Sends a request through `send`, retrying per `policy` on transient
+/// errors and retryable statuses. When retries run out the last outcome is
+/// returned as is, so callers still see the final status.
+pub(crate) async fn send<F, Fut>(policy: &RetryPolicy, url: &url::Url, offset: u64, send: F) -> Result<hyper::Response<hyper::Body>, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<hyper::Response<hyper::Body>, Error>>,
+{
+    run(policy, url, offset, 1, send).await
+}
+
+/// Like [`send`], for a transfer that already failed with `cause`.
+pub(crate) async fn resume<F, Fut>(policy: &RetryPolicy, url: &url::Url, offset: u64, cause: Error, send: F) -> Result<hyper::Response<hyper::Body>, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<hyper::Response<hyper::Body>, Error>>,
+{
+    let delay = policy.delay(1);
+    policy.notify(RetryEvent { url, attempt: 2, delay, offset, cause: RetryCause::Error(&cause) });
+    tokio::time::sleep(delay).await;
+    run(policy, url, offset, 2, send).await
+}
+
+async fn run<F, Fut>(policy: &RetryPolicy, url: &url::Url, offset: u64, mut attempt: u32, mut send: F) -> Result<hyper::Response<hyper::Body>, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<hyper::Response<hyper::Body>, Error>>,
+{
+    loop {
+        let outcome = send().await;
+        if attempt >= policy.max_attempts {
+            return outcome;
+        }
+        let delay = match &outcome {
+            Ok(res) if is_retryable_status(res.status()) => {
+                let delay = match retry_after(res.headers()) {
+                    Some(delay) if delay > policy.max_backoff => return outcome,
+                    Some(delay) => delay,
+                    None => policy.delay(attempt),
+                };
+                policy.notify(RetryEvent { url, attempt: attempt + 1, delay, offset, cause: RetryCause::Status(res.status()) });
+                delay
+            }
+            Err(e) if is_transient(e) => {
+                let delay = policy.delay(attempt);
+                policy.notify(RetryEvent { url, attempt: attempt + 1, delay, offset, cause: RetryCause::Error(e) });
+                delay
+            }
+            _ => return outcome,
+        };
+        drop(outcome);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = RetryPolicy::new()
+            .backoff(Duration::from_millis(100), Duration::from_millis(500))
+            .jitter(0.0);
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(4), Duration::from_millis(500));
+
+        let jittered = RetryPolicy::new()
+            .backoff(Duration::from_millis(100), Duration::from_secs(1))
+            .jitter(0.5);
+        for _ in 0..20 {
+            let delay = jittered.delay(1);
+            assert!(delay > Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn status_classification() {
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retryable_status(StatusCode::BAD_GATEWAY));
+        assert!(!is_retryable_status(StatusCode::NOT_IMPLEMENTED));
+        assert!(!is_retryable_status(StatusCode::NOT_FOUND));
+        assert!(!is_retryable_status(StatusCode::OK));
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+        headers.insert(header::RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+        headers.insert(header::RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn long_retry_after_gives_up() {
+        let policy = RetryPolicy::new().backoff(Duration::from_millis(10), Duration::from_secs(1));
+        let url = url::Url::parse("http://localhost/").unwrap();
+        let attempts = AtomicU32::new(0);
+        let send = |retry_after: &'static str| {
+            attempts.store(0, Ordering::SeqCst);
+            let send = || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let status = if attempt == 0 { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+                    Ok(hyper::Response::builder().status(status).header(header::RETRY_AFTER, retry_after).body(hyper::Body::empty()).unwrap())
+                }
+            };
+            crate::runtime::get().block_on(run(&policy, &url, 0, 1, send)).unwrap().status()
+        };
+        assert_eq!(send("3600"), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        assert_eq!(send("0"), StatusCode::OK);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
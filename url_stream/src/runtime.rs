@@ -0,0 +1,26 @@
+use std::{future::Future, sync::OnceLock};
+
+use tokio::runtime::{Builder, Runtime};
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// Runtime backing the blocking API.
+///
+/// It is shared by every blocking stream so that connections and the
+/// background tasks driving them outlive the call that created them.
+pub(crate) fn get() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .thread_name("url_stream")
+            .enable_all()
+            .build()
+            .expect("failed to start url_stream runtime")
+    })
+}
+
+/// Runs `future` to completion on the shared runtime.
+///
+/// Must not be called from within an async context.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    get().block_on(future)
+}
@@ -0,0 +1,73 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+
+/// Serves `handler` on an ephemeral local port of the shared runtime.
+pub fn serve<F, R>(handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    serve_counting(handler).0
+}
+
+/// Like [`serve`], also counting the connections accepted.
+pub fn serve_counting<F, R>(handler: F) -> (SocketAddr, Arc<AtomicUsize>)
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let _guard = crate::runtime::get().enter();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    let make_service = make_service_fn(move |_| {
+        accepted.fetch_add(1, Ordering::SeqCst);
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let res = handler(req);
+                async move { Ok::<_, Infallible>(res.await) }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, connections)
+}
+
+/// Like [`serve`], over TLS configured by `tls`.
+pub fn serve_tls<F, R>(tls: rustls::ServerConfig, handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let runtime = crate::runtime::get();
+    let listener = runtime.block_on(tokio::net::TcpListener::bind(("127.0.0.1", 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls));
+    runtime.spawn(async move {
+        while let Ok((tcp, _)) = listener.accept().await {
+            let (acceptor, handler) = (acceptor.clone(), handler.clone());
+            tokio::spawn(async move {
+                // handshake failures are what some tests expect
+                if let Ok(stream) = acceptor.accept(tcp).await {
+                    let service = service_fn(move |req| {
+                        let res = handler(req);
+                        async move { Ok::<_, Infallible>(res.await) }
+                    });
+                    let _ = hyper::server::conn::Http::new().serve_connection(stream, service).await;
+                }
+            });
+        }
+    });
+    addr
+}
@@ -0,0 +1,125 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::time::{Instant, Sleep};
+
+/// Token bucket capping the bandwidth of the streams reading through it.
+///
+/// Clones share the bucket: set on the client builder, one limiter caps
+/// the combined rate of all its streams; set with
+/// [`UrlStreamClient::with_rate_limit`](crate::UrlStreamClient::with_rate_limit)
+/// it caps the streams opened through the derived client. A stream obeys
+/// every limiter that applies to it.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    /// Tokens available; negative while readers are in debt.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// Limits to `bytes_per_second`, allowing bursts of one second's worth.
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket { rate, burst: rate, tokens: rate, refilled: Instant::now() })),
+        }
+    }
+
+    /// Bytes that may be read at once after a pause.
+    pub fn burst(self, bytes: u64) -> Self {
+        {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.burst = bytes.max(1) as f64;
+            bucket.tokens = bucket.tokens.min(bucket.burst);
+        }
+        self
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bucket.lock().unwrap().rate as u64
+    }
+
+    /// Takes `len` tokens, returning how long to wait before reading on.
+    fn take(&self, len: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = (now - bucket.refilled).as_secs_f64() * bucket.rate;
+        bucket.tokens = (bucket.tokens + refill).min(bucket.burst) - len as f64;
+        bucket.refilled = now;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+        }
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bucket = self.bucket.lock().unwrap();
+        f.debug_struct("RateLimiter").field("bytes_per_second", &bucket.rate).field("burst", &bucket.burst).finish()
+    }
+}
+
+/// Pauses a stream's reads to keep within its rate limiters.
+pub(crate) struct Throttle {
+    limiters: Vec<RateLimiter>,
+    pause: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(limiters: Vec<RateLimiter>) -> Self {
+        Throttle { limiters, pause: None }
+    }
+
+    /// Pays for `len` received bytes; the next read waits until the
+    /// limiters are out of debt.
+    pub(crate) fn received(&mut self, len: usize) {
+        let wait = self.limiters.iter().map(|limiter| limiter.take(len)).max().unwrap_or_default();
+        if !wait.is_zero() {
+            self.pause = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    /// Ready once reading may go on.
+    pub(crate) fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.pause.as_mut() {
+            Some(pause) => {
+                std::task::ready!(pause.as_mut().poll(cx));
+                self.pause = None;
+                Poll::Ready(())
+            }
+            None => Poll::Ready(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_at_rate() {
+        let limiter = RateLimiter::new(1024).burst(512);
+        assert_eq!(limiter.take(512), Duration::ZERO);
+        assert_eq!(limiter.take(256), Duration::from_millis(250));
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(limiter.take(0), Duration::ZERO);
+        // a pause doesn't bank more than the burst
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(limiter.take(768), Duration::from_millis(250));
+    }
+}
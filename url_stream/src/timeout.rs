@@ -0,0 +1,295 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    task::Context,
+    time::Duration,
+};
+
+use tokio::time::{Instant, Sleep};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+use crate::Error;
+
+tokio::task_local! {
+    /// Timeouts of the request being sent, read by the connector so that
+    /// connection setup honors per-request values.
+    static REQUEST_TIMEOUTS: Timeouts;
+}
+
+/// Time limits applied to opening and reading a stream. Every limit is
+/// disabled unless set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    pub(crate) connect: Option<Duration>,
+    pub(crate) tls_handshake: Option<Duration>,
+    pub(crate) first_byte: Option<Duration>,
+    pub(crate) read_idle: Option<Duration>,
+    pub(crate) total: Option<Duration>,
+}
+
+/// Which limit elapsed. Carried as the inner error of failed opens and of
+/// `io::ErrorKind::TimedOut` read errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutError {
+    Connect,
+    TlsHandshake,
+    FirstByte,
+    ReadIdle,
+    Total,
+}
+
+/// The stream was cancelled through its [`CancellationToken`].
+///
+/// The read in flight when cancellation happens fails with
+/// `io::ErrorKind::Interrupted`; later reads fail with
+/// `io::ErrorKind::ConnectionAborted`, so `read_to_end` and friends, which
+/// retry interrupted reads, still stop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit on establishing the TCP connection.
+    pub fn connect(mut self, timeout: Duration) -> Self {
+        self.connect = Some(timeout);
+        self
+    }
+
+    /// Limit on the TLS handshake, once connected.
+    pub fn tls_handshake(mut self, timeout: Duration) -> Self {
+        self.tls_handshake = Some(timeout);
+        self
+    }
+
+    /// Limit on receiving the response head, measured from when the
+    /// request is issued, connection setup included.
+    pub fn first_byte(mut self, timeout: Duration) -> Self {
+        self.first_byte = Some(timeout);
+        self
+    }
+
+    /// Limit on waiting for the next piece of the body.
+    pub fn read_idle(mut self, timeout: Duration) -> Self {
+        self.read_idle = Some(timeout);
+        self
+    }
+
+    /// Deadline for the whole transfer, from opening to the end of the body.
+    pub fn total(mut self, timeout: Duration) -> Self {
+        self.total = Some(timeout);
+        self
+    }
+
+    /// Timeouts of the request being sent from this task, if any.
+    pub(crate) fn current() -> Option<Self> {
+        REQUEST_TIMEOUTS.try_with(|t| *t).ok()
+    }
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self {
+            Self::Connect => "connect",
+            Self::TlsHandshake => "TLS handshake",
+            Self::FirstByte => "time-to-first-byte",
+            Self::ReadIdle => "read idle",
+            Self::Total => "total deadline",
+        };
+        write!(f, "{} timeout elapsed", what)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl TimeoutError {
+    pub(crate) fn into_io(self) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, self)
+    }
+
+    /// Finds a timeout anywhere in the source chain of `error`.
+    pub(crate) fn find(error: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        let mut current = Some(error);
+        while let Some(e) = current {
+            if let Some(timeout) = e.downcast_ref::<TimeoutError>() {
+                return Some(*timeout);
+            }
+            // io::Error skips its own payload when reporting the source.
+            current = match e.downcast_ref::<io::Error>().and_then(io::Error::get_ref) {
+                Some(inner) => Some(inner as &(dyn std::error::Error + 'static)),
+                None => e.source(),
+            };
+        }
+        None
+    }
+}
+
+/// Converts `error` to an `io::Error`, keeping timeouts and cancellation
+/// recognizable by their kind.
+pub(crate) fn into_io(error: Error) -> io::Error {
+    if let Some(timeout) = error.downcast_ref::<TimeoutError>() {
+        return timeout.into_io();
+    }
+    if error.is::<Cancelled>() {
+        return io::Error::new(io::ErrorKind::Interrupted, Cancelled);
+    }
+    match error.downcast::<io::Error>() {
+        Ok(e) => *e,
+        Err(e) => io::Error::other(e),
+    }
+}
+
+/// Runs `future` within `timeout`, failing with `kind` when it elapses.
+pub(crate) async fn within<T, F>(timeout: Option<Duration>, kind: TimeoutError, future: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.unwrap_or_else(|_| Err(Error::from(kind))),
+        None => future.await,
+    }
+}
+
+/// Limits of a single stream: its timeouts, the deadline derived from the
+/// total timeout and an optional cancellation token.
+#[derive(Clone, Debug)]
+pub(crate) struct Limits {
+    timeouts: Timeouts,
+    deadline: Option<Instant>,
+    cancel: Option<CancellationToken>,
+}
+
+impl Limits {
+    pub(crate) fn start(timeouts: Timeouts, cancel: Option<CancellationToken>) -> Self {
+        let deadline = timeouts.total.map(|total| Instant::now() + total);
+        Limits { timeouts, deadline, cancel }
+    }
+
+    /// Runs `future` until it completes, the deadline passes or the stream
+    /// is cancelled.
+    pub(crate) async fn guard<T, F>(&self, future: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let cancel = self.cancel.clone().unwrap_or_default();
+        let deadline = async {
+            match self.deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled(), if self.cancel.is_some() => Err(Error::from(Cancelled)),
+            _ = deadline => Err(Error::from(TimeoutError::Total)),
+            result = future => result,
+        }
+    }
+
+    /// Sends a request through `future`, applying the connection and
+    /// first-byte timeouts.
+    pub(crate) async fn request<T, F>(&self, future: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let result = REQUEST_TIMEOUTS
+            .scope(self.timeouts, within(self.timeouts.first_byte, TimeoutError::FirstByte, future))
+            .await;
+        result.map_err(|e| match TimeoutError::find(e.as_ref()) {
+            Some(timeout) => Error::from(timeout),
+            None => e,
+        })
+    }
+
+    pub(crate) fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    pub(crate) fn timers(&self) -> BodyTimers {
+        BodyTimers {
+            read_idle: self.timeouts.read_idle,
+            idle: None,
+            waiting: false,
+            deadline: self.deadline,
+            total: None,
+            cancel: self.cancel.clone(),
+            cancelled: None,
+            was_cancelled: false,
+        }
+    }
+}
+
+/// Timers watched while a body is read. They are created on first poll so
+/// that they belong to the runtime reading the body.
+pub(crate) struct BodyTimers {
+    read_idle: Option<Duration>,
+    idle: Option<Pin<Box<Sleep>>>,
+    /// Whether the idle timer runs; it starts when the reader begins
+    /// waiting, so a slow consumer doesn't count against the server.
+    waiting: bool,
+    deadline: Option<Instant>,
+    total: Option<Pin<Box<Sleep>>>,
+    cancel: Option<CancellationToken>,
+    cancelled: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+    was_cancelled: bool,
+}
+
+impl BodyTimers {
+    /// Error to fail the read with if the stream was cancelled or its
+    /// deadline passed.
+    pub(crate) fn poll_expired(&mut self, cx: &mut Context<'_>) -> Option<io::Error> {
+        if self.was_cancelled {
+            return Some(io::Error::new(io::ErrorKind::ConnectionAborted, Cancelled));
+        }
+        if let Some(token) = &self.cancel {
+            let cancelled = self.cancelled.get_or_insert_with(|| Box::pin(token.clone().cancelled_owned()));
+            if cancelled.as_mut().poll(cx).is_ready() {
+                self.was_cancelled = true;
+                return Some(io::Error::new(io::ErrorKind::Interrupted, Cancelled));
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            let total = self.total.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            if total.as_mut().poll(cx).is_ready() {
+                return Some(TimeoutError::Total.into_io());
+            }
+        }
+        None
+    }
+
+    /// Error to fail the read with if no data arrived for too long. Call
+    /// only after the body returned `Pending`.
+    pub(crate) fn poll_idle(&mut self, cx: &mut Context<'_>) -> Option<io::Error> {
+        let timeout = self.read_idle?;
+        if !self.waiting {
+            self.waiting = true;
+            match self.idle.as_mut() {
+                Some(idle) => idle.as_mut().reset(Instant::now() + timeout),
+                None => self.idle = Some(Box::pin(tokio::time::sleep(timeout))),
+            }
+        }
+        let idle = self.idle.as_mut()?;
+        if idle.as_mut().poll(cx).is_ready() {
+            self.waiting = false;
+            return Some(TimeoutError::ReadIdle.into_io());
+        }
+        None
+    }
+
+    /// Stops the idle timer once data arrived.
+    pub(crate) fn data_received(&mut self) {
+        self.waiting = false;
+    }
+}
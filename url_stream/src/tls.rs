@@ -0,0 +1,307 @@
+use std::{fmt, io, sync::Arc, time::SystemTime};
+
+use sha2::{Digest, Sha256};
+use rustls::{
+    client::{ResolvesClientCert, ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    sign::CertifiedKey,
+    Certificate, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName, SignatureScheme,
+};
+
+use crate::Error;
+
+/// TLS settings of a [`UrlStreamClient`](crate::UrlStreamClient).
+///
+/// By default the platform's root certificates are trusted and no client
+/// certificate is presented.
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    webpki_roots: bool,
+    extra_roots: Vec<Vec<u8>>,
+    client_cert: Option<Arc<CertifiedKey>>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("webpki_roots", &self.webpki_roots)
+            .field("extra_roots", &self.extra_roots.len())
+            .field("client_cert", &self.client_cert.is_some())
+            .field("pins", &self.pins.len())
+            .finish()
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the Mozilla root certificates bundled with the crate instead
+    /// of the platform's.
+    pub fn webpki_roots(mut self) -> Self {
+        self.webpki_roots = true;
+        self
+    }
+
+    /// Additionally trusts every certificate in `pem`, e.g. a private CA.
+    pub fn add_root_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        let certs = rustls_pemfile::certs(&mut &pem[..])?;
+        if certs.is_empty() {
+            return Err(Error::from("no certificate found in PEM"));
+        }
+        for cert in &certs {
+            RootCertStore::empty().add(&Certificate(cert.clone()))?;
+        }
+        self.extra_roots.extend(certs);
+        Ok(self)
+    }
+
+    /// Presents the certificate chain in `cert_pem`, leaf first, with the
+    /// private key in `key_pem` when the server asks for one.
+    pub fn client_auth_pem(mut self, cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, Error> {
+        let chain: Vec<Certificate> = rustls_pemfile::certs(&mut &cert_pem[..])?.into_iter().map(Certificate).collect();
+        if chain.is_empty() {
+            return Err(Error::from("no certificate found in PEM"));
+        }
+        let key = rustls_pemfile::read_all(&mut &key_pem[..])?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or("no private key found in PEM")?;
+        let key = rustls::sign::any_supported_type(&PrivateKey(key)).map_err(|_| "unsupported private key type")?;
+        self.client_cert = Some(Arc::new(CertifiedKey::new(chain, key)));
+        Ok(self)
+    }
+
+    /// Only accepts servers that send a certificate with this SHA-256 hash
+    /// of its `SubjectPublicKeyInfo`, on top of the usual verification.
+    /// Only the leaf and the intermediates the server sends are checked, not
+    /// the trusted root completing the chain, so pin one of those. Pins add
+    /// up; any of them matching is enough. See [`spki_sha256`] to compute one.
+    pub fn pin_spki_sha256(mut self, hash: [u8; 32]) -> Self {
+        self.pins.push(hash);
+        self
+    }
+
+    pub(crate) fn client_config(&self, alpn: &[&[u8]]) -> rustls::ClientConfig {
+        let mut roots = RootCertStore::empty();
+        if self.webpki_roots {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+            }));
+        } else if let Ok(certs) = rustls_native_certs::load_native_certs() {
+            let der: Vec<Vec<u8>> = certs.into_iter().map(|cert| cert.0).collect();
+            roots.add_parsable_certificates(&der);
+        }
+        roots.add_parsable_certificates(&self.extra_roots);
+
+        let builder = rustls::ClientConfig::builder().with_safe_defaults().with_root_certificates(roots.clone());
+        let mut config = match &self.client_cert {
+            Some(key) => builder.with_client_cert_resolver(Arc::new(ClientCert(key.clone()))),
+            None => builder.with_no_client_auth(),
+        };
+        if !self.pins.is_empty() {
+            let verifier = PinnedVerifier { inner: WebPkiVerifier::new(roots, None), pins: self.pins.clone() };
+            config.dangerous().set_certificate_verifier(Arc::new(verifier));
+        }
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        config
+    }
+}
+
+/// SHA-256 hash of the `SubjectPublicKeyInfo` of a DER certificate, the
+/// value expected by [`TlsConfig::pin_spki_sha256`].
+pub fn spki_sha256(cert_der: &[u8]) -> Result<[u8; 32], Error> {
+    let spki = subject_public_key_info(cert_der)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed certificate"))?;
+    Ok(Sha256::digest(spki).into())
+}
+
+struct ClientCert(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for ClientCert {
+    fn resolve(&self, _: &[&[u8]], _: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Verifies the chain as usual, then requires one of the certificates the
+/// server sent to match a pin.
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|cert| spki_sha256(&cert.0).ok())
+            .any(|hash| self.pins.contains(&hash));
+        if pinned {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General("no certificate sent by the server matches a pinned key".into()))
+        }
+    }
+}
+
+/// Splits the DER element at the start of `input` into the whole element,
+/// its contents and the rest of the input.
+fn der_element(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    if tag & 0x1f == 0x1f {
+        // multi-byte tags don't occur in certificates
+        return None;
+    }
+    let (&len, rest) = rest.split_first()?;
+    let (len, header) = if len & 0x80 == 0 {
+        (len as usize, 2)
+    } else {
+        let count = (len & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        (rest[..count].iter().fold(0usize, |acc, b| acc << 8 | *b as usize), 2 + count)
+    };
+    let end = header.checked_add(len).filter(|end| *end <= input.len())?;
+    Some((&input[..end], &input[header..end], &input[end..]))
+}
+
+/// Locates `tbsCertificate.subjectPublicKeyInfo` (RFC 5280, 4.1).
+fn subject_public_key_info(cert_der: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_element(cert_der)?;
+    let (_, tbs, _) = der_element(cert)?;
+    let mut rest = tbs;
+    if rest.first() == Some(&0xa0) {
+        rest = der_element(rest)?.2;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+    let (spki, _, _) = der_element(rest)?;
+    Some(spki)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn der_lengths() {
+        assert_eq!(der_element(&[0x30, 0x01, 0xff, 0x02]), Some((&[0x30, 0x01, 0xff][..], &[0xff][..], &[0x02][..])));
+        let long = [&[0x04, 0x81, 0x80][..], &[7u8; 0x80]].concat();
+        assert_eq!(der_element(&long).unwrap().1.len(), 0x80);
+        assert_eq!(der_element(&[0x30, 0x05, 0x00]), None);
+        assert_eq!(der_element(&[0x30]), None);
+    }
+
+    #[test]
+    fn spki_of_generated_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        assert_eq!(subject_public_key_info(&der).unwrap(), cert.get_key_pair().public_key_der());
+        assert!(spki_sha256(&der[..40]).is_err());
+    }
+
+    struct Pki {
+        ca: rcgen::Certificate,
+        server: rcgen::Certificate,
+    }
+
+    fn issue(ca: Option<&rcgen::Certificate>, name: &str) -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(vec![name.to_string()]);
+        if ca.is_none() {
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        }
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    fn pki() -> Pki {
+        let ca = issue(None, "url_stream test CA");
+        let server = issue(Some(&ca), "localhost");
+        Pki { ca, server }
+    }
+
+    /// HTTPS server presenting a certificate issued by the test CA, asking
+    /// for a client certificate issued by it when `client_auth` is set.
+    fn serve(pki: &Pki, client_auth: bool) -> url::Url {
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = if client_auth {
+            let mut roots = RootCertStore::empty();
+            roots.add(&Certificate(pki.ca.serialize_der().unwrap())).unwrap();
+            builder.with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(roots))
+        } else {
+            builder.with_no_client_auth()
+        };
+        let chain = vec![Certificate(pki.server.serialize_der_with_signer(&pki.ca).unwrap())];
+        let config = builder.with_single_cert(chain, PrivateKey(pki.server.serialize_private_key_der())).unwrap();
+        let addr = crate::test_server::serve_tls(config, |_| async { hyper::Response::new(hyper::Body::from("#EXTM3U\n")) });
+        url::Url::parse(&format!("https://localhost:{}/master.m3u8", addr.port())).unwrap()
+    }
+
+    fn fetch(url: &url::Url, tls: TlsConfig) -> Result<String, Error> {
+        use std::io::Read;
+
+        let client = crate::UrlStreamClient::builder().retry(crate::RetryPolicy::none()).tls(tls).build();
+        let mut body = String::new();
+        crate::HttpUrlStream::open(url, &client)?.read_to_string(&mut body)?;
+        Ok(body)
+    }
+
+    #[test]
+    fn private_ca_roots() {
+        let pki = pki();
+        let url = serve(&pki, false);
+        let ca_pem = pki.ca.serialize_pem().unwrap();
+
+        assert!(fetch(&url, TlsConfig::new()).is_err());
+        assert_eq!(fetch(&url, TlsConfig::new().add_root_pem(ca_pem.as_bytes()).unwrap()).unwrap(), "#EXTM3U\n");
+        assert_eq!(fetch(&url, TlsConfig::new().webpki_roots().add_root_pem(ca_pem.as_bytes()).unwrap()).unwrap(), "#EXTM3U\n");
+        assert!(TlsConfig::new().add_root_pem(b"not a certificate").is_err());
+    }
+
+    #[test]
+    fn client_certificate() {
+        let pki = pki();
+        let url = serve(&pki, true);
+        let trusted = TlsConfig::new().add_root_pem(pki.ca.serialize_pem().unwrap().as_bytes()).unwrap();
+        assert!(fetch(&url, trusted.clone()).is_err());
+
+        let client = issue(Some(&pki.ca), "client");
+        let cert_pem = client.serialize_pem_with_signer(&pki.ca).unwrap();
+        let with_cert = trusted.client_auth_pem(cert_pem.as_bytes(), client.serialize_private_key_pem().as_bytes()).unwrap();
+        assert_eq!(fetch(&url, with_cert).unwrap(), "#EXTM3U\n");
+    }
+
+    #[test]
+    fn pinned_keys() {
+        let pki = pki();
+        let url = serve(&pki, false);
+        let trusted = TlsConfig::new().add_root_pem(pki.ca.serialize_pem().unwrap().as_bytes()).unwrap();
+        let leaf_pin = spki_sha256(&pki.server.serialize_der_with_signer(&pki.ca).unwrap()).unwrap();
+
+        assert!(fetch(&url, trusted.clone().pin_spki_sha256([0; 32]).pin_spki_sha256(leaf_pin)).is_ok());
+        assert!(fetch(&url, trusted.clone().pin_spki_sha256([0; 32])).is_err());
+        // the root isn't part of the chain the server sends
+        let root_pin = spki_sha256(&pki.ca.serialize_der().unwrap()).unwrap();
+        assert!(fetch(&url, trusted.pin_spki_sha256(root_pin)).is_err());
+    }
+}